@@ -252,7 +252,7 @@ pub(crate) fn build_demux_probe<P: AsRef<str>>(
         let q_k_sink = q_dm_k.static_pad("sink").unwrap();
         metademux.connect_pad_added(move |src, src_pad| {
             log::info!("Received new pad {} from {}", src_pad.name(), src.name());
-            if src_pad.name().contains("meta") {
+            if src_pad.name() == "meta_rs" {
                 src_pad.link(&q_k_sink).unwrap();
             }
         });
@@ -2,41 +2,43 @@
 //!
 //! ExampleCMetaと同じ型を定義する
 
+use std::os::raw::c_char;
+
+/// MetaAPIに付けるタグの組み合わせ
+///
+/// Cの`ExampleMetaTags`と同じ値を持つ
 #[repr(C)]
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MetaTags {
+    /// タグなし。多くの要素でそのままコピーされる
+    ContentIndependent = 0,
+    /// "video", "size"。サイズの変化を扱えない要素では破棄される
+    VideoSize = 1,
+}
+
+/// ExampleCMetaに設定する値
+///
+/// labelはC側で複製されるので追加後もRust側の所有のまま
+#[derive(Debug, Clone, PartialEq)]
 pub struct ExampleCMetaParams {
-    pub label: gst::glib::GString,
+    pub label: String,
     pub count: i64,
     pub num: f32,
 }
 
 impl ExampleCMetaParams {
     pub fn new(label: String, count: i64, num: f32) -> Self {
-        Self {
-            label: label.into(),
-            count,
-            num,
-        }
+        Self { label, count, num }
     }
 }
-// TODO double free issue
-// impl Drop for ExampleCMetaParams {
-//     fn drop(&mut self) {
-//         println!("drop on ExampleCMetaParams");
-//     }
-// }
 
+/// labelはメタデータが所有するC文字列
+/// 直接書き換えずにexample_c_meta_set_labelを使う
 #[repr(C)]
 #[derive(Debug)]
 pub struct ExampleCMeta {
     parent: gst::ffi::GstMeta,
-    pub label: gst::glib::GString,
-    pub count: i64,
-    pub num: f32,
+    label: *mut c_char,
+    count: i64,
+    num: f32,
 }
-
-// impl Drop for ExampleCMeta {
-//     fn drop(&mut self) {
-//         println!("drop on ExampleCMeta");
-//     }
-// }
@@ -2,27 +2,31 @@
 //!
 //! ExampleCMetaをRustで扱うためのバインディング実装
 
+use std::{
+    ffi::{CStr, CString, NulError},
+    os::raw::c_char,
+};
+
 use gst::{ffi::GstBuffer, prelude::*};
 mod imp;
 
 #[link(name = "example_c_meta")]
 extern "C" {
     pub fn example_c_meta_get_info() -> *const gst::ffi::GstMetaInfo;
+    pub fn example_c_meta_api_register(tags: imp::MetaTags) -> gst::glib::ffi::gboolean;
     pub fn example_c_meta_api_get_type() -> gst::glib::Type;
-    // TODO GStringがFFIsafeでないというエラーが出ているので対応を考える
-    // RSでもstring実装であり、C実装で型sizeを合わせるために所有権のある型にしている
-    // しかしdouble freeが出てしまうので参照にしたほうがいいかも知れない
-    #[allow(improper_ctypes)]
     pub fn buffer_add_example_c_meta(
         buffer: *mut GstBuffer,
+        label: *const c_char,
         count: i64,
         num: f32,
     ) -> *mut imp::ExampleCMeta;
-    #[allow(improper_ctypes)]
-    pub fn buffer_add_param_example_c_meta(
-        buffer: *mut GstBuffer,
-        param: *mut ExampleCMetaParams,
-    ) -> *mut imp::ExampleCMeta;
+    pub fn example_c_meta_get_label(meta: *const imp::ExampleCMeta) -> *const c_char;
+    pub fn example_c_meta_get_count(meta: *const imp::ExampleCMeta) -> i64;
+    pub fn example_c_meta_get_num(meta: *const imp::ExampleCMeta) -> f32;
+    pub fn example_c_meta_set_label(meta: *mut imp::ExampleCMeta, label: *const c_char);
+    pub fn example_c_meta_set_count(meta: *mut imp::ExampleCMeta, count: i64);
+    pub fn example_c_meta_set_num(meta: *mut imp::ExampleCMeta, num: f32);
 }
 
 // Public Rust type for the custom meta.
@@ -30,8 +34,10 @@ extern "C" {
 #[derive(Debug)]
 pub struct ExampleCMeta(imp::ExampleCMeta);
 pub use imp::ExampleCMetaParams;
+pub use imp::MetaTags;
 
 // Metas must be Send+Sync.
+// labelはメタデータが所有していて変更には&mutが必要
 unsafe impl Send for ExampleCMeta {}
 unsafe impl Sync for ExampleCMeta {}
 
@@ -44,91 +50,205 @@ unsafe impl MetaAPI for ExampleCMeta {
 }
 
 impl ExampleCMeta {
-    // 別のバッファにデータをクローンするメソッド
+    /// タグを選んでMetaAPIを登録する
+    ///
+    /// タグは最初の登録で決まり、登録前にaddなどでMetaAPIを参照するとタグなしで登録される。
+    /// 登録済みのタグと異なる場合はErrを返す
+    pub fn register_api(tags: MetaTags) -> Result<(), gst::glib::BoolError> {
+        unsafe {
+            gst::glib::result_from_gboolean!(
+                example_c_meta_api_register(tags),
+                "ExampleCMetaAPI is already registered with other tags"
+            )
+        }
+    }
+
+    /// labelはC側で複製されるのでparamの所有権はRustに残る
+    ///
+    /// C文字列に変換するので、labelがNULを含む場合は追加せずにErrを返す
     pub fn add(
         buffer: &mut gst::BufferRef,
         param: imp::ExampleCMetaParams,
-    ) -> gst::MetaRefMut<Self, gst::meta::Standalone> {
+    ) -> Result<gst::MetaRefMut<Self, gst::meta::Standalone>, NulError> {
+        let label = CString::new(param.label)?;
         unsafe {
-            // OK
-            // 直接gst_buffer_add_metaを呼ぶ
-            // let mut params = std::mem::ManuallyDrop::new(param);
-            // let meta = gst::ffi::gst_buffer_add_meta(
-            //     buffer.as_mut_ptr(),
-            //     example_c_meta_get_info(),
-            //     &mut *params as *mut ExampleCMetaParams as gst::glib::ffi::gpointer ,
-            // ) as *mut imp::ExampleCMeta;
-
-            // OK
-            // 定義した関数に参照渡しで更新を指示する
-            // Heapにあるデータの場合は明示的にManuallyDropが必要
-            // println!(
-            //     "rust size of {}",
-            //     std::mem::size_of::<imp::ExampleCMetaParams>()
-            // );
-            let mut params = std::mem::ManuallyDrop::new(param);
-            let meta = buffer_add_param_example_c_meta(
+            let meta = buffer_add_example_c_meta(
                 buffer.as_mut_ptr(),
-                &mut *params as *mut ExampleCMetaParams,
+                label.as_ptr(),
+                param.count,
+                param.num,
             );
 
-            Self::from_mut_ptr(buffer, meta)
+            Ok(Self::from_mut_ptr(buffer, meta))
         }
     }
 
-    pub fn remove(buffer: &mut gst::BufferRef) {
-        if let Some(meta) = buffer.meta_mut::<Self>() {
-            meta.remove().unwrap();
-        }
+    pub fn remove(buffer: &mut gst::BufferRef) -> Option<imp::ExampleCMetaParams> {
+        let meta = buffer.meta_mut::<Self>()?;
+        let params = imp::ExampleCMetaParams::new(
+            meta.label().unwrap_or_default().to_string(),
+            meta.count(),
+            meta.num(),
+        );
+        meta.remove().unwrap();
+        Some(params)
     }
 
+    /// labelがNULLもしくはUTF-8でない場合はNone
     #[doc(alias = "get_label")]
-    pub fn label(&self) -> &gst::glib::GStr {
-        self.0.label.as_gstr()
+    pub fn label(&self) -> Option<&str> {
+        unsafe {
+            let label = example_c_meta_get_label(&self.0);
+            if label.is_null() {
+                None
+            } else {
+                CStr::from_ptr(label).to_str().ok()
+            }
+        }
     }
 
     #[doc(alias = "get_count")]
     pub fn count(&self) -> i64 {
-        self.0.count
+        unsafe { example_c_meta_get_count(&self.0) }
     }
 
     #[doc(alias = "get_num")]
     pub fn num(&self) -> f32 {
-        self.0.num
+        unsafe { example_c_meta_get_num(&self.0) }
+    }
+
+    /// C文字列に変換するので、NULを含む場合は変更せずにErrを返す
+    pub fn set_label(&mut self, label: &str) -> Result<(), NulError> {
+        let label = CString::new(label)?;
+        unsafe { example_c_meta_set_label(&mut self.0, label.as_ptr()) }
+        Ok(())
+    }
+
+    pub fn set_count(&mut self, count: i64) {
+        unsafe { example_c_meta_set_count(&mut self.0, count) }
+    }
+
+    pub fn set_num(&mut self, num: f32) {
+        unsafe { example_c_meta_set_num(&mut self.0, num) }
     }
 }
 
 #[cfg(test)]
 mod tests {
+    use gst::prelude::*;
+
+    use std::ffi::CStr;
+
+    use crate::{imp::ExampleCMetaParams, ExampleCMeta, MetaTags};
+
+    // テストではvideo-size featureでタグを選ぶ
+    #[cfg(not(feature = "video-size"))]
+    const TAGS: MetaTags = MetaTags::ContentIndependent;
+    #[cfg(feature = "video-size")]
+    const TAGS: MetaTags = MetaTags::VideoSize;
+
+    fn init() {
+        gst::init().unwrap();
+        ExampleCMeta::register_api(TAGS).unwrap();
+    }
+
+    // MetaAPIに登録されているタグの一覧
+    fn api_tags() -> Vec<String> {
+        use gst::glib::translate::IntoGlib;
+        let mut tags = vec![];
+        unsafe {
+            let mut p = gst::ffi::gst_meta_api_type_get_tags(ExampleCMeta::meta_api().into_glib());
+            while !(*p).is_null() {
+                tags.push(CStr::from_ptr(*p).to_str().unwrap().to_string());
+                p = p.add(1);
+            }
+        }
+        tags
+    }
+
+    #[test]
+    fn test_meta_tags() {
+        init();
+        #[cfg(not(feature = "video-size"))]
+        assert_eq!(api_tags(), Vec::<String>::new());
+        #[cfg(feature = "video-size")]
+        assert_eq!(api_tags(), ["video", "size"]);
+
+        // 登録後は同じタグなら成功し、異なるタグは選べない
+        assert!(ExampleCMeta::register_api(TAGS).is_ok());
+        let other = match TAGS {
+            MetaTags::ContentIndependent => MetaTags::VideoSize,
+            MetaTags::VideoSize => MetaTags::ContentIndependent,
+        };
+        assert!(ExampleCMeta::register_api(other).is_err());
+    }
 
-    use crate::{imp::ExampleCMetaParams, ExampleCMeta};
     #[test]
     fn test_write_read() {
         const LABEL: &str = "hello";
         const COUNT: i64 = 12345;
         const NUM: f32 = 1.2345;
-        gst::init().unwrap();
+        init();
         let mut buffer = gst::Buffer::with_size(1024).unwrap();
         {
             let buffer = buffer.make_mut();
             let params = ExampleCMetaParams::new(LABEL.to_owned(), COUNT, NUM);
-            let _meta = ExampleCMeta::add(buffer, params);
+            let _meta = ExampleCMeta::add(buffer, params).unwrap();
         }
         if let Some(meta) = buffer.meta::<ExampleCMeta>() {
-            assert_eq!(meta.label().as_str(), LABEL);
+            assert_eq!(meta.label(), Some(LABEL));
             assert_eq!(meta.count(), COUNT);
             assert_eq!(meta.num(), NUM);
         }
-        // TODO remove after resolve double free
+        // 読み出しで所有権が移らず何度でも読める
         if let Some(meta) = buffer.meta::<ExampleCMeta>() {
-            assert_eq!(meta.label().as_str(), LABEL);
+            assert_eq!(meta.label(), Some(LABEL));
             assert_eq!(meta.count(), COUNT);
             assert_eq!(meta.num(), NUM);
         }
         {
             let buffer = buffer.make_mut();
-            ExampleCMeta::remove(buffer);
+            let params = ExampleCMeta::remove(buffer).unwrap();
+            assert_eq!(
+                params,
+                ExampleCMetaParams::new(LABEL.to_owned(), COUNT, NUM)
+            );
         }
         assert!(buffer.meta::<ExampleCMeta>().is_none());
     }
+
+    #[test]
+    fn test_add_nul_label() {
+        init();
+        let mut buffer = gst::Buffer::with_size(1024).unwrap();
+        let buffer = buffer.make_mut();
+        // NULを含むlabelはmetaを追加しない
+        let params = ExampleCMetaParams::new("a\0b".to_owned(), 1, 0.5);
+        assert!(ExampleCMeta::add(buffer, params).is_err());
+        assert!(buffer.meta::<ExampleCMeta>().is_none());
+    }
+
+    #[test]
+    fn test_set_and_copy() {
+        init();
+        let mut buffer = gst::Buffer::with_size(1024).unwrap();
+        {
+            let buffer = buffer.make_mut();
+            let params = ExampleCMetaParams::new("before".to_owned(), 1, 0.5);
+            let mut meta = ExampleCMeta::add(buffer, params).unwrap();
+            meta.set_label("after").unwrap();
+            // NULを含むlabelは設定しない
+            assert!(meta.set_label("af\0ter").is_err());
+            assert_eq!(meta.label(), Some("after"));
+            meta.set_count(2);
+            meta.set_num(1.5);
+        }
+        // コピー先はlabelを複製して持つので両方を開放しても壊れない
+        let copied = buffer.copy();
+        drop(buffer);
+        let meta = copied.meta::<ExampleCMeta>().unwrap();
+        assert_eq!(meta.label(), Some("after"));
+        assert_eq!(meta.count(), 2);
+        assert_eq!(meta.num(), 1.5);
+    }
 }
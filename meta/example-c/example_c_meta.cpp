@@ -1,5 +1,48 @@
 #include "example_c_meta.h"
 
+#include <gst/video/video.h>
+
+/*
+タグを付けてAPI Typeを登録する
+独自のメタデータを追加する時もこの関数でタグの組み合わせを選ぶ
+*/
+GType example_meta_api_type_register(const gchar *api, ExampleMetaTags tags)
+{
+    static const gchar *content_independent[] = {NULL};
+    static const gchar *video_size[] = {
+        GST_META_TAG_VIDEO_STR, GST_META_TAG_VIDEO_SIZE_STR, NULL};
+
+    switch (tags)
+    {
+    case EXAMPLE_META_TAGS_VIDEO_SIZE:
+        return gst_meta_api_type_register(api, video_size);
+    case EXAMPLE_META_TAGS_CONTENT_INDEPENDENT:
+    default:
+        return gst_meta_api_type_register(api, content_independent);
+    }
+}
+
+/* example_c_meta_api_registerで選んだタグ。登録後は変更しない */
+static ExampleMetaTags c_meta_tags = EXAMPLE_META_TAGS_CONTENT_INDEPENDENT;
+static gboolean c_meta_registered = FALSE;
+G_LOCK_DEFINE_STATIC(c_meta_tags);
+
+gboolean example_c_meta_api_register(ExampleMetaTags tags)
+{
+    G_LOCK(c_meta_tags);
+    if (!c_meta_registered)
+        c_meta_tags = tags;
+    G_UNLOCK(c_meta_tags);
+
+    // 登録自体はexample_c_meta_api_get_typeで一度だけ行う
+    example_c_meta_api_get_type();
+
+    G_LOCK(c_meta_tags);
+    gboolean ok = c_meta_tags == tags;
+    G_UNLOCK(c_meta_tags);
+    return ok;
+}
+
 /*
 API Typeの登録。
 GLibにメタデータの存在を通知して参照のためのポインタを確保する
@@ -7,11 +50,15 @@ GLibにメタデータの存在を通知して参照のためのポインタを
 GType example_c_meta_api_get_type(void)
 {
     static volatile GType type;
-    static const gchar *tags[] = {NULL};
 
     if (g_once_init_enter(&type))
     {
-        GType _type = gst_meta_api_type_register("ExampleCMetaAPI", tags);
+        G_LOCK(c_meta_tags);
+        c_meta_registered = TRUE;
+        ExampleMetaTags tags = c_meta_tags;
+        G_UNLOCK(c_meta_tags);
+
+        GType _type = example_meta_api_type_register("ExampleCMetaAPI", tags);
         g_once_init_leave(&type, _type);
     }
     return type;
@@ -25,9 +72,10 @@ gboolean
 gst_example_c_meta_init(GstMeta *meta, gpointer params, GstBuffer *buffer)
 {
     _ExampleCMeta *dmeta = (_ExampleCMeta *)meta;
-    _ExampleCMetaParam *dparams = (_ExampleCMetaParam *)params;
+    const _ExampleCMetaParam *dparams = (const _ExampleCMetaParam *)params;
 
-    dmeta->label = dparams->label;
+    // 呼び出し元の文字列は借りているだけなので複製して所有する
+    dmeta->label = g_strdup(dparams->label);
     dmeta->count = dparams->count;
     dmeta->num = dparams->num;
     return TRUE;
@@ -42,11 +90,7 @@ void gst_example_c_meta_free(GstMeta *meta, GstBuffer *buffer)
     _ExampleCMeta *smeta;
     smeta = (_ExampleCMeta *)meta;
 
-    // FIXME double free or corruption (out)になる
-    // 到達前に開放されているが誰が開放しているのかわからない
-    if ((&smeta->label)->str) {
-        g_free(&smeta->label);
-    }
+    g_clear_pointer(&smeta->label, g_free);
 }
 
 /*
@@ -59,7 +103,9 @@ gst_example_c_meta_transform(GstBuffer *dest, GstMeta *meta,
     _ExampleCMeta *dmeta, *smeta;
     smeta = (_ExampleCMeta *)meta;
 
-    if (GST_META_TRANSFORM_IS_COPY(type))
+    // 値はフレームサイズに依存しないので拡縮でもそのままコピーする
+    // VIDEO_SIZEの場合のみscaleとして呼ばれる
+    if (GST_META_TRANSFORM_IS_COPY(type) || GST_VIDEO_META_TRANSFORM_IS_SCALE(type))
     {
         dmeta = (_ExampleCMeta *)buffer_add_example_c_meta(dest,
                                                            smeta->label, smeta->count, smeta->num);
@@ -99,7 +145,7 @@ example_c_meta_get_info(void)
 
 // 複数の値を分けて入力したい場合
 _ExampleCMeta *
-buffer_add_example_c_meta(GstBuffer *buffer, GString label, gint64 count, gfloat num)
+buffer_add_example_c_meta(GstBuffer *buffer, const gchar *label, gint64 count, gfloat num)
 {
     _ExampleCMeta *meta;
     _ExampleCMetaParam param = {label, count, num};
@@ -112,18 +158,58 @@ buffer_add_example_c_meta(GstBuffer *buffer, GString label, gint64 count, gfloat
 
 // 構造体で渡したい場合
 _ExampleCMeta *
-buffer_add_param_example_c_meta(GstBuffer *buffer, _ExampleCMetaParam *param)
+buffer_add_param_example_c_meta(GstBuffer *buffer, const _ExampleCMetaParam *param)
 {
     _ExampleCMeta *meta;
 
-    // TOOD: gst_example_c_meta_free douple freeの原因が分かったら
-    // デバッグプリントを削除する
-    // g_print("c sizeof %ld\n", sizeof(_ExampleCMetaParam));
-    // g_print("c pointer %p\n", param);
-    // g_print("c pointer label %p\n", &param->label);
-
     g_return_val_if_fail(GST_IS_BUFFER(buffer), NULL);
+    g_return_val_if_fail(param != NULL, NULL);
     meta = (_ExampleCMeta *)gst_buffer_add_meta(buffer,
-                                                EXAMPLE_C_META_INFO, param);
+                                                EXAMPLE_C_META_INFO, (gpointer)param);
     return meta;
 }
+
+const gchar *
+example_c_meta_get_label(const ExampleCMeta *meta)
+{
+    g_return_val_if_fail(meta != NULL, NULL);
+    return meta->label;
+}
+
+gint64
+example_c_meta_get_count(const ExampleCMeta *meta)
+{
+    g_return_val_if_fail(meta != NULL, 0);
+    return meta->count;
+}
+
+gfloat
+example_c_meta_get_num(const ExampleCMeta *meta)
+{
+    g_return_val_if_fail(meta != NULL, 0.0);
+    return meta->num;
+}
+
+// 新しい文字列を複製してから古い文字列を開放する
+// labelに自分自身のlabelを渡されても壊れない
+void example_c_meta_set_label(ExampleCMeta *meta, const gchar *label)
+{
+    gchar *old;
+
+    g_return_if_fail(meta != NULL);
+    old = meta->label;
+    meta->label = g_strdup(label);
+    g_free(old);
+}
+
+void example_c_meta_set_count(ExampleCMeta *meta, gint64 count)
+{
+    g_return_if_fail(meta != NULL);
+    meta->count = count;
+}
+
+void example_c_meta_set_num(ExampleCMeta *meta, gfloat num)
+{
+    g_return_if_fail(meta != NULL);
+    meta->num = num;
+}
@@ -5,23 +5,48 @@
 
 G_BEGIN_DECLS
 
+/*
+MetaAPIに付けるタグの組み合わせ
+CONTENT_INDEPENDENT: タグなし。多くの要素でそのままコピーされる
+VIDEO_SIZE: "video", "size"。サイズの変化を扱えない要素では破棄される
+*/
+typedef enum {
+    EXAMPLE_META_TAGS_CONTENT_INDEPENDENT = 0,
+    EXAMPLE_META_TAGS_VIDEO_SIZE = 1,
+} ExampleMetaTags;
+
 typedef struct _ExampleCMeta ExampleCMeta;
 typedef struct _ExampleCMetaParam ExampleCMetaParam;
 
+/*
+labelはメタデータが所有する。追加、コピー時に複製してfreeで開放する
+直接書き換えずにsetterを使うこと
+*/
 struct _ExampleCMeta {
     GstMeta meta;
 
-    GString label;
+    gchar *label;
     gint64 count;
     gfloat num;
 };
 
+/* labelは呼び出し側の所有のまま。メタデータには複製が入る */
 struct _ExampleCMetaParam {
-    GString label;
+    const gchar *label;
     gint64 count;
     gfloat num;
 };
 
+// register api type with tags
+GType example_meta_api_type_register (const gchar * api, ExampleMetaTags tags);
+
+/*
+タグを選んでExampleCMetaAPIを登録する
+タグは最初の登録で決まり、登録前にexample_c_meta_api_get_typeを呼ぶとタグなしで登録される
+登録済みのタグと異なる場合はFALSEを返す
+*/
+gboolean example_c_meta_api_register (ExampleMetaTags tags);
+
 // api get type
 GType example_c_meta_api_get_type (void);
 #define EXAMPLE_C_META_API_TYPE (example_c_meta_api_get_type())
@@ -31,10 +56,20 @@ const GstMetaInfo * example_c_meta_get_info (void);
 #define EXAMPLE_C_META_INFO (example_c_meta_get_info())
 
 // utility function
-ExampleCMeta * buffer_add_example_c_meta (GstBuffer * buffer, GString label, gint64 count, gfloat num);
+ExampleCMeta * buffer_add_example_c_meta (GstBuffer * buffer, const gchar * label, gint64 count, gfloat num);
 
 // utility function
-ExampleCMeta * buffer_add_param_example_c_meta (GstBuffer * buffer, ExampleCMetaParam *param);
+ExampleCMeta * buffer_add_param_example_c_meta (GstBuffer * buffer, const ExampleCMetaParam * param);
+
+// accessor
+const gchar * example_c_meta_get_label (const ExampleCMeta * meta);
+gint64 example_c_meta_get_count (const ExampleCMeta * meta);
+gfloat example_c_meta_get_num (const ExampleCMeta * meta);
+
+// setter
+void example_c_meta_set_label (ExampleCMeta * meta, const gchar * label);
+void example_c_meta_set_count (ExampleCMeta * meta, gint64 count);
+void example_c_meta_set_num (ExampleCMeta * meta, gfloat num);
 
 G_END_DECLS
 
@@ -0,0 +1,6 @@
+fn main() {
+    // SOを生成するexample-rs自身はlink featureを外してリンクしない
+    if std::env::var_os("CARGO_FEATURE_LINK").is_some() {
+        println!("cargo:rustc-link-lib=dylib=example_rs_meta");
+    }
+}
@@ -1,13 +1,16 @@
 //! ExampleRsMeta structs
 //!
-//! ExampleRsMetaと同じ型を定義する
-//! TODO 型定義だけ共有できる方法を考えたい
+//! ExampleRsMetaの型定義
+//! 登録やFFI関数は`#[derive(GstMeta)]`で生成し、example-rsでexportする
+
+use gst_meta_derive::GstMeta;
 
 #[repr(C)]
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum TransformMode {
     Ignore = 0,
     Copy = 1,
+    Region = 2,
 }
 
 impl Default for TransformMode {
@@ -20,29 +23,140 @@ impl From<u32> for TransformMode {
     fn from(x: u32) -> Self {
         match x {
             0 => Self::Ignore,
+            2 => Self::Region,
             _ => Self::Copy,
         }
     }
 }
 
+/// MetaAPIに付けるタグの組み合わせ
+///
+/// videoconvertやvideoscale、エンコーダなどはタグを見てメタデータを
+/// そのままコピーするか、transformを呼ぶか、破棄するかを決める
+#[repr(u32)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MetaTags {
+    /// タグなし
+    ///
+    /// フレームの内容に依存しないので多くの要素でgst-copyとしてコピーされる
+    ContentIndependent = 0,
+    /// "video", "size"
+    ///
+    /// フレームサイズに依存する。videoscaleではscaleとしてtransformが呼ばれ
+    /// サイズの変化を扱えない要素では破棄される
+    VideoSize = 1,
+}
+
+impl Default for MetaTags {
+    fn default() -> Self {
+        Self::ContentIndependent
+    }
+}
+
+impl From<u32> for MetaTags {
+    fn from(x: u32) -> Self {
+        match x {
+            1 => Self::VideoSize,
+            _ => Self::ContentIndependent,
+        }
+    }
+}
+
+impl MetaTags {
+    /// NUL終端したタグの一覧
+    pub fn tags(&self) -> &'static [&'static [u8]] {
+        match self {
+            Self::ContentIndependent => &[],
+            Self::VideoSize => &[b"video\0", b"size\0"],
+        }
+    }
+}
+
+/// フレーム上の矩形領域 [pixel]
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Region {
+    pub x: i32,
+    pub y: i32,
+    pub width: i32,
+    pub height: i32,
+}
+
+impl Region {
+    pub fn new(x: i32, y: i32, width: i32, height: i32) -> Self {
+        Self {
+            x,
+            y,
+            width,
+            height,
+        }
+    }
+
+    /// フレームからはみ出した部分を切り取る。完全に外に出た場合はNone
+    pub fn clip(&self, width: i32, height: i32) -> Option<Self> {
+        let x0 = self.x.clamp(0, width);
+        let y0 = self.y.clamp(0, height);
+        let x1 = self.x.saturating_add(self.width).clamp(0, width);
+        let y1 = self.y.saturating_add(self.height).clamp(0, height);
+        if x1 <= x0 || y1 <= y0 {
+            None
+        } else {
+            Some(Self::new(x0, y0, x1 - x0, y1 - y0))
+        }
+    }
+
+    /// 入力フレームサイズから出力フレームサイズへ拡縮する
+    pub fn scale(&self, in_size: (i32, i32), out_size: (i32, i32)) -> Option<Self> {
+        let (in_w, in_h) = in_size;
+        let (out_w, out_h) = out_size;
+        if in_w <= 0 || in_h <= 0 {
+            return None;
+        }
+        let scale = |v: i32, num: i32, den: i32| (v as i64 * num as i64 / den as i64) as i32;
+        let x0 = scale(self.x, out_w, in_w);
+        let y0 = scale(self.y, out_h, in_h);
+        let x1 = scale(self.x.saturating_add(self.width), out_w, in_w);
+        let y1 = scale(self.y.saturating_add(self.height), out_h, in_h);
+        Self::new(x0, y0, x1 - x0, y1 - y0).clip(out_w, out_h)
+    }
+}
+
 #[derive(Debug, Default)]
 pub struct ExampleRsMetaParams {
     pub label: String,
     pub index: i32,
     pub mode: TransformMode,
+    pub region: Option<Region>,
 }
 
 impl ExampleRsMetaParams {
     pub fn new(label: String, index: i32, mode: TransformMode) -> Self {
-        Self { label, index, mode }
+        Self {
+            label,
+            index,
+            mode,
+            region: None,
+        }
     }
 }
 
 #[repr(C)]
-#[derive(Debug)]
+#[derive(Debug, GstMeta)]
+#[gst_meta(
+    name = "ExampleRsMeta",
+    api = "ExampleRsMetaAPI",
+    symbol = "example_rs_meta",
+    wrapper = "crate::ExampleRsMeta",
+    params = "crate::ExampleRsMetaParams"
+)]
+// タグは登録時にMetaTagsから選ぶ。選ばなければタグなしで登録する
 pub struct ExampleRsMeta {
     parent: gst::ffi::GstMeta,
     pub label: String,
+    #[gst_meta(copy)]
     pub index: i32,
+    #[gst_meta(copy)]
     pub mode: TransformMode,
+    #[gst_meta(copy)]
+    pub region: Option<Region>,
 }
@@ -5,91 +5,115 @@
 use gst::prelude::*;
 mod imp;
 
-#[link(name = "example_rs_meta")]
+// リンク指定はbuild.rsでlink featureに応じて行う
 extern "C" {
-    pub fn example_rs_meta_get_info() -> *const gst::ffi::GstMetaInfo;
-    pub fn example_rs_meta_api_get_type() -> gst::glib::Type;
+    pub fn example_rs_meta_serialize(
+        meta: *const gst::ffi::GstMeta,
+        dest: *mut u8,
+        size: usize,
+        version: *mut u8,
+    ) -> usize;
+    pub fn example_rs_meta_deserialize(
+        buffer: *mut gst::ffi::GstBuffer,
+        data: *const u8,
+        size: usize,
+        version: u8,
+    ) -> *mut gst::ffi::GstMeta;
+    pub fn example_rs_meta_api_register(tags: u32) -> gst::glib::ffi::gboolean;
 }
+pub use imp::{example_rs_meta_api_get_type, example_rs_meta_get_info};
 
 // Public Rust type for the custom meta.
+// MetaAPIやadd/remove、アクセサはimpの`#[derive(GstMeta)]`で生成する
 #[repr(transparent)]
 #[derive(Debug)]
 pub struct ExampleRsMeta(imp::ExampleRsMeta);
 pub use imp::ExampleRsMetaParams;
+pub use imp::MetaTags;
+pub use imp::Region;
 pub use imp::TransformMode;
 
-// Metas must be Send+Sync.
-unsafe impl Send for ExampleRsMeta {}
-unsafe impl Sync for ExampleRsMeta {}
-
-unsafe impl MetaAPI for ExampleRsMeta {
-    type GstType = imp::ExampleRsMeta;
-
-    fn meta_api() -> gst::glib::Type {
-        unsafe { example_rs_meta_api_get_type() }
-    }
-}
-
 impl ExampleRsMeta {
-    // 別のバッファにデータをクローンするメソッド
-    pub fn add(
-        buffer: &mut gst::BufferRef,
-        param: imp::ExampleRsMetaParams,
-    ) -> gst::MetaRefMut<Self, gst::meta::Standalone> {
+    /// タグを選んでMetaAPIを登録する
+    ///
+    /// タグは最初の登録で決まり、登録前にaddなどでMetaAPIを参照するとタグなしで登録される。
+    /// 登録済みのタグと異なる場合はErrを返す
+    pub fn register_api(tags: MetaTags) -> Result<(), gst::glib::BoolError> {
         unsafe {
-            let mut params = std::mem::ManuallyDrop::new(param);
-            let meta = gst::ffi::gst_buffer_add_meta(
-                buffer.as_mut_ptr(),
-                example_rs_meta_get_info(),
-                &mut *params as *mut imp::ExampleRsMetaParams as gst::glib::ffi::gpointer,
-            ) as *mut imp::ExampleRsMeta;
-
-            Self::from_mut_ptr(buffer, meta)
+            gst::glib::result_from_gboolean!(
+                example_rs_meta_api_register(tags as u32),
+                "ExampleRsMetaAPI is already registered with other tags"
+            )
         }
     }
 
-    pub fn remove(buffer: &mut gst::BufferRef) -> Option<imp::ExampleRsMetaParams> {
-        if let Some(meta) = buffer.meta_mut::<Self>() {
-            let params = imp::ExampleRsMetaParams {
-                label: meta.label().to_string(),
-                index: meta.index(),
-                mode: meta.mode(),
-            };
-            meta.remove().unwrap();
-            Some(params)
-        } else {
-            None
+    /// プロセス間で受け渡すためのバイト列に変換する
+    ///
+    /// 先頭1byteがバイト列のバージョンで、その後ろにメタデータの内容が続く
+    pub fn serialize(&self) -> Vec<u8> {
+        unsafe {
+            let meta = &self.0 as *const imp::ExampleRsMeta as *const gst::ffi::GstMeta;
+            let size =
+                example_rs_meta_serialize(meta, std::ptr::null_mut(), 0, std::ptr::null_mut());
+            let mut data = vec![0u8; size + 1];
+            let (version, payload) = data.split_first_mut().unwrap();
+            example_rs_meta_serialize(meta, payload.as_mut_ptr(), size, version);
+            data
         }
     }
 
-    #[doc(alias = "get_label")]
-    pub fn label(&self) -> &str {
-        self.0.label.as_str()
-    }
-
-    #[doc(alias = "get_index")]
-    pub fn index(&self) -> i32 {
-        self.0.index
-    }
-
-    #[doc(alias = "get_mode")]
-    pub fn mode(&self) -> imp::TransformMode {
-        self.0.mode
+    /// serializeしたバイト列からメタデータを復元してバッファに追加する
+    ///
+    /// 未知のバージョンや壊れたデータの場合はNoneを返す
+    pub fn deserialize<'a>(
+        buffer: &'a mut gst::BufferRef,
+        data: &[u8],
+    ) -> Option<gst::MetaRefMut<'a, Self, gst::meta::Standalone>> {
+        let (version, payload) = data.split_first()?;
+        unsafe {
+            let meta = example_rs_meta_deserialize(
+                buffer.as_mut_ptr(),
+                payload.as_ptr(),
+                payload.len(),
+                *version,
+            );
+            if meta.is_null() {
+                None
+            } else {
+                Some(Self::from_mut_ptr(buffer, meta as *mut imp::ExampleRsMeta))
+            }
+        }
     }
 }
 
 #[cfg(test)]
 mod tests {
+    use gst::prelude::*;
+
+    #[cfg(feature = "video-size")]
+    use crate::Region;
     use crate::{
         imp::{ExampleRsMetaParams, TransformMode},
-        ExampleRsMeta,
+        ExampleRsMeta, MetaTags,
     };
+
+    // テストではvideo-size featureでタグを選ぶ
+    #[cfg(not(feature = "video-size"))]
+    const TAGS: MetaTags = MetaTags::ContentIndependent;
+    #[cfg(feature = "video-size")]
+    const TAGS: MetaTags = MetaTags::VideoSize;
+
+    fn init() {
+        gst::init().unwrap();
+        ExampleRsMeta::register_api(TAGS).unwrap();
+    }
+
     #[test]
     fn test_write_read() {
         const LABEL: &str = "testlabel";
         const INDEX: i32 = 12345;
         const MODE: TransformMode = TransformMode::Ignore;
-        gst::init().unwrap();
+        init();
         let mut buffer = gst::Buffer::with_size(1024).unwrap();
         {
             let buffer = buffer.make_mut();
@@ -107,4 +131,168 @@ mod tests {
         }
         assert!(buffer.meta::<ExampleRsMeta>().is_none());
     }
+
+    #[test]
+    fn test_serialize_deserialize() {
+        const LABEL: &str = "serialized";
+        const INDEX: i32 = -42;
+        const MODE: TransformMode = TransformMode::Copy;
+        init();
+        let mut buffer = gst::Buffer::with_size(1024).unwrap();
+        {
+            let buffer = buffer.make_mut();
+            let params = ExampleRsMetaParams::new(LABEL.to_string(), INDEX, MODE);
+            let _meta = ExampleRsMeta::add(buffer, params);
+        }
+        let data = buffer.meta::<ExampleRsMeta>().unwrap().serialize();
+
+        // 別のバッファで復元する
+        let mut restored = gst::Buffer::with_size(1024).unwrap();
+        {
+            let restored = restored.make_mut();
+            assert!(ExampleRsMeta::deserialize(restored, &data).is_some());
+            // 壊れたデータは復元しない
+            assert!(ExampleRsMeta::deserialize(restored, &data[..data.len() - 1]).is_none());
+            assert!(ExampleRsMeta::deserialize(restored, &[0xff]).is_none());
+        }
+        let meta = restored.meta::<ExampleRsMeta>().unwrap();
+        assert_eq!(meta.label(), LABEL);
+        assert_eq!(meta.index(), INDEX);
+        assert_eq!(meta.mode(), MODE);
+    }
+
+    // MetaAPIに登録されているタグの一覧
+    fn api_tags() -> Vec<String> {
+        use gst::glib::translate::IntoGlib;
+        let mut tags = vec![];
+        unsafe {
+            let mut p = gst::ffi::gst_meta_api_type_get_tags(ExampleRsMeta::meta_api().into_glib());
+            while !(*p).is_null() {
+                tags.push(std::ffi::CStr::from_ptr(*p).to_str().unwrap().to_string());
+                p = p.add(1);
+            }
+        }
+        tags
+    }
+
+    #[test]
+    fn test_meta_tags() {
+        init();
+        #[cfg(not(feature = "video-size"))]
+        assert_eq!(api_tags(), Vec::<String>::new());
+        #[cfg(feature = "video-size")]
+        assert_eq!(api_tags(), ["video", "size"]);
+
+        // 登録後は同じタグなら成功し、異なるタグは選べない
+        assert!(ExampleRsMeta::register_api(TAGS).is_ok());
+        let other = match TAGS {
+            MetaTags::ContentIndependent => MetaTags::VideoSize,
+            MetaTags::VideoSize => MetaTags::ContentIndependent,
+        };
+        assert!(ExampleRsMeta::register_api(other).is_err());
+    }
+
+    // srcの出力にメタデータを追加し、sinkに届いたメタデータを返す
+    fn run_pipeline<F>(desc: &str, params: F) -> Option<ExampleRsMetaParams>
+    where
+        F: Fn() -> ExampleRsMetaParams + Send + Sync + 'static,
+    {
+        use std::sync::{Arc, Mutex};
+
+        init();
+        let pipeline = gst::parse_launch(desc)
+            .unwrap()
+            .downcast::<gst::Pipeline>()
+            .unwrap();
+
+        let src = pipeline.by_name("src").unwrap();
+        src.static_pad("src")
+            .unwrap()
+            .add_probe(gst::PadProbeType::BUFFER, move |_, info| {
+                if let Some(gst::PadProbeData::Buffer(ref mut buffer)) = info.data {
+                    ExampleRsMeta::add(buffer.make_mut(), params());
+                }
+                gst::PadProbeReturn::Ok
+            });
+
+        let received = Arc::new(Mutex::new(None));
+        let received_clone = received.clone();
+        let sink = pipeline.by_name("sink").unwrap();
+        sink.static_pad("sink")
+            .unwrap()
+            .add_probe(gst::PadProbeType::BUFFER, move |_, info| {
+                if let Some(gst::PadProbeData::Buffer(ref buffer)) = info.data {
+                    *received_clone.lock().unwrap() =
+                        Some(buffer.meta::<ExampleRsMeta>().map(|meta| meta.to_params()));
+                }
+                gst::PadProbeReturn::Ok
+            });
+
+        pipeline.set_state(gst::State::Playing).unwrap();
+        let bus = pipeline.bus().unwrap();
+        for msg in bus.iter_timed(gst::ClockTime::NONE) {
+            match msg.view() {
+                gst::MessageView::Eos(..) => break,
+                gst::MessageView::Error(err) => panic!("{:?}", err),
+                _ => {}
+            }
+        }
+        pipeline.set_state(gst::State::Null).unwrap();
+
+        let received = received.lock().unwrap().take();
+        received.expect("no buffer received")
+    }
+
+    #[test]
+    fn test_encoder() {
+        let params = run_pipeline(
+            "videotestsrc num-buffers=1 ! video/x-raw,width=320,height=240 ! identity name=src \
+             ! jpegenc ! fakesink name=sink",
+            || ExampleRsMetaParams::new("encoded".to_string(), 1, TransformMode::Copy),
+        );
+        // エンコーダはタグなしのメタデータだけをコピーする
+        #[cfg(not(feature = "video-size"))]
+        assert_eq!(params.unwrap().label, "encoded");
+        #[cfg(feature = "video-size")]
+        assert!(params.is_none());
+    }
+
+    // srcの出力にregion付きのメタデータを追加し、sinkに届いたregionを返す
+    #[cfg(feature = "video-size")]
+    fn run_region_pipeline(desc: &str, region: Region) -> Option<Region> {
+        let params = run_pipeline(desc, move || {
+            let mut params =
+                ExampleRsMetaParams::new("region".to_string(), 0, TransformMode::Region);
+            params.region = Some(region);
+            params
+        })
+        .expect("meta is dropped");
+        assert_eq!(params.label, "region");
+        params.region
+    }
+
+    #[cfg(feature = "video-size")]
+    #[test]
+    fn test_region_videoscale() {
+        let region = run_region_pipeline(
+            "videotestsrc num-buffers=1 ! video/x-raw,width=320,height=240 ! identity name=src \
+             ! videoscale ! video/x-raw,width=160,height=120 ! fakesink name=sink",
+            Region::new(40, 20, 100, 60),
+        );
+        assert_eq!(region, Some(Region::new(20, 10, 50, 30)));
+    }
+
+    #[cfg(all(feature = "v1_26", feature = "video-size"))]
+    #[test]
+    fn test_region_videocrop() {
+        let desc =
+            "videotestsrc num-buffers=1 ! video/x-raw,width=320,height=240 ! identity name=src \
+             ! videocrop left=60 top=40 right=60 bottom=40 ! fakesink name=sink";
+        // 一部が切り取られる
+        let region = run_region_pipeline(desc, Region::new(40, 20, 100, 60));
+        assert_eq!(region, Some(Region::new(0, 0, 80, 40)));
+        // 切り取られた範囲の外に出る
+        let region = run_region_pipeline(desc, Region::new(0, 0, 40, 30));
+        assert_eq!(region, None);
+    }
 }
@@ -2,170 +2,151 @@
 //!
 //! Rustでメタデータを実装するサンプルとともに
 //! 内部動作を切り替えた時にどう振る舞うのかを確認する項目を実装している
+//!
+//! 型定義とinit/free、登録処理はexample-rs-sysの`#[derive(GstMeta)]`から生成し
+//! このcrateではメタデータ固有のtransformとserializeを実装する
+
+use std::sync::Mutex;
 
+use ers_meta::{ExampleRsMeta, Region, TransformMode};
 use gst::glib::translate::IntoGlib;
-use once_cell::sync::Lazy;
-use std::ptr;
 
-const METANAME: &[u8] = b"ExampleRsMeta\0";
-const METAAPINAME: &[u8] = b"ExampleRsMetaAPI\0";
+mod region;
+mod serialize;
+mod tags;
+pub use serialize::SERIALIZE_VERSION;
+pub use tags::{meta_api_type_register, MetaTags};
 
-/// transform関数での動作モード
-#[repr(C)]
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
-pub enum TransformMode {
-    // 何もしない
-    Ignore,
-    // 新しいGstBufferにDeepCopyする
-    Copy,
-}
+const COPY_QUARK: &[u8] = b"gst-copy\0";
 
-impl Default for TransformMode {
-    fn default() -> Self {
-        Self::Ignore
+/// 変換の種類に応じて矩形を出力フレームの座標系に合わせる
+///
+/// 出力フレームの外に出た場合はNone、対応していない変換の場合はErrを返す
+unsafe fn transform_region(
+    region: &Region,
+    type_: gst::glib::ffi::GQuark,
+    data: gst::glib::ffi::gpointer,
+) -> Result<Option<Region>, ()> {
+    if type_ == gst::glib::ffi::g_quark_from_static_string(COPY_QUARK.as_ptr() as *const _) {
+        return Ok(Some(*region));
     }
-}
-
-#[derive(Debug, Default)]
-pub struct ExampleRsMetaParams {
-    pub label: String,
-    pub index: i32,
-    pub mode: TransformMode,
-}
-
-impl ExampleRsMetaParams {
-    pub fn new(label: String, index: i32, mode: TransformMode) -> Self {
-        Self { label, index, mode }
+    if type_ == gst_video::ffi::gst_video_meta_transform_scale_get_quark() {
+        return Ok(region::transform_scale(region, data as *const _));
     }
-}
-
-// This is the C type that is actually stored as meta inside the buffers.
-#[repr(C)]
-pub struct ExampleRsMeta {
-    parent: gst::ffi::GstMeta,
-    pub label: String,
-    pub index: i32,
-    pub mode: TransformMode,
-}
-
-impl ExampleRsMeta {
-    pub fn clone_params(&self) -> ExampleRsMetaParams {
-        ExampleRsMetaParams {
-            label: self.label.clone(),
-            index: self.index,
-            mode: self.mode,
+    #[cfg(feature = "v1_26")]
+    {
+        if type_ == region::v1_26::gst_video_meta_transform_matrix_get_quark() {
+            return Ok(region::v1_26::transform_matrix(region, data));
         }
     }
+    Err(())
 }
 
-/// # Safety
-///
-/// MetaAPIにメタデータのtypeを返す関数
-#[no_mangle]
-pub unsafe extern "C" fn example_rs_meta_api_get_type() -> gst::glib::Type {
-    static TYPE: Lazy<gst::glib::Type> = Lazy::new(|| unsafe {
-        let t = gst::glib::translate::from_glib(gst::ffi::gst_meta_api_type_register(
-            METAAPINAME.as_ptr() as *const _,
-            [ptr::null::<std::os::raw::c_char>()].as_ptr() as *mut *const _,
-        ));
-
-        assert_ne!(t, gst::glib::Type::INVALID);
-        t
-    });
-
-    *TYPE
-}
-
-/// # Safety
-///
-/// メタデータの領域を確保して初期化を行う関数
-#[no_mangle]
-pub unsafe extern "C" fn example_rs_meta_init(
-    meta: *mut gst::ffi::GstMeta,
-    params: gst::glib::ffi::gpointer,
-    _buffer: *mut gst::ffi::GstBuffer,
-) -> gst::glib::ffi::gboolean {
-    assert!(!params.is_null());
-
-    let meta = &mut *(meta as *mut ExampleRsMeta);
-    let params = ptr::read(params as *const ExampleRsMetaParams);
-
-    // Need to initialize all our fields correctly here.
-    ptr::write(&mut meta.label, params.label);
-    ptr::write(&mut meta.index, params.index);
-    ptr::write(&mut meta.mode, params.mode);
-
-    true.into_glib()
-}
-
-/// # Safety
-///
-/// メタデータ開放時に呼ぶ関数
-#[no_mangle]
-pub unsafe extern "C" fn example_rs_meta_free(
-    meta: *mut gst::ffi::GstMeta,
-    _buffer: *mut gst::ffi::GstBuffer,
-) {
-    let meta = &mut *(meta as *mut ExampleRsMeta);
-
-    // ヒープにある情報は明示的に開放する
-    ptr::drop_in_place(&mut meta.label);
-}
 /// # Safety
 ///
 /// エレメントで古いバッファから新しいバッファにコピーする時に呼ぶ関数
 /// メタデータの種類や前後のエレメント、Capsなどを考慮して目的通りになるふるまいを設定する
-#[no_mangle]
-pub unsafe extern "C" fn example_rs_meta_transform(
-    dest: *mut gst::ffi::GstBuffer,
-    meta: *mut gst::ffi::GstMeta,
-    _buffer: *mut gst::ffi::GstBuffer,
-    _type_: gst::glib::ffi::GQuark,
-    _data: gst::glib::ffi::gpointer,
-) -> gst::glib::ffi::gboolean {
-    let meta = &*(meta as *mut ExampleRsMeta);
+unsafe fn transform(
+    dest: &mut gst::BufferRef,
+    meta: &ExampleRsMeta,
+    _buffer: &gst::BufferRef,
+    type_: gst::glib::ffi::GQuark,
+    data: gst::glib::ffi::gpointer,
+) -> bool {
     // メタデータの中身によって処理を変更する
-    match meta.mode {
+    match meta.mode() {
         // コピーしない
         // passthroughの場合はメタデータがあるが、それ以外では消える仕様
         TransformMode::Ignore => {}
         // シンプルにデータをコピーする
         TransformMode::Copy => {
-            let dest_buf = gst::BufferRef::from_mut_ptr(dest);
-            let mut params = std::mem::ManuallyDrop::new(meta.clone_params());
-            let _meta = gst::ffi::gst_buffer_add_meta(
-                dest_buf.as_mut_ptr(),
-                example_rs_meta_get_info(),
-                &mut *params as *mut ExampleRsMetaParams as gst::glib::ffi::gpointer,
-            ) as *mut ExampleRsMeta;
+            ExampleRsMeta::add(dest, meta.to_params());
+        }
+        // 変換の種類を見て矩形を合わせ込む
+        // 出力フレームの外に出た場合は矩形だけを消してlabel等は残す
+        TransformMode::Region => {
+            let mut params = meta.to_params();
+            if let Some(region) = meta.region() {
+                match transform_region(&region, type_, data) {
+                    Ok(region) => params.region = region,
+                    Err(_) => return false,
+                }
+            }
+            ExampleRsMeta::add(dest, params);
         }
     }
-    true.into_glib()
+    true
 }
 
-/// Register the meta itself with its functions.
-/// ここまでに定義した関数でメタデータ情報をGstに登録する
-/// 関数はNone登録も出来るが適切に設定することで
-/// 初期化失敗、メモリリーク、Transformでの消失を避けることが出来る
+#[cfg(not(feature = "v1_24"))]
+ers_meta::export_example_rs_meta!(transform = transform, api = api_type_register);
+
+#[cfg(feature = "v1_24")]
+ers_meta::export_example_rs_meta!(
+    transform = transform,
+    register = register_meta_info,
+    api = api_type_register
+);
+
+/// MetaAPIに付けるタグ
+///
+/// 登録前は`example_rs_meta_api_register`で変更できる
+struct ApiTags {
+    tags: MetaTags,
+    registered: bool,
+}
+
+static API_TAGS: Mutex<ApiTags> = Mutex::new(ApiTags {
+    tags: MetaTags::ContentIndependent,
+    registered: false,
+});
+
+/// # Safety
+///
+/// タグを選んでMetaAPIを登録する関数
+/// 既に登録済みの場合は同じタグであればTRUEを返す
 #[no_mangle]
-pub fn example_rs_meta_get_info() -> *const gst::ffi::GstMetaInfo {
-    struct MetaInfo(ptr::NonNull<gst::ffi::GstMetaInfo>);
-    unsafe impl Send for MetaInfo {}
-    unsafe impl Sync for MetaInfo {}
+pub unsafe extern "C" fn example_rs_meta_api_register(tags: u32) -> gst::glib::ffi::gboolean {
+    let tags = MetaTags::from(tags);
+    {
+        let mut api = API_TAGS.lock().unwrap();
+        if !api.registered {
+            api.tags = tags;
+        }
+    }
+    // 登録自体はexample_rs_meta_api_get_typeで一度だけ行う
+    example_rs_meta_api_get_type();
+    (API_TAGS.lock().unwrap().tags == tags).into_glib()
+}
 
-    static META_INFO: Lazy<MetaInfo> = Lazy::new(|| unsafe {
-        MetaInfo(
-            ptr::NonNull::new(gst::ffi::gst_meta_register(
-                example_rs_meta_api_get_type().into_glib(),
-                crate::METANAME.as_ptr() as *const _,
-                std::mem::size_of::<ExampleRsMeta>(),
-                Some(example_rs_meta_init),
-                Some(example_rs_meta_free),
-                Some(example_rs_meta_transform),
-            ) as *mut gst::ffi::GstMetaInfo)
-            .expect("Failed to register meta API"),
-        )
-    });
+/// example_rs_meta_api_get_typeから一度だけ呼ばれる
+unsafe fn api_type_register() -> gst::glib::Type {
+    let tags = {
+        let mut api = API_TAGS.lock().unwrap();
+        api.registered = true;
+        api.tags
+    };
+    meta_api_type_register(ExampleRsMeta::API_NAME, tags)
+}
 
-    META_INFO.0.as_ptr()
+/// GStreamer 1.24以降ではserialize/deserialize関数も登録して
+/// gdppayなどでプロセスをまたいでもメタデータが残るようにする
+#[cfg(feature = "v1_24")]
+unsafe fn register_meta_info() -> *const gst::ffi::GstMetaInfo {
+    use serialize::v1_24;
+
+    let info = v1_24::gst_meta_info_new(
+        example_rs_meta_api_get_type().into_glib(),
+        ExampleRsMeta::META_NAME.as_ptr() as *const _,
+        std::mem::size_of::<ExampleRsMeta>(),
+    );
+    if info.is_null() {
+        return std::ptr::null();
+    }
+    (*info).init_func = Some(example_rs_meta_init);
+    (*info).free_func = Some(example_rs_meta_free);
+    (*info).transform_func = Some(example_rs_meta_transform);
+    (*info).serialize_func = Some(v1_24::serialize_func);
+    (*info).deserialize_func = Some(v1_24::deserialize_func);
+    v1_24::gst_meta_info_register(info)
 }
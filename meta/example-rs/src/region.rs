@@ -0,0 +1,58 @@
+//! フレーム内の位置を表すメタデータの変換
+//!
+//! videoscaleやvideocropを通過した時に矩形を出力フレームの座標系に合わせる
+
+use ers_meta::Region;
+use gst_video::ffi::{GstVideoInfo, GstVideoMetaTransform};
+
+unsafe fn video_size(info: *const GstVideoInfo) -> Option<(i32, i32)> {
+    info.as_ref().map(|info| (info.width, info.height))
+}
+
+/// GST_VIDEO_META_TRANSFORM_IS_SCALEの場合の変換
+///
+/// # Safety
+///
+/// `data`はGstVideoMetaTransformを指していること
+pub(crate) unsafe fn transform_scale(
+    region: &Region,
+    data: *const GstVideoMetaTransform,
+) -> Option<Region> {
+    let trans = data.as_ref()?;
+    region.scale(video_size(trans.in_info)?, video_size(trans.out_info)?)
+}
+
+/// GStreamer 1.26で追加された行列による変換(videocropなど)
+///
+/// gstreamer-video-sys 0.19には定義がないため関数だけを宣言し
+/// GstVideoMetaTransformMatrixの中身はGStreamer側の関数で扱う
+#[cfg(feature = "v1_26")]
+pub(crate) mod v1_26 {
+    use ers_meta::Region;
+    use gst::glib::ffi::{gboolean, gconstpointer, GQuark, GFALSE};
+    use gst_video::ffi::GstVideoRectangle;
+
+    extern "C" {
+        pub fn gst_video_meta_transform_matrix_get_quark() -> GQuark;
+        fn gst_video_meta_transform_matrix_rectangle_clipped(
+            transform: gconstpointer,
+            rect: *mut GstVideoRectangle,
+        ) -> gboolean;
+    }
+
+    /// # Safety
+    ///
+    /// `data`はGstVideoMetaTransformMatrixを指していること
+    pub(crate) unsafe fn transform_matrix(region: &Region, data: gconstpointer) -> Option<Region> {
+        let mut rect = GstVideoRectangle {
+            x: region.x,
+            y: region.y,
+            w: region.width,
+            h: region.height,
+        };
+        if gst_video_meta_transform_matrix_rectangle_clipped(data, &mut rect) == GFALSE {
+            return None;
+        }
+        Some(Region::new(rect.x, rect.y, rect.w, rect.h))
+    }
+}
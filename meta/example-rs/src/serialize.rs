@@ -0,0 +1,233 @@
+//! ExampleRsMetaのシリアライズ
+//!
+//! gdppayやshm、TCPなどプロセスをまたぐ経路でメタデータを失わないためのバイト列表現
+//!
+//! version 2 (big endian)
+//!
+//! |offset|size|field|
+//! |---|---|---|
+//! |0|4|index: i32|
+//! |4|4|mode: u32|
+//! |8|4|label length: u32|
+//! |12|n|label: UTF-8|
+//! |12+n|1|has region: u8|
+//! |13+n|16|region: x, y, width, height (i32)|
+//!
+//! version 1はregionを持たない
+
+use std::ptr;
+
+use ers_meta::{ExampleRsMeta, ExampleRsMetaParams, Region, TransformMode};
+
+/// 現在のバイト列のバージョン
+pub const SERIALIZE_VERSION: u8 = 2;
+
+const HEADER_SIZE: usize = 12;
+const REGION_SIZE: usize = 16;
+
+// 未知の値は壊れたデータとして扱う
+fn mode_from_raw(v: u32) -> Option<TransformMode> {
+    match v {
+        0 => Some(TransformMode::Ignore),
+        1 => Some(TransformMode::Copy),
+        2 => Some(TransformMode::Region),
+        _ => None,
+    }
+}
+
+fn to_bytes(meta: &ExampleRsMeta) -> Vec<u8> {
+    let label = meta.label().as_bytes();
+    let mut data = Vec::with_capacity(HEADER_SIZE + label.len());
+    data.extend_from_slice(&meta.index().to_be_bytes());
+    data.extend_from_slice(&(meta.mode() as u32).to_be_bytes());
+    data.extend_from_slice(&(label.len() as u32).to_be_bytes());
+    data.extend_from_slice(label);
+    match meta.region() {
+        Some(region) => {
+            data.push(1);
+            for v in [region.x, region.y, region.width, region.height] {
+                data.extend_from_slice(&v.to_be_bytes());
+            }
+        }
+        None => data.push(0),
+    }
+    data
+}
+
+fn read_i32(data: &[u8], offset: usize) -> Option<i32> {
+    Some(i32::from_be_bytes(
+        data.get(offset..offset + 4)?.try_into().ok()?,
+    ))
+}
+
+fn from_bytes(data: &[u8], version: u8) -> Option<ExampleRsMetaParams> {
+    if !(1..=SERIALIZE_VERSION).contains(&version) || data.len() < HEADER_SIZE {
+        return None;
+    }
+    let index = read_i32(data, 0)?;
+    let mode = mode_from_raw(read_i32(data, 4)? as u32)?;
+    let len = read_i32(data, 8)? as u32 as usize;
+    let end = HEADER_SIZE.checked_add(len)?;
+    let label = std::str::from_utf8(data.get(HEADER_SIZE..end)?)
+        .ok()?
+        .to_string();
+    let mut params = ExampleRsMetaParams::new(label, index, mode);
+
+    // 古いバージョンを読めるように分岐を残していく
+    if version >= 2 {
+        match data.get(end)? {
+            0 => {}
+            1 => {
+                let offset = end + 1;
+                if data.len() < offset + REGION_SIZE {
+                    return None;
+                }
+                params.region = Some(Region::new(
+                    read_i32(data, offset)?,
+                    read_i32(data, offset + 4)?,
+                    read_i32(data, offset + 8)?,
+                    read_i32(data, offset + 12)?,
+                ));
+            }
+            _ => return None,
+        }
+    }
+    Some(params)
+}
+
+/// # Safety
+///
+/// メタデータをバイト列に変換する
+/// `dest`がnullもしくは`size`が足りない場合は書き込まずに必要なサイズだけを返す
+/// `version`がnullでなければバイト列のバージョンを書き込む
+#[no_mangle]
+pub unsafe extern "C" fn example_rs_meta_serialize(
+    meta: *const gst::ffi::GstMeta,
+    dest: *mut u8,
+    size: usize,
+    version: *mut u8,
+) -> usize {
+    assert!(!meta.is_null());
+
+    let data = to_bytes(&*(meta as *const ExampleRsMeta));
+    if !dest.is_null() && size >= data.len() {
+        ptr::copy_nonoverlapping(data.as_ptr(), dest, data.len());
+    }
+    if !version.is_null() {
+        *version = SERIALIZE_VERSION;
+    }
+    data.len()
+}
+
+/// # Safety
+///
+/// バイト列からメタデータを復元してバッファに追加する
+/// 未知のバージョンや壊れたデータの場合はnullを返す
+#[no_mangle]
+pub unsafe extern "C" fn example_rs_meta_deserialize(
+    buffer: *mut gst::ffi::GstBuffer,
+    data: *const u8,
+    size: usize,
+    version: u8,
+) -> *mut gst::ffi::GstMeta {
+    if buffer.is_null() || data.is_null() {
+        return ptr::null_mut();
+    }
+    let data = std::slice::from_raw_parts(data, size);
+    match from_bytes(data, version) {
+        Some(params) => {
+            let meta = ExampleRsMeta::add(gst::BufferRef::from_mut_ptr(buffer), params);
+            &*meta as *const ExampleRsMeta as *mut gst::ffi::GstMeta
+        }
+        None => ptr::null_mut(),
+    }
+}
+
+/// GStreamer 1.24で追加されたGstMetaのserialize/deserialize関数の登録
+///
+/// gstreamer-sys 0.19には定義がないため必要な部分だけを宣言する
+#[cfg(feature = "v1_24")]
+pub(crate) mod v1_24 {
+    use gst::glib::translate::IntoGlib;
+
+    use super::*;
+
+    #[repr(C)]
+    pub struct GstByteArrayInterface {
+        pub data: *mut u8,
+        pub len: usize,
+        pub resize: Option<
+            unsafe extern "C" fn(*mut GstByteArrayInterface, usize) -> gst::glib::ffi::gboolean,
+        >,
+        _gst_reserved: [gst::glib::ffi::gpointer; 4],
+    }
+
+    // GstMetaInfoはGStreamerが確保するので拡張されたレイアウトで扱う
+    #[repr(C)]
+    pub struct GstMetaInfo {
+        pub api: gst::glib::ffi::GType,
+        pub type_: gst::glib::ffi::GType,
+        pub size: usize,
+        pub init_func: gst::ffi::GstMetaInitFunction,
+        pub free_func: gst::ffi::GstMetaFreeFunction,
+        pub transform_func: gst::ffi::GstMetaTransformFunction,
+        pub serialize_func: Option<
+            unsafe extern "C" fn(
+                *const gst::ffi::GstMeta,
+                *mut GstByteArrayInterface,
+                *mut u8,
+            ) -> gst::glib::ffi::gboolean,
+        >,
+        pub deserialize_func: Option<
+            unsafe extern "C" fn(
+                *const gst::ffi::GstMetaInfo,
+                *mut gst::ffi::GstBuffer,
+                *const u8,
+                usize,
+                u8,
+            ) -> *mut gst::ffi::GstMeta,
+        >,
+        pub clear_func:
+            Option<unsafe extern "C" fn(*mut gst::ffi::GstBuffer, *mut gst::ffi::GstMeta)>,
+    }
+
+    extern "C" {
+        pub fn gst_meta_info_new(
+            api: gst::glib::ffi::GType,
+            impl_: *const std::os::raw::c_char,
+            size: usize,
+        ) -> *mut GstMetaInfo;
+        pub fn gst_meta_info_register(info: *mut GstMetaInfo) -> *const gst::ffi::GstMetaInfo;
+    }
+
+    pub(crate) unsafe extern "C" fn serialize_func(
+        meta: *const gst::ffi::GstMeta,
+        data: *mut GstByteArrayInterface,
+        version: *mut u8,
+    ) -> gst::glib::ffi::gboolean {
+        let data = &mut *data;
+        let resize = match data.resize {
+            Some(resize) => resize,
+            None => return false.into_glib(),
+        };
+        let bytes = to_bytes(&*(meta as *const ExampleRsMeta));
+        // gst_byte_array_interface_append_dataはinline関数なので同じ処理を行う
+        let offset = data.len;
+        if resize(data, offset + bytes.len()) == gst::glib::ffi::GFALSE {
+            return false.into_glib();
+        }
+        ptr::copy_nonoverlapping(bytes.as_ptr(), data.data.add(offset), bytes.len());
+        *version = SERIALIZE_VERSION;
+        true.into_glib()
+    }
+
+    pub(crate) unsafe extern "C" fn deserialize_func(
+        _info: *const gst::ffi::GstMetaInfo,
+        buffer: *mut gst::ffi::GstBuffer,
+        data: *const u8,
+        size: usize,
+        version: u8,
+    ) -> *mut gst::ffi::GstMeta {
+        example_rs_meta_deserialize(buffer, data, size, version)
+    }
+}
@@ -0,0 +1,26 @@
+//! MetaAPIに付けるタグ
+//!
+//! videoconvertやvideoscale、エンコーダなどはタグを見てメタデータを
+//! そのままコピーするか、transformを呼ぶか、破棄するかを決める
+
+use std::ptr;
+
+pub use ers_meta::MetaTags;
+
+/// タグを付けてMetaAPIを登録する
+///
+/// # Safety
+///
+/// `name`はNUL終端していること
+pub unsafe fn meta_api_type_register(name: &[u8], tags: MetaTags) -> gst::glib::Type {
+    let mut tags = tags
+        .tags()
+        .iter()
+        .map(|tag| tag.as_ptr() as *const std::os::raw::c_char)
+        .collect::<Vec<_>>();
+    tags.push(ptr::null());
+    gst::glib::translate::from_glib(gst::ffi::gst_meta_api_type_register(
+        name.as_ptr() as *const _,
+        tags.as_mut_ptr(),
+    ))
+}
@@ -0,0 +1,658 @@
+//! GstMetaの定型実装を生成するderiveマクロ
+//!
+//! `#[repr(C)]`の構造体1つからMetaAPIの登録、FFI関数のexport、
+//! `MetaAPI`の実装とadd/remove/アクセサを持つwrapperを生成する
+//!
+//! ```ignore
+//! #[derive(GstMeta)]
+//! #[gst_meta(
+//!     name = "ExampleRsMeta",
+//!     symbol = "example_rs_meta",
+//!     wrapper = "crate::ExampleRsMeta",
+//!     params = "crate::ExampleRsMetaParams",
+//!     tags = "video-size",
+//!     link = "example_rs_meta"
+//! )]
+//! #[repr(C)]
+//! pub struct ExampleRsMeta {
+//!     parent: gst::ffi::GstMeta,
+//!     pub label: String,
+//!     #[gst_meta(copy)]
+//!     pub index: i32,
+//! }
+//! ```
+//!
+//! |key|内容|
+//! |---|---|
+//! |name|GstMetaInfoの登録名|
+//! |api|MetaAPIの登録名。省略時は`{name}API`|
+//! |symbol|exportするC関数の接頭辞|
+//! |wrapper|`#[repr(transparent)]`で構造体を包むRust向けの型|
+//! |params|`gst_buffer_add_meta`に渡す型。構造体と同じ名前のフィールドを持つこと|
+//! |tags|MetaAPIのタグ `content-independent`(省略時) or `video-size`|
+//! |link|テスト時にリンクするSO名|
+//!
+//! フィールドに`#[gst_meta(copy)]`を付けるとアクセサは値を返す。
+//! `String`は`&str`、それ以外は参照を返す
+//!
+//! 構造体を定義したcrateにはextern宣言とwrapperの実装が生成される。
+//! SOを作るcrateで`export_{symbol}!()`を呼ぶとC ABIの関数がexportされる。
+//! transformと登録処理は`transform = 関数名`, `register = 関数名`で差し替えられる。
+//! `api = 関数名`を指定するとtagsの代わりにその関数でMetaAPIを登録するので、
+//! 実行時にタグを選べるようになる
+
+use proc_macro::TokenStream;
+use proc_macro2::{Span, TokenStream as TokenStream2};
+use quote::{format_ident, quote};
+use syn::{
+    parse_macro_input, Attribute, Data, DeriveInput, Error, Fields, Ident, Lit, LitByteStr, LitStr,
+    Meta, NestedMeta, Path, Type, Visibility,
+};
+
+/// MetaAPIに付けるタグの組み合わせ
+#[derive(Debug, Clone, Copy)]
+enum Tags {
+    ContentIndependent,
+    VideoSize,
+}
+
+impl Tags {
+    fn parse(lit: &LitStr) -> syn::Result<Self> {
+        match lit.value().as_str() {
+            "content-independent" => Ok(Self::ContentIndependent),
+            "video-size" => Ok(Self::VideoSize),
+            _ => Err(Error::new_spanned(
+                lit,
+                "expected \"content-independent\" or \"video-size\"",
+            )),
+        }
+    }
+
+    fn names(&self) -> &'static [&'static str] {
+        match self {
+            Self::ContentIndependent => &[],
+            Self::VideoSize => &["video", "size"],
+        }
+    }
+}
+
+struct Options {
+    name: String,
+    api: String,
+    symbol: String,
+    wrapper: Path,
+    params: Path,
+    tags: Tags,
+    link: Option<String>,
+}
+
+struct Field {
+    ident: Ident,
+    ty: Type,
+    copy: bool,
+}
+
+#[proc_macro_derive(GstMeta, attributes(gst_meta))]
+pub fn derive_gst_meta(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    match expand(&input) {
+        Ok(tokens) => tokens.into(),
+        Err(e) => e.to_compile_error().into(),
+    }
+}
+
+fn gst_meta_attrs(attrs: &[Attribute]) -> syn::Result<Vec<NestedMeta>> {
+    let mut nested = vec![];
+    for attr in attrs.iter().filter(|a| a.path.is_ident("gst_meta")) {
+        match attr.parse_meta()? {
+            Meta::List(list) => nested.extend(list.nested),
+            meta => return Err(Error::new_spanned(meta, "expected #[gst_meta(...)]")),
+        }
+    }
+    Ok(nested)
+}
+
+// export側からは$crateで参照するのでcrateから始まるパスに限る
+fn crate_path(lit: &LitStr) -> syn::Result<Path> {
+    let path: Path = lit.parse()?;
+    match path.segments.first() {
+        Some(seg) if path.leading_colon.is_none() && seg.ident == "crate" => Ok(path),
+        _ => Err(Error::new_spanned(lit, "path must start with `crate::`")),
+    }
+}
+
+fn parse_options(input: &DeriveInput) -> syn::Result<Options> {
+    let mut name = None;
+    let mut api = None;
+    let mut symbol = None;
+    let mut wrapper = None;
+    let mut params = None;
+    let mut tags = Tags::ContentIndependent;
+    let mut link = None;
+
+    for nested in gst_meta_attrs(&input.attrs)? {
+        let nv = match nested {
+            NestedMeta::Meta(Meta::NameValue(nv)) => nv,
+            other => return Err(Error::new_spanned(other, "expected key = \"value\"")),
+        };
+        let value = match &nv.lit {
+            Lit::Str(s) => s.clone(),
+            lit => return Err(Error::new_spanned(lit, "expected string literal")),
+        };
+        let key = nv
+            .path
+            .get_ident()
+            .map(|i| i.to_string())
+            .unwrap_or_default();
+        match key.as_str() {
+            "name" => name = Some(value.value()),
+            "api" => api = Some(value.value()),
+            "symbol" => symbol = Some(value.value()),
+            "wrapper" => wrapper = Some(crate_path(&value)?),
+            "params" => params = Some(crate_path(&value)?),
+            "tags" => tags = Tags::parse(&value)?,
+            "link" => link = Some(value.value()),
+            _ => return Err(Error::new_spanned(&nv.path, "unknown gst_meta option")),
+        }
+    }
+
+    let missing = |key: &str| {
+        Error::new(
+            Span::call_site(),
+            format!("missing #[gst_meta({} = \"...\")]", key),
+        )
+    };
+    let name = name.ok_or_else(|| missing("name"))?;
+    Ok(Options {
+        api: api.unwrap_or_else(|| format!("{}API", name)),
+        name,
+        symbol: symbol.ok_or_else(|| missing("symbol"))?,
+        wrapper: wrapper.ok_or_else(|| missing("wrapper"))?,
+        params: params.ok_or_else(|| missing("params"))?,
+        tags,
+        link,
+    })
+}
+
+fn type_is(ty: &Type, name: &str) -> bool {
+    match ty {
+        Type::Path(p) if p.qself.is_none() => {
+            p.path.segments.last().map_or(false, |s| s.ident == name)
+        }
+        _ => false,
+    }
+}
+
+fn parse_fields(input: &DeriveInput) -> syn::Result<Vec<Field>> {
+    let is_repr_c = input
+        .attrs
+        .iter()
+        .any(|a| a.path.is_ident("repr") && a.tokens.to_string().contains('C'));
+    if !is_repr_c {
+        return Err(Error::new_spanned(
+            &input.ident,
+            "GstMeta requires #[repr(C)]",
+        ));
+    }
+    let fields = match &input.data {
+        Data::Struct(s) => match &s.fields {
+            Fields::Named(f) => &f.named,
+            _ => return Err(Error::new_spanned(&input.ident, "expected named fields")),
+        },
+        _ => {
+            return Err(Error::new_spanned(
+                &input.ident,
+                "GstMeta supports only struct",
+            ))
+        }
+    };
+
+    let mut iter = fields.iter();
+    // 先頭はGstMetaでなければGStreamerから扱えない
+    match iter.next() {
+        Some(f) if type_is(&f.ty, "GstMeta") => {}
+        _ => {
+            return Err(Error::new_spanned(
+                &input.ident,
+                "first field must be gst::ffi::GstMeta",
+            ))
+        }
+    }
+
+    iter.map(|f| {
+        // exportする側のcrateから初期化、開放するので公開する
+        if !matches!(f.vis, Visibility::Public(_)) {
+            return Err(Error::new_spanned(f, "GstMeta field must be pub"));
+        }
+        let mut copy = false;
+        for nested in gst_meta_attrs(&f.attrs)? {
+            match nested {
+                NestedMeta::Meta(Meta::Path(p)) if p.is_ident("copy") => copy = true,
+                other => return Err(Error::new_spanned(other, "unknown gst_meta option")),
+            }
+        }
+        Ok(Field {
+            ident: f.ident.clone().unwrap(),
+            ty: f.ty.clone(),
+            copy,
+        })
+    })
+    .collect()
+}
+
+fn byte_str(s: &str) -> LitByteStr {
+    LitByteStr::new(format!("{}\0", s).as_bytes(), Span::call_site())
+}
+
+// `crate::A::B`を`$crate::A::B`に置き換える
+fn dollar_crate(path: &Path) -> TokenStream2 {
+    let rest = path.segments.iter().skip(1);
+    quote!($crate #(:: #rest)*)
+}
+
+fn expand(input: &DeriveInput) -> syn::Result<TokenStream2> {
+    let opts = parse_options(input)?;
+    let fields = parse_fields(input)?;
+
+    let ident = &input.ident;
+    let wrapper = &opts.wrapper;
+    let params = &opts.params;
+    let ex_wrapper = dollar_crate(wrapper);
+    let ex_params = dollar_crate(params);
+
+    let get_info = format_ident!("{}_get_info", opts.symbol);
+    let api_get_type = format_ident!("{}_api_get_type", opts.symbol);
+    let init = format_ident!("{}_init", opts.symbol);
+    let free = format_ident!("{}_free", opts.symbol);
+    let transform = format_ident!("{}_transform", opts.symbol);
+    let register = format_ident!("__{}_register", opts.symbol);
+    let export = format_ident!("export_{}", opts.symbol);
+
+    let meta_name = byte_str(&opts.name);
+    let api_name = byte_str(&opts.api);
+    let tags = opts
+        .tags
+        .names()
+        .iter()
+        .map(|t| byte_str(t))
+        .collect::<Vec<_>>();
+    let link = opts
+        .link
+        .as_ref()
+        .map(|name| quote!(#[cfg_attr(test, link(name = #name))]));
+
+    let field_idents = fields.iter().map(|f| &f.ident).collect::<Vec<_>>();
+    let accessors = fields.iter().map(|f| {
+        let ident = &f.ident;
+        let ty = &f.ty;
+        let alias = format!("get_{}", ident);
+        if f.copy {
+            quote! {
+                #[doc(alias = #alias)]
+                pub fn #ident(&self) -> #ty {
+                    self.0.#ident
+                }
+            }
+        } else if type_is(ty, "String") {
+            quote! {
+                #[doc(alias = #alias)]
+                pub fn #ident(&self) -> &str {
+                    self.0.#ident.as_str()
+                }
+            }
+        } else {
+            quote! {
+                #[doc(alias = #alias)]
+                pub fn #ident(&self) -> &#ty {
+                    &self.0.#ident
+                }
+            }
+        }
+    });
+
+    let export_doc = format!(
+        "`{}`をGStreamerに登録するC ABIの関数をexportする\n\n\
+         呼び出し側のcrateで`gst`(gstreamer)を参照できること",
+        opts.name
+    );
+
+    Ok(quote! {
+        #link
+        extern "C" {
+            pub fn #get_info() -> *const gst::ffi::GstMetaInfo;
+            pub fn #api_get_type() -> gst::glib::Type;
+        }
+
+        // Metas must be Send+Sync.
+        unsafe impl Send for #wrapper {}
+        unsafe impl Sync for #wrapper {}
+
+        unsafe impl gst::prelude::MetaAPI for #wrapper {
+            type GstType = #ident;
+
+            fn meta_api() -> gst::glib::Type {
+                unsafe { #api_get_type() }
+            }
+        }
+
+        impl #wrapper {
+            /// GstMetaInfoの登録名(NUL終端)
+            pub const META_NAME: &'static [u8] = #meta_name;
+            /// MetaAPIの登録名(NUL終端)
+            pub const API_NAME: &'static [u8] = #api_name;
+            /// deriveで指定したタグ(NUL終端)
+            ///
+            /// exportで`api`を指定した場合はその関数で登録したタグになる
+            pub const TAGS: &'static [&'static [u8]] = &[#(#tags),*];
+
+            pub fn add(
+                buffer: &mut gst::BufferRef,
+                params: #params,
+            ) -> gst::MetaRefMut<Self, gst::meta::Standalone> {
+                use gst::prelude::*;
+                unsafe {
+                    // 所有権はinitでメタデータに移る
+                    let mut params = std::mem::ManuallyDrop::new(params);
+                    let meta = gst::ffi::gst_buffer_add_meta(
+                        buffer.as_mut_ptr(),
+                        #get_info(),
+                        &mut *params as *mut #params as gst::glib::ffi::gpointer,
+                    ) as *mut #ident;
+
+                    Self::from_mut_ptr(buffer, meta)
+                }
+            }
+
+            pub fn remove(buffer: &mut gst::BufferRef) -> Option<#params> {
+                let meta = buffer.meta_mut::<Self>()?;
+                let params = meta.to_params();
+                meta.remove().unwrap();
+                Some(params)
+            }
+
+            /// 同じ内容のメタデータを別のバッファに追加するためのパラメータ
+            pub fn to_params(&self) -> #params {
+                #params {
+                    #(#field_idents: Clone::clone(&self.0.#field_idents),)*
+                }
+            }
+
+            #(#accessors)*
+        }
+
+        #[doc = #export_doc]
+        #[macro_export]
+        macro_rules! #export {
+            (
+                $(transform = $transform:ident)? $(,)?
+                $(register = $register:ident)? $(,)?
+                $(api = $api:ident)? $(,)?
+            ) => {
+                /// # Safety
+                ///
+                /// MetaAPIにメタデータのtypeを返す関数
+                #[no_mangle]
+                #[allow(unreachable_code)]
+                pub unsafe extern "C" fn #api_get_type() -> gst::glib::Type {
+                    static ONCE: std::sync::Once = std::sync::Once::new();
+                    static mut TYPE: gst::glib::ffi::GType = 0;
+                    ONCE.call_once(|| {
+                        $(
+                            TYPE = gst::glib::translate::IntoGlib::into_glib($api());
+                            return;
+                        )?
+                        let mut tags = [
+                            #(#tags.as_ptr() as *const std::os::raw::c_char,)*
+                            std::ptr::null(),
+                        ];
+                        TYPE = gst::ffi::gst_meta_api_type_register(
+                            #api_name.as_ptr() as *const _,
+                            tags.as_mut_ptr(),
+                        );
+                    });
+                    let t = gst::glib::translate::from_glib(TYPE);
+                    assert_ne!(t, gst::glib::Type::INVALID);
+                    t
+                }
+
+                /// # Safety
+                ///
+                /// メタデータの領域を確保して初期化を行う関数
+                #[no_mangle]
+                pub unsafe extern "C" fn #init(
+                    meta: *mut gst::ffi::GstMeta,
+                    params: gst::glib::ffi::gpointer,
+                    _buffer: *mut gst::ffi::GstBuffer,
+                ) -> gst::glib::ffi::gboolean {
+                    assert!(!params.is_null());
+
+                    let meta = &mut *(meta
+                        as *mut <#ex_wrapper as gst::prelude::MetaAPI>::GstType);
+                    let params = std::ptr::read(params as *const #ex_params);
+
+                    // Need to initialize all our fields correctly here.
+                    #(std::ptr::write(&mut meta.#field_idents, params.#field_idents);)*
+
+                    gst::glib::ffi::GTRUE
+                }
+
+                /// # Safety
+                ///
+                /// メタデータ開放時に呼ぶ関数
+                #[no_mangle]
+                pub unsafe extern "C" fn #free(
+                    meta: *mut gst::ffi::GstMeta,
+                    _buffer: *mut gst::ffi::GstBuffer,
+                ) {
+                    let meta = &mut *(meta
+                        as *mut <#ex_wrapper as gst::prelude::MetaAPI>::GstType);
+
+                    // ヒープにある情報は明示的に開放する
+                    #(std::ptr::drop_in_place(&mut meta.#field_idents);)*
+                }
+
+                /// # Safety
+                ///
+                /// エレメントで古いバッファから新しいバッファにコピーする時に呼ぶ関数
+                /// 指定がなければgst-copyの場合だけコピーする
+                #[no_mangle]
+                #[allow(unreachable_code)]
+                pub unsafe extern "C" fn #transform(
+                    dest: *mut gst::ffi::GstBuffer,
+                    meta: *mut gst::ffi::GstMeta,
+                    buffer: *mut gst::ffi::GstBuffer,
+                    type_: gst::glib::ffi::GQuark,
+                    data: gst::glib::ffi::gpointer,
+                ) -> gst::glib::ffi::gboolean {
+                    let dest = gst::BufferRef::from_mut_ptr(dest);
+                    let meta = &*(meta as *const #ex_wrapper);
+                    let _buffer = gst::BufferRef::from_ptr(buffer);
+                    $(
+                        return gst::glib::translate::IntoGlib::into_glib(
+                            $transform(dest, meta, _buffer, type_, data),
+                        );
+                    )?
+                    let _ = data;
+                    let copy = gst::glib::ffi::g_quark_from_static_string(
+                        b"gst-copy\0".as_ptr() as *const _,
+                    );
+                    if type_ == copy {
+                        #ex_wrapper::add(dest, meta.to_params());
+                        gst::glib::ffi::GTRUE
+                    } else {
+                        gst::glib::ffi::GFALSE
+                    }
+                }
+
+                #[allow(unreachable_code)]
+                unsafe fn #register() -> *const gst::ffi::GstMetaInfo {
+                    $(return $register();)?
+                    gst::ffi::gst_meta_register(
+                        gst::glib::translate::IntoGlib::into_glib(#api_get_type()),
+                        #meta_name.as_ptr() as *const _,
+                        std::mem::size_of::<#ex_wrapper>(),
+                        Some(#init),
+                        Some(#free),
+                        Some(#transform),
+                    )
+                }
+
+                /// Register the meta itself with its functions.
+                /// 定義した関数でメタデータ情報をGstに登録する
+                #[no_mangle]
+                pub extern "C" fn #get_info() -> *const gst::ffi::GstMetaInfo {
+                    static ONCE: std::sync::Once = std::sync::Once::new();
+                    static mut INFO: *const gst::ffi::GstMetaInfo = std::ptr::null();
+                    unsafe {
+                        ONCE.call_once(|| {
+                            INFO = #register();
+                            assert!(!INFO.is_null(), "Failed to register meta API");
+                        });
+                        INFO
+                    }
+                }
+            };
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const OPTIONS: &str = r#"#[gst_meta(
+        name = "TestMeta",
+        symbol = "test_meta",
+        wrapper = "crate::TestMeta",
+        params = "crate::TestMetaParams"
+    )]"#;
+
+    fn input(attrs: &str, fields: &str) -> DeriveInput {
+        syn::parse_str(&format!("{} pub struct TestMeta {{ {} }}", attrs, fields)).unwrap()
+    }
+
+    fn meta(attrs: &str) -> DeriveInput {
+        input(
+            &format!("#[repr(C)] {}", attrs),
+            "parent: gst::ffi::GstMeta, pub label: String, #[gst_meta(copy)] pub index: i32",
+        )
+    }
+
+    fn error(input: &DeriveInput) -> String {
+        expand(input).unwrap_err().to_string()
+    }
+
+    #[test]
+    fn test_parse_options() {
+        let opts = parse_options(&meta(OPTIONS)).unwrap();
+        assert_eq!(opts.name, "TestMeta");
+        // apiは省略するとnameから決まる
+        assert_eq!(opts.api, "TestMetaAPI");
+        assert_eq!(opts.symbol, "test_meta");
+        assert_eq!(
+            dollar_crate(&opts.wrapper).to_string(),
+            quote!($crate::TestMeta).to_string()
+        );
+        assert_eq!(
+            dollar_crate(&opts.params).to_string(),
+            quote!($crate::TestMetaParams).to_string()
+        );
+        assert!(opts.tags.names().is_empty());
+        assert!(opts.link.is_none());
+
+        // 属性は分けて書ける
+        let opts = parse_options(&meta(&format!(
+            r#"{} #[gst_meta(api = "OtherAPI", tags = "video-size", link = "test_meta")]"#,
+            OPTIONS
+        )))
+        .unwrap();
+        assert_eq!(opts.api, "OtherAPI");
+        assert_eq!(opts.tags.names(), ["video", "size"]);
+        assert_eq!(opts.link.as_deref(), Some("test_meta"));
+    }
+
+    #[test]
+    fn test_parse_fields() {
+        let fields = parse_fields(&meta(OPTIONS)).unwrap();
+        // 先頭のGstMetaは含まない
+        let fields = fields
+            .iter()
+            .map(|f| (f.ident.to_string(), f.copy))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            fields,
+            [("label".to_string(), false), ("index".to_string(), true)]
+        );
+    }
+
+    #[test]
+    fn test_expand() {
+        let tokens = expand(&meta(OPTIONS)).unwrap().to_string();
+        for ident in [
+            "test_meta_get_info",
+            "test_meta_api_get_type",
+            "test_meta_init",
+            "test_meta_free",
+            "test_meta_transform",
+            "export_test_meta",
+        ] {
+            assert!(tokens.contains(ident), "{} is not generated", ident);
+        }
+    }
+
+    #[test]
+    fn test_missing_option() {
+        for key in ["name", "symbol", "wrapper", "params"] {
+            let attrs = OPTIONS
+                .lines()
+                .filter(|line| !line.trim_start().starts_with(key))
+                .collect::<Vec<_>>()
+                .join("\n");
+            assert_eq!(
+                error(&meta(&attrs)),
+                format!("missing #[gst_meta({} = \"...\")]", key)
+            );
+        }
+    }
+
+    #[test]
+    fn test_invalid_option() {
+        let with = |attr: &str| error(&meta(&format!("{} #[gst_meta({})]", OPTIONS, attr)));
+        assert_eq!(with(r#"unknown = "x""#), "unknown gst_meta option");
+        assert_eq!(
+            with(r#"tags = "video""#),
+            "expected \"content-independent\" or \"video-size\""
+        );
+        assert_eq!(with("name = 1"), "expected string literal");
+        assert_eq!(with("name"), "expected key = \"value\"");
+        assert_eq!(
+            with(r#"wrapper = "TestMeta""#),
+            "path must start with `crate::`"
+        );
+    }
+
+    #[test]
+    fn test_invalid_struct() {
+        let fields = "parent: gst::ffi::GstMeta, pub label: String";
+        assert_eq!(
+            error(&input(OPTIONS, fields)),
+            "GstMeta requires #[repr(C)]"
+        );
+        let attrs = format!("#[repr(C)] {}", OPTIONS);
+        assert_eq!(
+            error(&input(&attrs, "pub label: String")),
+            "first field must be gst::ffi::GstMeta"
+        );
+        assert_eq!(
+            error(&input(&attrs, "parent: gst::ffi::GstMeta, label: String")),
+            "GstMeta field must be pub"
+        );
+        assert_eq!(
+            error(&input(
+                &attrs,
+                "parent: gst::ffi::GstMeta, #[gst_meta(clone)] pub label: String"
+            )),
+            "unknown gst_meta option"
+        );
+    }
+}
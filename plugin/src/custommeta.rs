@@ -0,0 +1,156 @@
+//! GstCustomMetaによるメタデータ
+//!
+//! GstStructureに任意のフィールドを持たせられるので、soを追加せずにメタデータの内容を定義できる
+//!
+//! gst_meta_register_customはGStreamer 1.20で追加されたAPI
+//! gstreamer-sys 0.19にも定義はあるが、有効にすると要求するGStreamerのバージョンが上がるため
+//! `v1_20` featureで必要な部分だけを宣言する
+
+use gst::glib;
+use gst::glib::translate::ToGlibPtr;
+use once_cell::sync::Lazy;
+
+/// 登録するカスタムメタの名前
+pub const CUSTOM_META_NAME: &str = "GstExampleCustomMeta";
+
+mod ffi {
+    use gst::glib::ffi::{gpointer, GDestroyNotify};
+    use std::os::raw::c_char;
+
+    #[repr(C)]
+    pub struct GstCustomMeta {
+        pub meta: gst::ffi::GstMeta,
+    }
+
+    // transform_funcは使わないので型は省略してNoneを渡す
+    extern "C" {
+        pub fn gst_meta_register_custom(
+            name: *const c_char,
+            tags: *mut *const c_char,
+            transform_func: gpointer,
+            user_data: gpointer,
+            destroy_data: GDestroyNotify,
+        ) -> *const gst::ffi::GstMetaInfo;
+        pub fn gst_buffer_add_custom_meta(
+            buffer: *mut gst::ffi::GstBuffer,
+            name: *const c_char,
+        ) -> *mut GstCustomMeta;
+        pub fn gst_buffer_get_custom_meta(
+            buffer: *mut gst::ffi::GstBuffer,
+            name: *const c_char,
+        ) -> *mut GstCustomMeta;
+        pub fn gst_custom_meta_get_structure(
+            meta: *mut GstCustomMeta,
+        ) -> *mut gst::ffi::GstStructure;
+    }
+}
+
+/// カスタムメタを登録する
+///
+/// タグを持たないのでバッファのコピー時にだけ複製される。
+/// 同じ名前が別のメタとして登録済みの場合などはErrを返す
+pub fn register() -> Result<(), glib::BoolError> {
+    static REGISTERED: Lazy<bool> = Lazy::new(|| unsafe {
+        let mut tags = [std::ptr::null()];
+        let info = ffi::gst_meta_register_custom(
+            CUSTOM_META_NAME.to_glib_none().0,
+            tags.as_mut_ptr(),
+            std::ptr::null_mut(),
+            std::ptr::null_mut(),
+            None,
+        );
+        !info.is_null()
+    });
+    if *REGISTERED {
+        Ok(())
+    } else {
+        Err(glib::bool_error!("Failed to register {}", CUSTOM_META_NAME))
+    }
+}
+
+/// `fields`のフィールドを持つカスタムメタをバッファに追加する
+///
+/// structureの名前はCUSTOM_META_NAMEになるので`fields`の名前は使わない
+pub fn add(buffer: &mut gst::BufferRef, fields: &gst::StructureRef) -> Result<(), glib::BoolError> {
+    unsafe {
+        let meta =
+            ffi::gst_buffer_add_custom_meta(buffer.as_mut_ptr(), CUSTOM_META_NAME.to_glib_none().0);
+        if meta.is_null() {
+            return Err(glib::bool_error!(
+                "Failed to add {}, not registered",
+                CUSTOM_META_NAME
+            ));
+        }
+        let s = gst::StructureRef::from_glib_borrow_mut(ffi::gst_custom_meta_get_structure(meta));
+        for (name, value) in fields.iter() {
+            s.set_value(name, value.clone());
+        }
+    }
+    Ok(())
+}
+
+/// バッファに付与されたカスタムメタの内容を複製して返す
+pub fn structure(buffer: &gst::BufferRef) -> Option<gst::Structure> {
+    unsafe {
+        let meta = get(buffer)?;
+        Some(
+            gst::StructureRef::from_glib_borrow(ffi::gst_custom_meta_get_structure(meta))
+                .to_owned(),
+        )
+    }
+}
+
+/// カスタムメタを削除し、削除したメタの内容を返す
+pub fn remove(buffer: &mut gst::BufferRef) -> Option<gst::Structure> {
+    let fields = structure(buffer)?;
+    unsafe {
+        let meta = get(buffer)?;
+        gst::ffi::gst_buffer_remove_meta(buffer.as_mut_ptr(), meta as *mut gst::ffi::GstMeta);
+    }
+    Some(fields)
+}
+
+unsafe fn get(buffer: &gst::BufferRef) -> Option<*mut ffi::GstCustomMeta> {
+    let meta = ffi::gst_buffer_get_custom_meta(
+        buffer.as_ptr() as *mut gst::ffi::GstBuffer,
+        CUSTOM_META_NAME.to_glib_none().0,
+    );
+    if meta.is_null() {
+        None
+    } else {
+        Some(meta)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use super::*;
+
+    #[test]
+    fn test_custom_meta() {
+        gst::init().unwrap();
+        register().unwrap();
+        // 2回目以降は登録済みの結果を返す
+        register().unwrap();
+        let fields =
+            gst::Structure::from_str("fields, label=(string)cam0, altitude=(double)100.5").unwrap();
+        let mut buffer = gst::Buffer::with_size(16).unwrap();
+        add(buffer.make_mut(), &fields).unwrap();
+
+        // コピーしたバッファにも引き継がれる
+        let copied = buffer.copy();
+        for b in [&buffer, &copied] {
+            let s = structure(b).unwrap();
+            assert_eq!(s.name(), CUSTOM_META_NAME);
+            assert_eq!(s.get::<&str>("label").unwrap(), "cam0");
+            assert_eq!(s.get::<f64>("altitude").unwrap(), 100.5);
+        }
+
+        let removed = remove(buffer.make_mut()).unwrap();
+        assert_eq!(removed.get::<&str>("label").unwrap(), "cam0");
+        assert!(structure(&buffer).is_none());
+        assert!(remove(buffer.make_mut()).is_none());
+    }
+}
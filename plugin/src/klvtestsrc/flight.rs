@@ -0,0 +1,379 @@
+//! klvtestsrcで生成する飛行経路
+//!
+//! 位置は経過時間から計算するので、seekしても同じ時刻には同じ状態になる
+//! noiseもseedとバッファの番号から決めるので、同じ設定なら何度生成しても同じ値になる
+use crate::metaklv::FlightTelemetry;
+
+// 緯度1度あたりの距離 [m]
+const METERS_PER_DEG: f64 = 111_320.0;
+
+/// 経路の中心や通過点を指定しない場合の位置。東京駅の上空100m
+pub const DEFAULT_ORIGIN: Waypoint = Waypoint {
+    latitude: 35.681236,
+    longitude: 139.767125,
+    altitude: 100.0,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Waypoint {
+    /// [deg]
+    pub latitude: f64,
+    /// [deg]
+    pub longitude: f64,
+    /// [m]
+    pub altitude: f64,
+}
+
+/// `lat,lon,alt;lat,lon,alt;...`の形式の通過点を読む
+pub fn parse_waypoints(s: &str) -> Result<Vec<Waypoint>, String> {
+    s.split(';')
+        .map(str::trim)
+        .filter(|p| !p.is_empty())
+        .map(|p| {
+            let v = p
+                .split(',')
+                .map(|v| v.trim().parse::<f64>())
+                .collect::<Result<Vec<_>, _>>()
+                .map_err(|e| format!("invalid waypoint {:?}: {}", p, e))?;
+            match v[..] {
+                [latitude, longitude, altitude] => Ok(Waypoint {
+                    latitude,
+                    longitude,
+                    altitude,
+                }),
+                _ => Err(format!("waypoint {:?} is not lat,lon,alt", p)),
+            }
+        })
+        .collect()
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Path {
+    /// centerの周りを時計回りに周回する
+    Circle { center: Waypoint, radius: f64 },
+    /// 通過点を順に巡り、最後の点から最初の点に戻る
+    Waypoints(Vec<Waypoint>),
+}
+
+/// 経路と速度から飛行の状態を生成する
+#[derive(Debug, Clone, PartialEq)]
+pub struct Flight {
+    pub path: Path,
+    /// [m/s]
+    pub speed: f64,
+    /// 位置と高度は[m]、角度は[deg]の一様乱数の振幅
+    pub noise: f64,
+    pub seed: u64,
+}
+
+// 経路の原点を基準にした北と東の距離 [m]
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Local {
+    north: f64,
+    east: f64,
+    altitude: f64,
+}
+
+impl Local {
+    fn from_waypoint(origin: &Waypoint, p: &Waypoint) -> Self {
+        Self {
+            north: (p.latitude - origin.latitude) * METERS_PER_DEG,
+            east: (p.longitude - origin.longitude)
+                * METERS_PER_DEG
+                * origin.latitude.to_radians().cos(),
+            altitude: p.altitude,
+        }
+    }
+
+    fn to_waypoint(self, origin: &Waypoint) -> Waypoint {
+        Waypoint {
+            latitude: origin.latitude + self.north / METERS_PER_DEG,
+            longitude: origin.longitude
+                + self.east / (METERS_PER_DEG * origin.latitude.to_radians().cos()),
+            altitude: self.altitude,
+        }
+    }
+
+    // 北を0とした時計回りの方位 [deg]
+    fn bearing_to(&self, other: &Local) -> f64 {
+        (other.east - self.east)
+            .atan2(other.north - self.north)
+            .to_degrees()
+            .rem_euclid(360.0)
+    }
+
+    fn distance_to(&self, other: &Local) -> f64 {
+        (other.north - self.north).hypot(other.east - self.east)
+    }
+
+    fn lerp(&self, other: &Local, r: f64) -> Local {
+        Local {
+            north: self.north + (other.north - self.north) * r,
+            east: self.east + (other.east - self.east) * r,
+            altitude: self.altitude + (other.altitude - self.altitude) * r,
+        }
+    }
+}
+
+// 経路上の位置と向き、センサが向く地上の点
+struct Pose {
+    position: Local,
+    heading: f64,
+    speed: f64,
+    target: Local,
+}
+
+// splitmix64
+fn mix(mut x: u64) -> u64 {
+    x = x.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    x = (x ^ (x >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    x = (x ^ (x >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    x ^ (x >> 31)
+}
+
+impl Flight {
+    fn origin(&self) -> Waypoint {
+        match &self.path {
+            Path::Circle { center, .. } => *center,
+            Path::Waypoints(points) => points.first().copied().unwrap_or(DEFAULT_ORIGIN),
+        }
+    }
+
+    fn pose(&self, origin: &Waypoint, t: f64) -> Pose {
+        match &self.path {
+            Path::Circle { center, radius } => {
+                // 真北から時計回りに進むので機首は中心から見た方位の90度先
+                let theta = self.speed / radius * t;
+                let position = Local {
+                    north: radius * theta.cos(),
+                    east: radius * theta.sin(),
+                    altitude: center.altitude,
+                };
+                Pose {
+                    position,
+                    heading: (theta.to_degrees() + 90.0).rem_euclid(360.0),
+                    speed: self.speed,
+                    target: Local {
+                        north: 0.0,
+                        east: 0.0,
+                        altitude: 0.0,
+                    },
+                }
+            }
+            Path::Waypoints(points) => {
+                let points = points
+                    .iter()
+                    .map(|p| Local::from_waypoint(origin, p))
+                    .collect::<Vec<_>>();
+                let legs = (0..points.len())
+                    .map(|i| (points[i], points[(i + 1) % points.len()]))
+                    .filter(|(a, b)| a.distance_to(b) > 0.0)
+                    .collect::<Vec<_>>();
+                let total = legs.iter().map(|(a, b)| a.distance_to(b)).sum::<f64>();
+                if total == 0.0 {
+                    // 動けないのでその場で止まる
+                    let position = points.first().copied().unwrap_or(Local {
+                        north: 0.0,
+                        east: 0.0,
+                        altitude: origin.altitude,
+                    });
+                    return Pose {
+                        position,
+                        heading: 0.0,
+                        speed: 0.0,
+                        target: Local {
+                            altitude: 0.0,
+                            ..position
+                        },
+                    };
+                }
+                let mut s = (self.speed * t).rem_euclid(total);
+                for (a, b) in legs.iter() {
+                    let d = a.distance_to(b);
+                    if s < d {
+                        return Pose {
+                            position: a.lerp(b, s / d),
+                            heading: a.bearing_to(b),
+                            speed: self.speed,
+                            target: Local {
+                                altitude: 0.0,
+                                ..*b
+                            },
+                        };
+                    }
+                    s -= d;
+                }
+                // 浮動小数点の誤差で範囲を超えた場合は最初の点
+                let (a, b) = legs[0];
+                Pose {
+                    position: a,
+                    heading: a.bearing_to(&b),
+                    speed: self.speed,
+                    target: Local { altitude: 0.0, ..b },
+                }
+            }
+        }
+    }
+
+    // seedとバッファの番号とチャンネルから-1..1の値を決める
+    fn noise(&self, count: u64, channel: u64) -> f64 {
+        let x = mix(mix(self.seed ^ mix(count)) ^ channel);
+        ((x >> 11) as f64 / (1u64 << 53) as f64) * 2.0 - 1.0
+    }
+
+    /// count番目のバッファの経過時間`elapsed`での状態
+    ///
+    /// `epoch`は経過時間0のPrecision Time Stamp [us]
+    pub fn telemetry(&self, count: u64, elapsed: gst::ClockTime, epoch: u64) -> FlightTelemetry {
+        let origin = self.origin();
+        let pose = self.pose(&origin, elapsed.nseconds() as f64 / 1e9);
+        let n = |channel| self.noise * self.noise(count, channel);
+        let position = Local {
+            north: pose.position.north + n(0),
+            east: pose.position.east + n(1),
+            altitude: pose.position.altitude + n(2),
+        };
+        // センサは地上の目標を向く
+        let distance = position.distance_to(&pose.target);
+        let sensor_azimuth = if distance > 0.0 {
+            position.bearing_to(&pose.target) - pose.heading
+        } else {
+            0.0
+        };
+        let sensor_elevation = -(position.altitude - pose.target.altitude)
+            .atan2(distance)
+            .to_degrees();
+        let p = position.to_waypoint(&origin);
+        FlightTelemetry {
+            timestamp: epoch + elapsed.useconds(),
+            latitude: p.latitude,
+            longitude: p.longitude,
+            altitude: p.altitude,
+            heading: (pose.heading + n(3)).rem_euclid(360.0),
+            speed: pose.speed,
+            sensor_azimuth: (sensor_azimuth + n(4)).rem_euclid(360.0),
+            sensor_elevation: (sensor_elevation + n(5)).clamp(-90.0, 90.0),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 1周100秒
+    fn circle(noise: f64, seed: u64) -> Flight {
+        Flight {
+            path: Path::Circle {
+                center: DEFAULT_ORIGIN,
+                radius: 500.0,
+            },
+            speed: 10.0 * std::f64::consts::PI,
+            noise,
+            seed,
+        }
+    }
+
+    fn secs(v: f64) -> gst::ClockTime {
+        gst::ClockTime::from_nseconds((v * 1e9) as u64)
+    }
+
+    fn assert_near(a: f64, b: f64) {
+        assert!((a - b).abs() < 1e-3, "{} != {}", a, b);
+    }
+
+    // 0と360をまたぐ角度の比較
+    fn assert_angle(a: f64, b: f64) {
+        let d = (a - b).rem_euclid(360.0);
+        assert!(d.min(360.0 - d) < 1e-3, "{} != {}", a, b);
+    }
+
+    #[test]
+    fn test_circle() {
+        let flight = circle(0.0, 0);
+        let origin = DEFAULT_ORIGIN;
+        for (t, heading) in [(0.0, 90.0), (25.0, 180.0), (50.0, 270.0), (75.0, 0.0)] {
+            let tel = flight.telemetry(0, secs(t), 1_000_000);
+            assert_eq!(tel.timestamp, 1_000_000 + (t * 1e6) as u64);
+            let p = Local::from_waypoint(
+                &origin,
+                &Waypoint {
+                    latitude: tel.latitude,
+                    longitude: tel.longitude,
+                    altitude: tel.altitude,
+                },
+            );
+            assert_near(p.north.hypot(p.east), 500.0);
+            assert_near(tel.altitude, 100.0);
+            assert_angle(tel.heading, heading);
+            // 時計回りなので中心は右手側
+            assert_angle(tel.sensor_azimuth, 90.0);
+            assert_near(
+                tel.sensor_elevation,
+                -(100.0f64 / 500.0).atan().to_degrees(),
+            );
+        }
+    }
+
+    #[test]
+    fn test_waypoints() {
+        // 北に1000m進んでから戻る
+        let north = Local {
+            north: 1000.0,
+            east: 0.0,
+            altitude: 200.0,
+        }
+        .to_waypoint(&DEFAULT_ORIGIN);
+        let flight = Flight {
+            path: Path::Waypoints(vec![DEFAULT_ORIGIN, north]),
+            speed: 10.0,
+            noise: 0.0,
+            seed: 0,
+        };
+        let tel = flight.telemetry(0, secs(50.0), 0);
+        assert_angle(tel.heading, 0.0);
+        assert_near(tel.altitude, 150.0);
+        assert_angle(tel.sensor_azimuth, 0.0);
+        let tel = flight.telemetry(0, secs(150.0), 0);
+        assert_angle(tel.heading, 180.0);
+        assert_near(tel.altitude, 150.0);
+        // 1周200秒
+        assert_eq!(
+            flight.telemetry(0, secs(30.0), 0).latitude,
+            flight.telemetry(0, secs(230.0), 0).latitude
+        );
+    }
+
+    #[test]
+    fn test_noise_seed() {
+        let t = secs(10.0);
+        let a = circle(5.0, 1).telemetry(3, t, 0);
+        assert_eq!(a, circle(5.0, 1).telemetry(3, t, 0));
+        assert_ne!(a, circle(5.0, 2).telemetry(3, t, 0));
+        assert_ne!(a, circle(5.0, 1).telemetry(4, t, 0));
+        let exact = circle(0.0, 1).telemetry(3, t, 0);
+        assert!((a.altitude - exact.altitude).abs() <= 5.0);
+        assert!((a.latitude - exact.latitude).abs() * METERS_PER_DEG <= 5.0);
+    }
+
+    #[test]
+    fn test_parse_waypoints() {
+        assert_eq!(
+            parse_waypoints("35.0,139.0,100; 35.1,139.1,120;"),
+            Ok(vec![
+                Waypoint {
+                    latitude: 35.0,
+                    longitude: 139.0,
+                    altitude: 100.0
+                },
+                Waypoint {
+                    latitude: 35.1,
+                    longitude: 139.1,
+                    altitude: 120.0
+                },
+            ])
+        );
+        assert!(parse_waypoints("35.0,139.0").is_err());
+        assert!(parse_waypoints("a,b,c").is_err());
+    }
+}
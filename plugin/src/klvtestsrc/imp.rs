@@ -1,4 +1,10 @@
-use std::sync::atomic::AtomicU64;
+//! KLVを生成するテスト用のsource
+//!
+//! 各バッファの時刻はfpsの分数から計算するので30000/1001でもずれない
+//! 出力するバッファの数はBaseSrcのnum-buffersで制限でき、出力し終えるとEOSを送る
+//!
+//! pathを指定すると円や通過点を巡る飛行の状態を生成し、example, uas-datalink, customのKLVに載せる
+//! 状態はバッファの時刻とseedから決まるので、同じ設定なら何度実行しても同じKLVになる
 use std::sync::{Mutex, RwLock};
 
 use ers_meta::ExampleRsMetaParams;
@@ -17,8 +23,12 @@ use gst_base::traits::BaseSrcExt;
 
 use once_cell::sync::Lazy;
 
-use crate::metaklv::ExampleDataset;
+use crate::metaklv::{
+    CustomDataset, ExampleCDataset, ExampleDataset, FlightTelemetry, KlvDataset, KlvDatasetType,
+    UasDatalinkLS,
+};
 
+use super::flight::{self, Flight};
 use super::CLASS_NAME;
 use super::ELEMENT_NAME;
 
@@ -38,10 +48,66 @@ pub static KLV_CAPS: Lazy<Caps> = Lazy::new(|| {
 
 // properties()でクラス定数を参照できないので外部に定義
 const DEFAULT_IS_LIVE: bool = false;
+const DEFAULT_TIMESTAMP_OFFSET: i64 = 0;
+const DEFAULT_RADIUS: f64 = 500.0;
+const DEFAULT_SPEED: f64 = 20.0;
+const DEFAULT_NOISE: f64 = 0.0;
+const DEFAULT_SEED: u64 = 0;
+// liveでない場合のPrecision Time Stampの起点。2023-01-01T00:00:00Z [us]
+const FIXED_EPOCH: u64 = 1_672_531_200_000_000;
+
+/// 生成する飛行経路の形
+#[derive(Default, Debug, PartialEq, Eq, Clone, Copy, glib::Enum)]
+#[repr(u32)]
+#[enum_type(name = "GstKlvTestSrcPath")]
+enum FlightPath {
+    #[default]
+    #[enum_value(name = "None: counter without flight telemetry", nick = "none")]
+    None = 0,
+    #[enum_value(name = "Circle: orbit around the center", nick = "circle")]
+    Circle = 1,
+    #[enum_value(name = "Waypoints: loop through the waypoints", nick = "waypoints")]
+    Waypoints = 2,
+}
 
 struct Settings {
     fps: Fraction,
     is_live: bool,
+    dataset: KlvDatasetType,
+    timestamp_offset: gst::ClockTime,
+    path: FlightPath,
+    waypoints: Option<String>,
+    radius: f64,
+    speed: f64,
+    noise: f64,
+    seed: u64,
+}
+
+impl Settings {
+    // 設定から飛行経路を作る。pathがNoneならNone
+    fn flight(&self) -> Result<Option<Flight>, String> {
+        let waypoints = match self.waypoints.as_deref() {
+            Some(s) => flight::parse_waypoints(s)?,
+            None => vec![],
+        };
+        let path = match self.path {
+            FlightPath::None => return Ok(None),
+            FlightPath::Circle => flight::Path::Circle {
+                center: waypoints.first().copied().unwrap_or(flight::DEFAULT_ORIGIN),
+                radius: self.radius,
+            },
+            FlightPath::Waypoints if waypoints.is_empty() => {
+                return Err("waypoints path requires waypoints".to_string())
+            }
+            FlightPath::Waypoints => flight::Path::Waypoints(waypoints),
+        };
+        Ok(Some(Flight {
+            path,
+            speed: self.speed,
+            noise: self.noise,
+            seed: self.seed,
+        }))
+    }
 }
 
 impl Default for Settings {
@@ -49,33 +115,135 @@ impl Default for Settings {
         Self {
             fps: Fraction::new(30, 1),
             is_live: DEFAULT_IS_LIVE,
+            dataset: KlvDatasetType::default(),
+            timestamp_offset: gst::ClockTime::from_nseconds(DEFAULT_TIMESTAMP_OFFSET as u64),
+            path: FlightPath::default(),
+            waypoints: None,
+            radius: DEFAULT_RADIUS,
+            speed: DEFAULT_SPEED,
+            noise: DEFAULT_NOISE,
+            seed: DEFAULT_SEED,
         }
     }
 }
 
+// unlockで待機中のclockを止めるために保持する
+// flushingはunlockからunlock_stopまでの間に新たに待たないようにする
+#[derive(Default)]
 struct ClockWait {
     clock_id: Option<gst::SingleShotClockId>,
-    // flushing: bool,
+    flushing: bool,
 }
 
-#[allow(clippy::derivable_impls)]
-impl Default for ClockWait {
-    fn default() -> ClockWait {
-        ClockWait {
-            clock_id: None,
-            // flushing: true,
-        }
-    }
+#[derive(Default)]
+struct State {
+    // 次に生成するバッファの番号。逆再生では次に生成するバッファの番号+1
+    count: u64,
+    // 負のrateのsegmentでは番号を減らしながら生成する
+    reverse: bool,
+    // startで設定から作る
+    flight: Option<Flight>,
+    // 時刻0のPrecision Time Stamp [us]
+    epoch: u64,
 }
 
 #[derive(Default)]
 pub struct KlvTestSrc {
-    count: AtomicU64,
+    state: Mutex<State>,
     clock_wait: Mutex<ClockWait>,
     settings: RwLock<Settings>,
 }
 
-impl KlvTestSrc {}
+// count番目のバッファの時刻
+//
+// 前のバッファの時刻に足していくと30000/1001などで誤差が積み重なるので、毎回countから計算する
+fn timestamp(fps: Fraction, count: u64) -> gst::ClockTime {
+    let ns = count as u128 * fps.denom() as u128 * gst::ClockTime::SECOND.nseconds() as u128
+        / fps.numer() as u128;
+    gst::ClockTime::from_nseconds(ns as u64)
+}
+
+// 時刻を含むバッファの番号
+fn count_at(fps: Fraction, ts: gst::ClockTime) -> u64 {
+    let count = (ts.nseconds() as u128 * fps.numer() as u128
+        / (fps.denom() as u128 * gst::ClockTime::SECOND.nseconds() as u128)) as u64;
+    // timestampは切り捨てるので次のバッファの開始と一致する場合がある
+    if timestamp(fps, count + 1) <= ts {
+        count + 1
+    } else {
+        count
+    }
+}
+
+impl KlvTestSrc {
+    // 指定された種類のKLVパケットを生成する
+    // 飛行の状態があればexample-c以外のパケットに載せる
+    fn dataset(
+        &self,
+        dataset: KlvDatasetType,
+        count: u64,
+        telemetry: Option<&FlightTelemetry>,
+    ) -> KlvDataset {
+        match dataset {
+            KlvDatasetType::Example => {
+                let meta = ExampleRsMetaParams::new(
+                    "KlvTestSrcLabel".to_string(),
+                    (count % i32::MAX as u64) as i32,
+                    ers_meta::TransformMode::Copy,
+                );
+                let mut ds = ExampleDataset::from(&meta);
+                if let Some(t) = telemetry {
+                    ds.set_flight(t);
+                }
+                KlvDataset::Example(ds)
+            }
+            KlvDatasetType::UasDatalink => {
+                let mut ds = match telemetry {
+                    Some(t) => {
+                        let mut ds = UasDatalinkLS::new(t.timestamp);
+                        ds.set_heading(t.heading);
+                        ds.set_latitude(t.latitude);
+                        ds.set_longitude(t.longitude);
+                        ds.set_altitude(t.altitude);
+                        ds
+                    }
+                    None => {
+                        let mut ds = UasDatalinkLS::now();
+                        ds.set_heading(count as f64);
+                        ds.set_latitude(35.681236);
+                        ds.set_longitude(139.767125);
+                        ds.set_altitude(100.0);
+                        ds
+                    }
+                };
+                ds.mission_id = Some("KlvTestSrcLabel".to_string());
+                KlvDataset::UasDatalink(ds)
+            }
+            KlvDatasetType::Custom => {
+                let mut s = gst::Structure::builder("klvtestsrc")
+                    .field("label", "KlvTestSrcLabel")
+                    .field("count", count)
+                    .build();
+                if let Some(t) = telemetry {
+                    s.set("timestamp", t.timestamp);
+                    s.set("latitude", t.latitude);
+                    s.set("longitude", t.longitude);
+                    s.set("altitude", t.altitude);
+                    s.set("heading", t.heading);
+                    s.set("speed", t.speed);
+                    s.set("sensor_azimuth", t.sensor_azimuth);
+                    s.set("sensor_elevation", t.sensor_elevation);
+                }
+                KlvDataset::Custom(CustomDataset::from(s.as_ref()))
+            }
+            KlvDatasetType::ExampleC => KlvDataset::ExampleC(ExampleCDataset::new(
+                "KlvTestSrcLabel".to_string(),
+                count as i64,
+                count as f32 / 10.0,
+            )),
+        }
+    }
+}
 
 impl ElementImpl for KlvTestSrc {
     fn metadata() -> Option<&'static gst::subclass::ElementMetadata> {
@@ -150,6 +318,58 @@ impl ObjectImpl for KlvTestSrc {
                     .default_value(DEFAULT_IS_LIVE)
                     .mutable_ready()
                     .build(),
+                glib::ParamSpecEnum::builder::<KlvDatasetType>(
+                    "dataset",
+                    KlvDatasetType::default(),
+                )
+                .nick("Dataset")
+                .blurb("select klv dataset")
+                .mutable_ready()
+                .build(),
+                glib::ParamSpecInt64::builder("timestamp-offset")
+                    .nick("Timestamp offset")
+                    .blurb("an offset added to timestamps set on buffers (in ns)")
+                    .minimum(0)
+                    .default_value(DEFAULT_TIMESTAMP_OFFSET)
+                    .mutable_ready()
+                    .build(),
+                glib::ParamSpecEnum::builder::<FlightPath>("path", FlightPath::default())
+                    .nick("Path")
+                    .blurb("shape of the flight track")
+                    .mutable_ready()
+                    .build(),
+                glib::ParamSpecString::builder("waypoints")
+                    .nick("Waypoints")
+                    .blurb("waypoints as \"lat,lon,alt;lat,lon,alt;...\" (first one is the center of circle)")
+                    .mutable_ready()
+                    .build(),
+                glib::ParamSpecDouble::builder("radius")
+                    .nick("Radius")
+                    .blurb("radius of circle path in meters")
+                    .minimum(1.0)
+                    .default_value(DEFAULT_RADIUS)
+                    .mutable_ready()
+                    .build(),
+                glib::ParamSpecDouble::builder("speed")
+                    .nick("Speed")
+                    .blurb("ground speed in m/s")
+                    .minimum(0.0)
+                    .default_value(DEFAULT_SPEED)
+                    .mutable_ready()
+                    .build(),
+                glib::ParamSpecDouble::builder("noise")
+                    .nick("Noise")
+                    .blurb("amplitude of uniform noise (meters for position, degrees for angles)")
+                    .minimum(0.0)
+                    .default_value(DEFAULT_NOISE)
+                    .mutable_ready()
+                    .build(),
+                glib::ParamSpecUInt64::builder("seed")
+                    .nick("Seed")
+                    .blurb("seed of the noise")
+                    .default_value(DEFAULT_SEED)
+                    .mutable_ready()
+                    .build(),
             ]
         });
 
@@ -176,6 +396,58 @@ impl ObjectImpl for KlvTestSrc {
                 );
                 settings.is_live = is_live;
             }
+            "dataset" => {
+                let x = value
+                    .get::<KlvDatasetType>()
+                    .expect("type checked upstream");
+                gst::info!(CAT, imp: self, "set prop dataset to {:?}", x);
+                let mut settings = self.settings.write().unwrap();
+                settings.dataset = x;
+            }
+            "timestamp-offset" => {
+                let x = value.get::<i64>().expect("type checked upstream");
+                gst::info!(CAT, imp: self, "set prop timestamp-offset to {}", x);
+                let mut settings = self.settings.write().unwrap();
+                settings.timestamp_offset = gst::ClockTime::from_nseconds(x as u64);
+            }
+            "path" => {
+                let x = value.get::<FlightPath>().expect("type checked upstream");
+                gst::info!(CAT, imp: self, "set prop path to {:?}", x);
+                let mut settings = self.settings.write().unwrap();
+                settings.path = x;
+            }
+            "waypoints" => {
+                let x = value
+                    .get::<Option<String>>()
+                    .expect("type checked upstream");
+                gst::info!(CAT, imp: self, "set prop waypoints to {:?}", x);
+                let mut settings = self.settings.write().unwrap();
+                settings.waypoints = x;
+            }
+            "radius" => {
+                let x = value.get::<f64>().expect("type checked upstream");
+                gst::info!(CAT, imp: self, "set prop radius to {}", x);
+                let mut settings = self.settings.write().unwrap();
+                settings.radius = x;
+            }
+            "speed" => {
+                let x = value.get::<f64>().expect("type checked upstream");
+                gst::info!(CAT, imp: self, "set prop speed to {}", x);
+                let mut settings = self.settings.write().unwrap();
+                settings.speed = x;
+            }
+            "noise" => {
+                let x = value.get::<f64>().expect("type checked upstream");
+                gst::info!(CAT, imp: self, "set prop noise to {}", x);
+                let mut settings = self.settings.write().unwrap();
+                settings.noise = x;
+            }
+            "seed" => {
+                let x = value.get::<u64>().expect("type checked upstream");
+                gst::info!(CAT, imp: self, "set prop seed to {}", x);
+                let mut settings = self.settings.write().unwrap();
+                settings.seed = x;
+            }
 
             _ => unimplemented!(),
         }
@@ -191,6 +463,38 @@ impl ObjectImpl for KlvTestSrc {
                 let settings = self.settings.read().unwrap();
                 settings.is_live.to_value()
             }
+            "dataset" => {
+                let settings = self.settings.read().unwrap();
+                settings.dataset.to_value()
+            }
+            "timestamp-offset" => {
+                let settings = self.settings.read().unwrap();
+                (settings.timestamp_offset.nseconds() as i64).to_value()
+            }
+            "path" => {
+                let settings = self.settings.read().unwrap();
+                settings.path.to_value()
+            }
+            "waypoints" => {
+                let settings = self.settings.read().unwrap();
+                settings.waypoints.to_value()
+            }
+            "radius" => {
+                let settings = self.settings.read().unwrap();
+                settings.radius.to_value()
+            }
+            "speed" => {
+                let settings = self.settings.read().unwrap();
+                settings.speed.to_value()
+            }
+            "noise" => {
+                let settings = self.settings.read().unwrap();
+                settings.noise.to_value()
+            }
+            "seed" => {
+                let settings = self.settings.read().unwrap();
+                settings.seed.to_value()
+            }
 
             _ => unimplemented!(),
         }
@@ -206,7 +510,109 @@ impl ObjectSubclass for KlvTestSrc {
     type ParentType = gst_base::PushSrc;
 }
 
-impl BaseSrcImpl for KlvTestSrc {}
+impl BaseSrcImpl for KlvTestSrc {
+    fn start(&self) -> Result<(), gst::ErrorMessage> {
+        let (flight, is_live) = {
+            let settings = self.settings.read().unwrap();
+            (settings.flight(), settings.is_live)
+        };
+        let flight = flight.map_err(|e| {
+            gst::error_msg!(gst::LibraryError::Settings, ["Invalid flight path: {}", e])
+        })?;
+        // liveでは開始した時刻から、そうでなければ固定の時刻から数える
+        let epoch = if is_live {
+            std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .map(|d| d.as_micros() as u64)
+                .unwrap_or_default()
+        } else {
+            FIXED_EPOCH
+        };
+        *self.state.lock().unwrap() = State {
+            flight,
+            epoch,
+            ..Default::default()
+        };
+        self.clock_wait.lock().unwrap().flushing = false;
+        Ok(())
+    }
+
+    fn is_seekable(&self) -> bool {
+        true
+    }
+
+    // 生成するバッファの番号をseek先に合わせる
+    // 逆再生ではstopを含むバッファから0番に向かって生成する
+    fn do_seek(&self, segment: &mut gst::Segment) -> bool {
+        let segment = match segment.downcast_ref::<gst::format::Time>() {
+            Some(segment) => segment,
+            None => {
+                gst::error!(
+                    CAT,
+                    imp: self,
+                    "Unsupported segment format {:?}",
+                    segment.format()
+                );
+                return false;
+            }
+        };
+        let (fps, offset) = {
+            let settings = self.settings.read().unwrap();
+            (settings.fps, settings.timestamp_offset)
+        };
+        // segmentの時刻はtimestamp-offsetを含む
+        let reverse = segment.rate() < 0.0;
+        let count = if reverse {
+            match segment.stop() {
+                Some(stop) if stop > offset => {
+                    count_at(fps, stop - offset - gst::ClockTime::from_nseconds(1)) + 1
+                }
+                Some(_) => 0,
+                None => {
+                    gst::error!(CAT, imp: self, "Reverse playback requires stop position");
+                    return false;
+                }
+            }
+        } else {
+            count_at(
+                fps,
+                segment
+                    .start()
+                    .unwrap_or(gst::ClockTime::ZERO)
+                    .saturating_sub(offset),
+            )
+        };
+        gst::debug!(
+            CAT,
+            imp: self,
+            "seek to {:?}, count {} reverse {}",
+            segment,
+            count,
+            reverse
+        );
+        let mut state = self.state.lock().unwrap();
+        state.count = count;
+        state.reverse = reverse;
+        true
+    }
+
+    // state変更やflushing seekで待機中のclockを止める
+    fn unlock(&self) -> Result<(), gst::ErrorMessage> {
+        gst::debug!(CAT, imp: self, "unlock");
+        let mut clock_wait = self.clock_wait.lock().unwrap();
+        if let Some(clock_id) = clock_wait.clock_id.take() {
+            clock_id.unschedule();
+        }
+        clock_wait.flushing = true;
+        Ok(())
+    }
+
+    fn unlock_stop(&self) -> Result<(), gst::ErrorMessage> {
+        gst::debug!(CAT, imp: self, "unlock_stop");
+        self.clock_wait.lock().unwrap().flushing = false;
+        Ok(())
+    }
+}
 
 impl PushSrcImpl for KlvTestSrc {
     fn create(
@@ -214,31 +620,77 @@ impl PushSrcImpl for KlvTestSrc {
         _buffer: Option<&mut gst::BufferRef>,
     ) -> Result<gst_base::subclass::base_src::CreateSuccess, gst::FlowError> {
         gst::debug!(CAT, imp: self, "create PushSrcImpl");
-        let count = self
-            .count
-            .fetch_add(1, std::sync::atomic::Ordering::Relaxed);
-        let (fps, is_live) = {
+        let (fps, is_live, dataset, offset) = {
             let settings = self.settings.read().unwrap();
-            (settings.fps.numer(), settings.is_live)
+            (
+                settings.fps,
+                settings.is_live,
+                settings.dataset,
+                settings.timestamp_offset,
+            )
         };
-        let meta = ExampleRsMetaParams::new(
-            "KlvTestSrcLabel".to_string(),
-            (count % i32::MAX as u64) as i32,
-            ers_meta::TransformMode::Copy,
-        );
-        let records = serde_klv::to_bytes(&ExampleDataset::from(&meta)).unwrap();
+        // シーク可能なセグメント生成
+        let segment = self
+            .obj()
+            .segment()
+            .downcast::<gst::format::Time>()
+            .unwrap();
+        let (count, pts, duration, reverse, telemetry) = {
+            let mut state = self.state.lock().unwrap();
+            let count = if state.reverse {
+                match state.count.checked_sub(1) {
+                    Some(count) => count,
+                    None => {
+                        gst::debug!(CAT, imp: self, "reached the beginning");
+                        return Err(gst::FlowError::Eos);
+                    }
+                }
+            } else {
+                state.count
+            };
+            let pts = offset + timestamp(fps, count);
+            let duration = timestamp(fps, count + 1) - timestamp(fps, count);
+            // segmentの範囲外に出たら終わる
+            let out = if state.reverse {
+                segment
+                    .start()
+                    .map_or(false, |start| pts + duration <= start)
+            } else {
+                segment.stop().map_or(false, |stop| pts >= stop)
+            };
+            if out {
+                gst::debug!(CAT, imp: self, "{} is out of segment", pts);
+                return Err(gst::FlowError::Eos);
+            }
+            state.count = if state.reverse { count } else { count + 1 };
+            // 飛行の状態はtimestamp-offsetを含まない経過時間で決める
+            let telemetry = state
+                .flight
+                .as_ref()
+                .map(|f| f.telemetry(count, timestamp(fps, count), state.epoch));
+            (count, pts, duration, state.reverse, telemetry)
+        };
+        let records = self
+            .dataset(dataset, count, telemetry.as_ref())
+            .to_bytes()
+            .map_err(|e| {
+                gst::element_imp_error!(
+                    self,
+                    gst::StreamError::Encode,
+                    ["Failed to encode klv: {}", e]
+                );
+                gst::FlowError::Error
+            })?;
         let mut buffer = gst::Buffer::with_size(records.len()).unwrap();
         {
             let mut bw = buffer.make_mut().map_writable().unwrap();
             bw.copy_from_slice(&records);
         }
 
-        // fractionから設定可能にしたい
-        let timeoffset = gst::ClockTime::SECOND / fps as u64;
         {
             let buffer = buffer.get_mut().unwrap();
-            buffer.set_pts(Some(timeoffset * count));
-            buffer.set_duration(Some(timeoffset));
+            buffer.set_pts(Some(pts));
+            buffer.set_duration(Some(duration));
             buffer.set_offset(count)
         }
 
@@ -248,13 +700,12 @@ impl PushSrcImpl for KlvTestSrc {
                 None => return Ok(CreateSuccess::NewBuffer(buffer)),
                 Some(res) => res,
             };
-            // シーク可能なセグメント生成
-            let segment = self
-                .obj()
-                .segment()
-                .downcast::<gst::format::Time>()
-                .unwrap();
-            let running_time = segment.to_running_time(buffer.pts().opt_add(buffer.duration()));
+            // 逆再生ではバッファの先頭の時刻が最後に表示される
+            let running_time = if reverse {
+                segment.to_running_time(pts)
+            } else {
+                segment.to_running_time(pts + duration)
+            };
 
             // 待ち時間作成
             let wait_until = match running_time.opt_add(base_time) {
@@ -264,11 +715,10 @@ impl PushSrcImpl for KlvTestSrc {
 
             // 実時間を待つ
             let mut clock_wait = self.clock_wait.lock().unwrap();
-            // gst-plugin-rsのsinesrcでは存在するがデフォルトflushing=trueなので常に終了してしまうためコメントアウトしている
-            // if clock_wait.flushing {
-            //     gst::debug!(CAT, imp: self, "clock_wait Flushing");
-            //     return Err(gst::FlowError::Flushing);
-            // }
+            if clock_wait.flushing {
+                gst::debug!(CAT, imp: self, "clock_wait Flushing");
+                return Err(gst::FlowError::Flushing);
+            }
 
             // タイマー設定
             let id = clock.new_single_shot_id(wait_until);
@@ -295,3 +745,162 @@ impl PushSrcImpl for KlvTestSrc {
         Ok(CreateSuccess::NewBuffer(buffer))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+    use std::time::{Duration, Instant};
+
+    use gst::prelude::*;
+
+    use crate::test_run as run;
+
+    type Received = Arc<Mutex<Vec<(gst::ClockTime, u64)>>>;
+
+    fn pipeline(description: &str) -> (gst::Pipeline, Received) {
+        crate::test_init();
+        let pipeline = gst::parse_launch(description)
+            .unwrap()
+            .downcast::<gst::Pipeline>()
+            .unwrap();
+        let received: Received = Arc::default();
+        let r = received.clone();
+        if let Some(sink) = pipeline.by_name("sink") {
+            sink.connect("handoff", false, move |args| {
+                let buffer = args[1].get::<gst::Buffer>().unwrap();
+                r.lock()
+                    .unwrap()
+                    .push((buffer.pts().unwrap(), buffer.offset()));
+                None
+            });
+        }
+        (pipeline, received)
+    }
+
+    // seek後に受け取ったバッファの時刻と番号
+    fn seek(rate: f64, start: u64, stop: u64) -> Received {
+        let (pipeline, received) =
+            pipeline("klvtestsrc fps=10/1 ! fakesink name=sink sync=false signal-handoffs=true");
+        pipeline.set_state(gst::State::Paused).unwrap();
+        run(&pipeline, gst::MessageType::AsyncDone);
+        received.lock().unwrap().clear();
+        pipeline
+            .seek(
+                rate,
+                gst::SeekFlags::FLUSH | gst::SeekFlags::ACCURATE,
+                gst::SeekType::Set,
+                gst::ClockTime::from_mseconds(start),
+                gst::SeekType::Set,
+                gst::ClockTime::from_mseconds(stop),
+            )
+            .unwrap();
+        pipeline.set_state(gst::State::Playing).unwrap();
+        run(&pipeline, gst::MessageType::Eos);
+        pipeline.set_state(gst::State::Null).unwrap();
+        received
+    }
+
+    fn expected(counts: impl Iterator<Item = u64>) -> Vec<(gst::ClockTime, u64)> {
+        counts
+            .map(|i| (gst::ClockTime::from_mseconds(i * 100), i))
+            .collect()
+    }
+
+    #[test]
+    fn test_seek() {
+        assert_eq!(*seek(1.0, 1000, 2000).lock().unwrap(), expected(10..20));
+    }
+
+    #[test]
+    fn test_seek_reverse() {
+        assert_eq!(
+            *seek(-1.0, 500, 1000).lock().unwrap(),
+            expected((5..10).rev())
+        );
+    }
+
+    #[test]
+    fn test_fractional_fps() {
+        // 29.97fpsで10秒分出力しても時刻がずれずにEOSで終わる
+        let (pipeline, received) = pipeline(
+            "klvtestsrc fps=30000/1001 num-buffers=300 timestamp-offset=1000 \
+             ! fakesink name=sink sync=false signal-handoffs=true",
+        );
+        pipeline.set_state(gst::State::Playing).unwrap();
+        run(&pipeline, gst::MessageType::Eos);
+        pipeline.set_state(gst::State::Null).unwrap();
+        let expected = (0..300)
+            .map(|i| {
+                let pts = 1000 + i * 1001 * gst::ClockTime::SECOND.nseconds() / 30000;
+                (gst::ClockTime::from_nseconds(pts), i)
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(*received.lock().unwrap(), expected);
+    }
+
+    #[test]
+    fn test_count_at() {
+        let fps = gst::Fraction::new(30000, 1001);
+        for count in [0, 1, 2, 29, 30, 1000, 107892] {
+            let ts = super::timestamp(fps, count);
+            assert_eq!(super::count_at(fps, ts), count);
+            assert_eq!(
+                super::count_at(fps, ts + gst::ClockTime::from_nseconds(1)),
+                count
+            );
+        }
+    }
+
+    // 受け取ったKLVの飛行の状態
+    fn flight(description: &str) -> Vec<crate::metaklv::FlightTelemetry> {
+        let (pipeline, _) = pipeline(description);
+        let flights: Arc<Mutex<Vec<_>>> = Arc::default();
+        let f = flights.clone();
+        pipeline
+            .by_name("sink")
+            .unwrap()
+            .connect("handoff", false, move |args| {
+                let buffer = args[1].get::<gst::Buffer>().unwrap();
+                let map = buffer.map_readable().unwrap();
+                match crate::metaklv::KlvDataset::from_bytes(&map).unwrap() {
+                    crate::metaklv::KlvDataset::Example(ds) => {
+                        f.lock().unwrap().push(ds.flight().unwrap())
+                    }
+                    ds => panic!("unexpected dataset {:?}", ds),
+                }
+                None
+            });
+        pipeline.set_state(gst::State::Playing).unwrap();
+        run(&pipeline, gst::MessageType::Eos);
+        pipeline.set_state(gst::State::Null).unwrap();
+        let flights = flights.lock().unwrap().clone();
+        flights
+    }
+
+    #[test]
+    fn test_flight() {
+        let description =
+            "klvtestsrc path=circle radius=200 speed=20 noise=2 seed=7 num-buffers=30 \
+             ! fakesink name=sink sync=false signal-handoffs=true";
+        let a = flight(description);
+        assert_eq!(a.len(), 30);
+        // 同じseedなら同じ経路になる
+        assert_eq!(a, flight(description));
+        // 1秒で20m進み、時刻は固定の起点から数える
+        assert_eq!(a[0].timestamp, super::FIXED_EPOCH);
+        assert_eq!(a[29].timestamp - a[0].timestamp, 29 * 1_000_000 / 30);
+        assert!(a.iter().all(|t| (t.altitude - 100.0).abs() <= 2.0));
+        assert!(a.iter().all(|t| t.speed == 20.0));
+    }
+
+    #[test]
+    fn test_unlock() {
+        // liveで次のバッファを待っている間にstateを変えても待ち続けない
+        let (pipeline, _) = pipeline("klvtestsrc is-live=true fps=1/1 ! fakesink");
+        pipeline.set_state(gst::State::Playing).unwrap();
+        std::thread::sleep(Duration::from_millis(100));
+        let now = Instant::now();
+        pipeline.set_state(gst::State::Null).unwrap();
+        assert!(now.elapsed() < Duration::from_millis(500));
+    }
+}
@@ -6,6 +6,7 @@ use gst::prelude::*;
 const ELEMENT_NAME: &str = "klvtestsrc";
 const CLASS_NAME: &str = "KlvTestSrc";
 
+mod flight;
 mod imp;
 
 gst::glib::wrapper! {
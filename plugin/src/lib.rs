@@ -2,11 +2,16 @@
 
 use gst::glib;
 
+#[cfg(feature = "v1_20")]
+mod custommeta;
 mod exampletestsrc;
 mod klvtestsrc;
 mod metademux;
+mod metafilesrc;
 mod metaklv;
 mod metamux;
+mod metasrtenc;
+mod metatextmux;
 mod metatrans;
 mod testtrans;
 
@@ -26,11 +31,64 @@ gst::plugin_define!(
 );
 
 fn plugin_init(plugin: &gst::Plugin) -> Result<(), glib::BoolError> {
+    #[cfg(feature = "v1_20")]
+    custommeta::register()?;
     testtrans::register(plugin)?;
     metatrans::register(plugin)?;
     exampletestsrc::register(plugin)?;
     klvtestsrc::register(plugin)?;
     metademux::register(plugin)?;
+    metafilesrc::register(plugin)?;
     metamux::register(plugin)?;
+    metasrtenc::register(plugin)?;
+    metatextmux::register(plugin)?;
     Ok(())
 }
+
+/// テストでpluginのエレメントを使えるようにする
+#[cfg(test)]
+fn test_init() {
+    static ONCE: std::sync::Once = std::sync::Once::new();
+    ONCE.call_once(|| {
+        gst::init().unwrap();
+        plugin_register_static().unwrap();
+    });
+}
+
+/// videotestsrcなどgst-plugins-baseのエレメントがあるか。無ければテストしない
+///
+/// CIでは必要なプラグインをインストールしているので、無ければskipせずに失敗させる
+#[cfg(test)]
+fn test_has_elements(names: &[&str]) -> bool {
+    test_init();
+    let missing = names
+        .iter()
+        .filter(|name| gst::ElementFactory::find(name).is_none())
+        .collect::<Vec<_>>();
+    if !missing.is_empty() {
+        assert!(
+            std::env::var_os("CI").is_none(),
+            "missing elements {:?}",
+            missing
+        );
+        eprintln!("skip: missing elements {:?}", missing);
+    }
+    missing.is_empty()
+}
+
+/// `until`のメッセージが届くまでbusを待つ。エラーならpanicする
+#[cfg(test)]
+fn test_run(pipeline: &gst::Pipeline, until: gst::MessageType) {
+    use gst::prelude::*;
+
+    let bus = pipeline.bus().unwrap();
+    let msg = bus
+        .timed_pop_filtered(
+            gst::ClockTime::from_seconds(10),
+            &[until, gst::MessageType::Error],
+        )
+        .expect("timeout");
+    if let gst::MessageView::Error(e) = msg.view() {
+        panic!("{:?}", e);
+    }
+}
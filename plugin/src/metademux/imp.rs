@@ -1,11 +1,30 @@
 //! MetaDemuxer
 //!
-//! Videoに埋め込まれたmetadataをvideo + klvの2ストリームに分割する
+//! Videoに埋め込まれたmetadataをvideo + klvのストリームに分割する
+//!
+//! capsは制限しないので、encode済みのvideoやaudioに付与されたmetadataも分割できる
+//!
+//! metaの種類ごとにsometimes padを持ち、最初にそのmetaを受け取った時にpadを生成する
+//! metaのないフレームではGAPイベントを送るので、muxer側はKLVを待たずに進められる
+//!
+//! `static-meta-pads`を有効にするとREADY→PAUSEDですべてのmeta padを生成する
+//! 繋がっていないmeta padはvideoの出力を止めない
+//!
+//! 抽出するmetaは`extract`で選び、`strip`を有効にすると抽出したmetaをvideoから削除する
+//!
+//! `decouple`を有効にするとsrc padごとのタスクとキューから出力するので
+//! queueを挟まずにencoderやmuxerに繋げられる
+//!
+//! - `meta_rs`: ExampleRsMeta。datasetプロパティでKLVの種類を選ぶ
+//! - `meta_c`: ExampleCMeta
+//! - `meta_custom`: GstCustomMeta(`v1_20` featureが必要)
 use std::ops::Deref;
-use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
 
+use ec_meta::ExampleCMeta;
 use ers_meta::ExampleRsMeta;
-use gst::prelude::{ElementClassExt, ElementExtManual, PadExtManual};
+use gst::prelude::{ElementClassExt, ElementExtManual, PadExtManual, ParamSpecBuilderExt, ToValue};
 use gst::subclass::prelude::{
     ElementImpl, ElementImplExt, GstObjectImpl, ObjectImpl, ObjectImplExt, ObjectSubclass,
     ObjectSubclassExt,
@@ -15,12 +34,20 @@ use gst::{glib, EventView, Segment};
 use gst_base::UniqueFlowCombiner;
 use once_cell::sync::Lazy;
 
-use crate::metaklv::{ExampleDataset, KLV_CAPS};
+#[cfg(feature = "v1_20")]
+use crate::custommeta;
+#[cfg(feature = "v1_20")]
+use crate::metaklv::CustomDataset;
+use crate::metaklv::{
+    unencodable_details, ExampleCDataset, ExampleDataset, KlvDataset, KlvError, KlvErrorPolicy,
+    UasDatalinkLS, KLV_CAPS,
+};
 
+use super::queue::{Limits, OutputQueue};
 use super::CLASS_NAME;
 use super::ELEMENT_NAME;
 
-static CAT: Lazy<gst::DebugCategory> = Lazy::new(|| {
+pub(super) static CAT: Lazy<gst::DebugCategory> = Lazy::new(|| {
     gst::DebugCategory::new(
         ELEMENT_NAME,
         gst::DebugColorFlags::empty(),
@@ -28,18 +55,150 @@ static CAT: Lazy<gst::DebugCategory> = Lazy::new(|| {
     )
 });
 
+/// 抽出するmetaの種類を選ぶフラグ
+///
+/// glib::flagsはcfgを付けた値を扱えないので`v1_20`の有無で定義を分ける
+#[cfg(feature = "v1_20")]
+#[glib::flags(name = "GstMetaDemuxExtractFlags")]
+enum ExtractFlags {
+    #[flags_value(name = "Rs: ExampleRsMeta", nick = "rs")]
+    RS = 0b0000_0001,
+    #[flags_value(name = "C: ExampleCMeta", nick = "c")]
+    C = 0b0000_0010,
+    #[flags_value(name = "Custom: GstCustomMeta", nick = "custom")]
+    CUSTOM = 0b0000_0100,
+}
+
+#[cfg(not(feature = "v1_20"))]
+#[glib::flags(name = "GstMetaDemuxExtractFlags")]
+enum ExtractFlags {
+    #[flags_value(name = "Rs: ExampleRsMeta", nick = "rs")]
+    RS = 0b0000_0001,
+    #[flags_value(name = "C: ExampleCMeta", nick = "c")]
+    C = 0b0000_0010,
+}
+
+/// meta_rsに出力するKLVの種類
+///
+/// ExampleRsMetaから変換できるdatasetだけを選べるようにKlvDatasetTypeとは分ける
+#[derive(Default, Debug, PartialEq, Eq, Clone, Copy, glib::Enum)]
+#[repr(u32)]
+#[enum_type(name = "GstMetaDemuxRsDataset")]
+enum RsDataset {
+    #[default]
+    #[enum_value(name = "Example: ExampleDataset", nick = "example")]
+    Example = 0,
+    #[enum_value(
+        name = "UasDatalink: MISB ST 0601 UAS Datalink LS",
+        nick = "uas-datalink"
+    )]
+    UasDatalink = 1,
+}
+
+/// 分離するmetaの種類
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+enum MetaKind {
+    Rs,
+    C,
+    #[cfg(feature = "v1_20")]
+    Custom,
+}
+
+impl MetaKind {
+    const ALL: &'static [MetaKind] = &[
+        MetaKind::Rs,
+        MetaKind::C,
+        #[cfg(feature = "v1_20")]
+        MetaKind::Custom,
+    ];
+
+    // pad templateとpadの名前
+    fn pad_name(&self) -> &'static str {
+        match self {
+            MetaKind::Rs => "meta_rs",
+            MetaKind::C => "meta_c",
+            #[cfg(feature = "v1_20")]
+            MetaKind::Custom => "meta_custom",
+        }
+    }
+
+    fn flag(&self) -> ExtractFlags {
+        match self {
+            MetaKind::Rs => ExtractFlags::RS,
+            MetaKind::C => ExtractFlags::C,
+            #[cfg(feature = "v1_20")]
+            MetaKind::Custom => ExtractFlags::CUSTOM,
+        }
+    }
+
+    // 抽出したmetaをバッファから削除する
+    fn strip(&self, buffer: &mut gst::BufferRef) {
+        match self {
+            MetaKind::Rs => {
+                ers_meta::ExampleRsMeta::remove(buffer);
+            }
+            MetaKind::C => {
+                ec_meta::ExampleCMeta::remove(buffer);
+            }
+            #[cfg(feature = "v1_20")]
+            MetaKind::Custom => {
+                custommeta::remove(buffer);
+            }
+        }
+    }
+}
+
+const DEFAULT_MAX_SIZE_BUFFERS: u32 = 200;
+const DEFAULT_MAX_SIZE_TIME: gst::ClockTime = gst::ClockTime::SECOND;
+
+#[derive(Debug)]
+struct Settings {
+    // meta_rsに出力するKLVの種類
+    dataset: RsDataset,
+    error_policy: KlvErrorPolicy,
+    static_meta_pads: bool,
+    extract: ExtractFlags,
+    strip: bool,
+    decouple: bool,
+    limits: Limits,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            dataset: RsDataset::default(),
+            error_policy: KlvErrorPolicy::default(),
+            static_meta_pads: false,
+            extract: ExtractFlags::all(),
+            strip: false,
+            decouple: false,
+            limits: Limits {
+                max_buffers: DEFAULT_MAX_SIZE_BUFFERS,
+                max_time: DEFAULT_MAX_SIZE_TIME,
+            },
+        }
+    }
+}
+
 #[derive(Default)]
 pub struct State {
     // metaストリームをsrcと同じsegmentにするため
     segment: Segment,
+    segment_seqnum: Option<gst::Seqnum>,
+    // 両方のsrcから届く同じseekを1回だけ上流に送るため
+    seek_seqnum: Option<gst::Seqnum>,
 }
 
 pub struct MetaDemux {
     sinkpad: gst::Pad,
     srcpad: gst::Pad,
-    klvsrcpad: Mutex<Option<gst::Pad>>,
+    klvsrcpads: Mutex<Vec<(MetaKind, gst::Pad)>>,
+    // decouple時のsrc padごとの出力キュー
+    queues: Mutex<Vec<Arc<OutputQueue>>>,
     flow_combiner: Mutex<UniqueFlowCombiner>,
     state: Mutex<State>,
+    settings: Mutex<Settings>,
+    malformed: AtomicU64,
 }
 
 impl MetaDemux {
@@ -54,27 +213,124 @@ impl MetaDemux {
         res
     }
 
+    // 下流へのイベントの配信先
+    //
+    // - segment, flush, eos, gap: すべてのsrc pad
+    // - stream-start, caps, tagなど: videoの情報なのでsrcのみ
+    //
+    // meta padは自身のstream-startとcapsをpad生成時に送る
     fn sink_event(&self, pad: &gst::Pad, event: gst::Event) -> bool {
         gst::trace!(CAT, obj: pad, "Handling event {:?}", event);
-        match event.type_() {
+        match event.view() {
             // Segmentはsrcと同じものにする
-            gst::EventType::Segment => {
+            // 後から生成するmeta padにも同じseqnumで送るため記録する
+            EventView::Segment(seg) => {
                 gst::trace!(
                     CAT,
                     obj: pad,
                     "Segment rtoffset {:?}",
                     event.running_time_offset()
                 );
-                if let EventView::Segment(seg) = event.view() {
-                    gst::trace!(CAT, obj: pad, "Segment: {:?}", seg.segment());
-                    let mut state = self.state.lock().unwrap();
-                    state.segment = seg.segment().clone();
+                gst::trace!(CAT, obj: pad, "Segment: {:?}", seg.segment());
+                let mut state = self.state.lock().unwrap();
+                state.segment = seg.segment().clone();
+                state.segment_seqnum = Some(event.seqnum());
+                drop(state);
+                self.forward_event(event)
+            }
+            // flushはキューを通さずに送り、キューのタスクを止めてから空にする
+            EventView::FlushStart(_) => {
+                let queues = self.queues.lock().unwrap().clone();
+                for queue in queues.iter() {
+                    queue.flush_start();
+                }
+                let res = gst::Pad::event_default(pad, Some(&*self.obj()), event);
+                for queue in queues.iter() {
+                    queue.pause();
                 }
-                gst::Pad::event_default(pad, Some(&*self.obj()), event)
+                res
             }
-            // Eosは両ストリームに配信する
-            gst::EventType::Eos => gst::Pad::event_default(pad, Some(&*self.obj()), event),
-            _ => self.srcpad.push_event(event),
+            // flush後は各padのflowをリセットする
+            EventView::FlushStop(_) => {
+                let res = gst::Pad::event_default(pad, Some(&*self.obj()), event);
+                self.flow_combiner.lock().unwrap().reset();
+                for queue in self.queues.lock().unwrap().iter() {
+                    if let Err(e) = queue.start() {
+                        gst::error!(CAT, obj: queue.pad(), "failed to start task: {}", e);
+                    }
+                }
+                res
+            }
+            EventView::Eos(_) | EventView::Gap(_) => self.forward_event(event),
+            _ => self.push_event_to(&self.srcpad, event),
+        }
+    }
+
+    // すべてのsrc padにイベントを送る
+    fn forward_event(&self, event: gst::Event) -> bool {
+        let klvpads = self
+            .klvsrcpads
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(_, pad)| pad.clone())
+            .collect::<Vec<_>>();
+        std::iter::once(self.srcpad.clone())
+            .chain(klvpads)
+            .fold(false, |res, pad| {
+                self.push_event_to(&pad, event.clone()) | res
+            })
+    }
+
+    fn queue(&self, pad: &gst::Pad) -> Option<Arc<OutputQueue>> {
+        self.queues
+            .lock()
+            .unwrap()
+            .iter()
+            .find(|queue| queue.pad() == pad)
+            .cloned()
+    }
+
+    // decouple時はキューに積み、タスクからpushする
+    fn push_buffer(
+        &self,
+        pad: &gst::Pad,
+        buffer: gst::Buffer,
+    ) -> Result<gst::FlowSuccess, gst::FlowError> {
+        match self.queue(pad) {
+            Some(queue) => queue.push_buffer(buffer),
+            None => pad.push(buffer),
+        }
+    }
+
+    // serializedなイベントはバッファとの順序を保つためキューに積む
+    fn push_event_to(&self, pad: &gst::Pad, event: gst::Event) -> bool {
+        match self.queue(pad) {
+            Some(queue) if event.is_serialized() => queue.push_event(event),
+            _ => pad.push_event(event),
+        }
+    }
+
+    // decouple時はsrc padのタスクを開始する
+    fn start_queue(&self, pad: &gst::Pad) {
+        let limits = {
+            let settings = self.settings.lock().unwrap();
+            if !settings.decouple {
+                return;
+            }
+            settings.limits
+        };
+        let queue = OutputQueue::new(pad, limits);
+        if let Err(e) = queue.start() {
+            gst::error!(CAT, obj: pad, "failed to start task: {}", e);
+        }
+        self.queues.lock().unwrap().push(queue);
+    }
+
+    fn stop_queues(&self) {
+        let queues = std::mem::take(&mut *self.queues.lock().unwrap());
+        for queue in queues {
+            queue.stop();
         }
     }
     fn sink_query(&self, pad: &gst::Pad, query: &mut gst::QueryRef) -> bool {
@@ -84,7 +340,7 @@ impl MetaDemux {
     }
     fn src_event(&self, pad: &gst::Pad, event: gst::Event) -> bool {
         gst::trace!(CAT, obj: pad, "Handling event {:?}", event);
-        gst::Pad::event_default(pad, Some(&*self.obj()), event)
+        self.upstream_event(pad, event)
     }
     fn src_query(&self, pad: &gst::Pad, query: &mut gst::QueryRef) -> bool {
         gst::trace!(CAT, obj: pad, "Handling query {:?}", query);
@@ -93,7 +349,34 @@ impl MetaDemux {
     }
     fn src_event_klv(&self, pad: &gst::Pad, event: gst::Event) -> bool {
         gst::trace!(CAT, obj: pad, "Handling klv event {:?}", event);
-        gst::Pad::event_default(pad, Some(&*self.obj()), event)
+        match event.view() {
+            // videoのQoSではないので上流には送らない
+            EventView::Qos(_) => false,
+            _ => self.upstream_event(pad, event),
+        }
+    }
+
+    // 上流へのイベント
+    //
+    // pipelineのseekはすべてのsinkから送られるので、srcとmetaから同じseqnumのseekが届く
+    // 上流には最初の1回だけ送る
+    fn upstream_event(&self, pad: &gst::Pad, event: gst::Event) -> bool {
+        match event.view() {
+            EventView::Seek(_) => {
+                let seqnum = event.seqnum();
+                {
+                    let mut state = self.state.lock().unwrap();
+                    if state.seek_seqnum == Some(seqnum) {
+                        gst::debug!(CAT, obj: pad, "seek {:?} is already sent", seqnum);
+                        return true;
+                    }
+                    state.seek_seqnum = Some(seqnum);
+                }
+                gst::debug!(CAT, obj: pad, "forward seek {:?}", seqnum);
+                self.sinkpad.push_event(event)
+            }
+            _ => gst::Pad::event_default(pad, Some(&*self.obj()), event),
+        }
     }
     fn src_query_klv(&self, pad: &gst::Pad, query: &mut gst::QueryRef) -> bool {
         gst::trace!(CAT, obj: pad, "Handling klv query {:?}", query);
@@ -101,8 +384,8 @@ impl MetaDemux {
     }
 
     // 特定のmetaを含む場合はklvpadを生成
-    fn create_pad(&self) -> gst::Pad {
-        let name = "meta";
+    fn create_pad(&self, kind: MetaKind) -> gst::Pad {
+        let name = kind.pad_name();
         let templ = self.obj().element_class().pad_template(name).unwrap();
         let srcpad = gst::Pad::builder_with_template(&templ, Some(name))
             .event_function(|pad, parent, event| {
@@ -117,84 +400,258 @@ impl MetaDemux {
 
         let full_stream_id = srcpad.create_stream_id(&*self.obj(), Some(name));
         gst::debug!(CAT, imp: self, "metapad stream_name ({:?})", full_stream_id);
-        srcpad.push_event(gst::event::StreamStart::new(&full_stream_id));
+        // metaのないフレームがあるので疎なストリームとして扱ってもらう
+        srcpad.push_event(
+            gst::event::StreamStart::builder(&full_stream_id)
+                .flags(gst::StreamFlags::SPARSE)
+                .build(),
+        );
         srcpad.push_event(gst::event::Caps::new(&KLV_CAPS));
 
-        let segment = self.state.lock().unwrap().segment.clone();
+        let (segment, seqnum) = {
+            let state = self.state.lock().unwrap();
+            (state.segment.clone(), state.segment_seqnum)
+        };
         gst::debug!(CAT, imp: self, "metapad segment ({:?})", &segment);
-        srcpad.push_event(gst::event::Segment::new(&segment));
+        // 上流のsegmentを受け取る前に生成した場合は仮のsegmentを送り、後から上書きする
+        let segment = if segment.format() == gst::Format::Undefined {
+            gst::FormattedSegment::<gst::ClockTime>::new().upcast()
+        } else {
+            segment
+        };
+        let mut builder = gst::event::Segment::builder(&segment);
+        if let Some(seqnum) = seqnum {
+            builder = builder.seqnum(seqnum);
+        }
+        srcpad.push_event(builder.build());
+        self.start_queue(&srcpad);
+
+        srcpad
+    }
+
+    // 他スレッドとの生成競合に負けたpadを片付ける
+    fn discard_pad(&self, pad: &gst::Pad) {
+        let queue = {
+            let mut queues = self.queues.lock().unwrap();
+            let index = queues.iter().position(|queue| queue.pad() == pad);
+            index.map(|index| queues.remove(index))
+        };
+        if let Some(queue) = queue {
+            queue.stop();
+        }
+        let _ = pad.set_active(false);
+    }
+
+    // PAUSED→READYでmeta padを削除する
+    fn remove_pads(&self) {
+        let klvpads = std::mem::take(&mut *self.klvsrcpads.lock().unwrap());
+        for (_, pad) in klvpads {
+            self.flow_combiner.lock().unwrap().remove_pad(&pad);
+            let _ = pad.set_active(false);
+            self.obj().remove_pad(&pad).unwrap();
+        }
+        self.flow_combiner.lock().unwrap().reset();
+    }
+
+    // 種類ごとのpadを取得し、まだなければ生成する
+    // add_padはpad-addedシグナルを同期的に発行し、その中からforward_eventなどが
+    // klvsrcpadsを参照しうるのでロックを持ったまま呼ばない
+    fn klv_pad(&self, kind: MetaKind) -> gst::Pad {
+        let find = |klvpads: &[(MetaKind, gst::Pad)]| {
+            klvpads
+                .iter()
+                .find(|(k, _)| *k == kind)
+                .map(|(_, pad)| pad.clone())
+        };
+        if let Some(klvpad) = find(&self.klvsrcpads.lock().unwrap()) {
+            return klvpad;
+        }
+        let srcpad = self.create_pad(kind);
+        {
+            let mut klvpads = self.klvsrcpads.lock().unwrap();
+            // 生成中に他のスレッドが同じ種類のpadを登録していればそちらを使う
+            if let Some(klvpad) = find(&klvpads) {
+                drop(klvpads);
+                self.discard_pad(&srcpad);
+                return klvpad;
+            }
+            klvpads.push((kind, srcpad.clone()));
+        }
         self.obj().add_pad(&srcpad).unwrap();
         self.flow_combiner.lock().unwrap().add_pad(&srcpad);
-
+        gst::trace!(
+            CAT,
+            imp: self,
+            "videopad stream_name ({:?})",
+            self.srcpad.stream_id().unwrap()
+        );
         srcpad
     }
 
-    fn sink_klv(&self, buffer: gst::Buffer) -> Result<gst::FlowSuccess, gst::FlowError> {
-        if let Some(meta) = buffer.meta::<ExampleRsMeta>() {
-            let klvpad = {
-                let mut klvpad = self.klvsrcpad.lock().unwrap();
-                if let Some(ref klvpad) = *klvpad {
-                    klvpad.clone()
-                } else {
-                    let srcpad = self.create_pad();
-                    *klvpad = Some(srcpad.clone());
-                    gst::trace!(
-                        CAT,
-                        imp: self,
-                        "videopad stream_name ({:?})",
-                        self.srcpad.stream_id().unwrap()
-                    );
-
-                    srcpad
+    // 対応するmetaを持っていればKLVに変換する
+    fn meta_dataset(&self, kind: MetaKind, buffer: &gst::BufferRef) -> Option<KlvDataset> {
+        let dataset = match kind {
+            // ExampleRsMetaはdatasetプロパティで選んだ形式にする
+            MetaKind::Rs => {
+                let meta = buffer.meta::<ExampleRsMeta>()?;
+                match self.settings.lock().unwrap().dataset {
+                    // Precision Time StampにはバッファのPTSを使う
+                    RsDataset::UasDatalink => {
+                        KlvDataset::UasDatalink(UasDatalinkLS::from_example_rs(
+                            meta.deref(),
+                            buffer.pts().map_or(0, |pts| pts.useconds()),
+                        ))
+                    }
+                    RsDataset::Example => KlvDataset::Example(ExampleDataset::from(meta.deref())),
                 }
+            }
+            MetaKind::C => KlvDataset::ExampleC(ExampleCDataset::from(
+                buffer.meta::<ExampleCMeta>()?.deref(),
+            )),
+            #[cfg(feature = "v1_20")]
+            MetaKind::Custom => {
+                KlvDataset::Custom(CustomDataset::from(custommeta::structure(buffer)?.as_ref()))
+            }
+        };
+        Some(dataset)
+    }
+
+    // KLVに変換できなかったmetaを記録してwarningを送る
+    fn report_malformed(&self, dataset: &KlvDataset, err: &KlvError) {
+        let count = self.malformed.fetch_add(1, Ordering::Relaxed) + 1;
+        gst::warning!(
+            CAT,
+            imp: self,
+            "failed to encode klv ({} total) {:?}: {}",
+            count,
+            dataset,
+            err
+        );
+        gst::element_imp_warning!(
+            self,
+            gst::StreamError::Encode,
+            ["Failed to encode KLV packet: {}", err],
+            details: unencodable_details(dataset)
+        );
+    }
+
+    fn sink_klv(&self, mut buffer: gst::Buffer) -> Result<gst::FlowSuccess, gst::FlowError> {
+        let (extract, strip) = {
+            let settings = self.settings.lock().unwrap();
+            (settings.extract, settings.strip)
+        };
+        let mut records = vec![];
+        for kind in MetaKind::ALL
+            .iter()
+            .filter(|kind| extract.contains(kind.flag()))
+        {
+            let dataset = match self.meta_dataset(*kind, &buffer) {
+                Some(dataset) => dataset,
+                None => continue,
             };
-            let records = serde_klv::to_bytes(&ExampleDataset::from(meta.deref())).unwrap();
-            let mut klvbuf = gst::Buffer::with_size(records.len()).unwrap();
-            {
-                let mut bw = klvbuf.make_mut().map_writable().unwrap();
-                bw.copy_from_slice(&records);
-            }
-            // metaはsrc依存なのでsrc bufferと同じptsを指定する
-            {
-                let bufref = klvbuf.make_mut();
-                if let Some(pts) = buffer.pts() {
-                    gst::trace!(CAT, imp: self, "pts {}", pts);
-                    bufref.set_pts(pts);
-                }
-                if let Some(dts) = buffer.dts() {
-                    bufref.set_dts(dts);
+            match dataset.to_bytes() {
+                Ok(r) => records.push((*kind, r)),
+                Err(e) => {
+                    self.report_malformed(&dataset, &e);
+                    match self.settings.lock().unwrap().error_policy {
+                        KlvErrorPolicy::Drop => {
+                            gst::debug!(CAT, imp: self, "drop video frame {:?}", buffer.pts());
+                            return Ok(gst::FlowSuccess::Ok);
+                        }
+                        // 変換できなかった種類のKLVだけを出力しない
+                        KlvErrorPolicy::Pass => (),
+                        KlvErrorPolicy::Error => {
+                            gst::element_imp_error!(
+                                self,
+                                gst::StreamError::Encode,
+                                ["Failed to encode KLV packet: {}", e]
+                            );
+                            return Err(gst::FlowError::Error);
+                        }
+                    }
                 }
-                if let Some(dur) = buffer.duration() {
-                    bufref.set_duration(dur);
+            }
+        }
+
+        // 抽出したmetaを削除する。削除するmetaがある時だけwritableにする
+        if strip && !records.is_empty() {
+            let bufref = buffer.make_mut();
+            for (kind, _) in records.iter() {
+                kind.strip(bufref);
+            }
+        }
+
+        // metaはsrc依存なのでsrc bufferと同じptsを指定する
+        // ptsの順序が入れ替わるストリームでもmuxerがdtsで並べられるようにdtsもコピーする
+        let klvbufs = records
+            .into_iter()
+            .map(|(kind, records)| {
+                let mut klvbuf = gst::Buffer::from_mut_slice(records);
+                {
+                    let bufref = klvbuf.get_mut().unwrap();
+                    bufref.set_pts(buffer.pts());
+                    bufref.set_dts(buffer.dts());
+                    bufref.set_duration(buffer.duration());
+                    bufref.set_offset(buffer.offset());
                 }
-                bufref.set_offset(buffer.offset());
-            }
-            // teeと同じくalwaysなsrcからpush
-            // この後ろ次第だが基本的にはqueueを繋ぐ必要がある
-            gst::trace!(CAT, imp: self, "before push video {}", buffer.offset());
-            let res_src = self.srcpad.push(buffer);
-            gst::trace!(CAT, imp: self, "after push srcpad");
-            self.flow_combiner
-                .lock()
-                .unwrap()
-                .update_pad_flow(&self.srcpad, res_src)?;
-
-            // 後からmetaをpush
-            // encodeなどがある場合は複数回呼ばれるためこちらの後ろにもqueueがあるのが望ましい
-            gst::trace!(CAT, imp: self, "before push klv");
-            let res_klv = klvpad.push(klvbuf);
-            gst::trace!(CAT, imp: self, "after push klv");
-            self.flow_combiner
+                (self.klv_pad(kind), klvbuf)
+            })
+            .collect::<Vec<_>>();
+        // このフレームで出力するKLVがないpadには同じ時刻のGAPを送る
+        // B-frameなどでptsは前後するので、GAPの時刻は単調増加するdtsを優先する
+        let gaps = match buffer.dts_or_pts() {
+            Some(ts) => self
+                .klvsrcpads
                 .lock()
                 .unwrap()
-                .update_pad_flow(&klvpad, res_klv)
-        } else {
-            let res = self.srcpad.push(buffer);
-            self.flow_combiner
+                .iter()
+                .filter(|(_, pad)| klvbufs.iter().all(|(klvpad, _)| klvpad != pad))
+                .map(|(_, pad)| {
+                    let gap = gst::event::Gap::builder(ts)
+                        .duration(buffer.duration())
+                        .build();
+                    (pad.clone(), gap)
+                })
+                .collect::<Vec<_>>(),
+            None => vec![],
+        };
+
+        // teeと同じくalwaysなsrcからpush
+        // この後ろ次第だが基本的にはqueueを繋ぐ必要がある
+        gst::trace!(CAT, imp: self, "before push video {}", buffer.offset());
+        let res_src = self.push_buffer(&self.srcpad, buffer);
+        gst::trace!(CAT, imp: self, "after push srcpad");
+        let mut res = self
+            .flow_combiner
+            .lock()
+            .unwrap()
+            .update_pad_flow(&self.srcpad, res_src)?;
+
+        // 後からmetaをpush
+        // encodeなどがある場合は複数回呼ばれるためこちらの後ろにもqueueがあるのが望ましい
+        for (klvpad, klvbuf) in klvbufs {
+            gst::trace!(CAT, obj: klvpad, "before push klv");
+            // 繋がっていないmeta padのためにvideoを止めない
+            let res_klv = match self.push_buffer(&klvpad, klvbuf) {
+                Err(gst::FlowError::NotLinked) => {
+                    gst::trace!(CAT, obj: klvpad, "meta pad is not linked");
+                    Ok(gst::FlowSuccess::Ok)
+                }
+                res => res,
+            };
+            gst::trace!(CAT, obj: klvpad, "after push klv");
+            res = self
+                .flow_combiner
                 .lock()
                 .unwrap()
-                .update_pad_flow(&self.srcpad, res)
+                .update_pad_flow(&klvpad, res_klv)?;
+        }
+        for (klvpad, gap) in gaps {
+            if !self.push_event_to(&klvpad, gap) {
+                gst::trace!(CAT, obj: klvpad, "gap event was not handled");
+            }
         }
+        Ok(res)
     }
 }
 
@@ -214,8 +671,8 @@ impl ElementImpl for MetaDemux {
 
     fn pad_templates() -> &'static [gst::PadTemplate] {
         static PAD_TEMPLATES: Lazy<Vec<gst::PadTemplate>> = Lazy::new(|| {
-            // encode後はフレームの順序が変わることがあるのでエンコード前に分離したいのでx-rawに限定する
-            let caps = gst::Caps::builder("video/x-raw").build();
+            // KLVにはフレームのpts/dtsをそのままコピーするので、encode済みのvideoやaudioも扱える
+            let caps = gst::Caps::new_any();
             let src_pad_template = gst::PadTemplate::new(
                 "src",
                 gst::PadDirection::Src,
@@ -232,24 +689,220 @@ impl ElementImpl for MetaDemux {
             )
             .unwrap();
 
-            let meta_pad_template = {
+            let mut templates = vec![src_pad_template, sink_pad_template];
+            // metaの種類ごとにpadを分ける
+            templates.extend(MetaKind::ALL.iter().map(|kind| {
                 gst::PadTemplate::new(
-                    "meta",
+                    kind.pad_name(),
                     gst::PadDirection::Src,
                     gst::PadPresence::Sometimes,
                     &KLV_CAPS,
                 )
                 .unwrap()
-            };
-
-            vec![src_pad_template, sink_pad_template, meta_pad_template]
+            }));
+            templates
         });
 
         PAD_TEMPLATES.as_ref()
     }
+
+    fn change_state(
+        &self,
+        transition: gst::StateChange,
+    ) -> Result<gst::StateChangeSuccess, gst::StateChangeError> {
+        gst::trace!(CAT, imp: self, "Changing state {:?}", transition);
+        // 待機中のchainとタスクを終了させる
+        if let gst::StateChange::PausedToReady = transition {
+            self.stop_queues();
+        }
+        let res = self.parent_change_state(transition)?;
+        match transition {
+            gst::StateChange::ReadyToPaused => {
+                self.start_queue(&self.srcpad);
+                let (static_meta_pads, extract) = {
+                    let settings = self.settings.lock().unwrap();
+                    (settings.static_meta_pads, settings.extract)
+                };
+                if static_meta_pads {
+                    for kind in MetaKind::ALL
+                        .iter()
+                        .filter(|kind| extract.contains(kind.flag()))
+                    {
+                        self.klv_pad(*kind);
+                    }
+                    self.obj().no_more_pads();
+                }
+            }
+            gst::StateChange::PausedToReady => {
+                self.remove_pads();
+                *self.state.lock().unwrap() = State::default();
+            }
+            _ => (),
+        }
+        Ok(res)
+    }
 }
 
 impl ObjectImpl for MetaDemux {
+    fn properties() -> &'static [glib::ParamSpec] {
+        static PROPERTIES: Lazy<Vec<glib::ParamSpec>> = Lazy::new(|| {
+            vec![
+                glib::ParamSpecEnum::builder::<RsDataset>("dataset", RsDataset::default())
+                    .nick("Dataset")
+                    .blurb("select klv dataset of meta_rs pad (example or uas-datalink)")
+                    .build(),
+                glib::ParamSpecEnum::builder::<KlvErrorPolicy>(
+                    "error-policy",
+                    KlvErrorPolicy::default(),
+                )
+                .nick("Error policy")
+                .blurb("handling of video frame with metadata failed to encode klv")
+                .mutable_playing()
+                .build(),
+                glib::ParamSpecBoolean::builder("static-meta-pads")
+                    .nick("Static meta pads")
+                    .blurb("create all meta pads at READY to PAUSED instead of on first metadata")
+                    .default_value(false)
+                    .mutable_ready()
+                    .build(),
+                glib::ParamSpecFlags::builder::<ExtractFlags>("extract")
+                    .nick("Extract")
+                    .blurb("metadata types to extract into klv")
+                    .default_value(ExtractFlags::all())
+                    .mutable_ready()
+                    .build(),
+                glib::ParamSpecBoolean::builder("strip")
+                    .nick("Strip")
+                    .blurb("remove extracted metadata from video buffer")
+                    .default_value(false)
+                    .mutable_playing()
+                    .build(),
+                glib::ParamSpecBoolean::builder("decouple")
+                    .nick("Decouple")
+                    .blurb("push each src pad from its own streaming task with a bounded queue")
+                    .default_value(false)
+                    .mutable_ready()
+                    .build(),
+                glib::ParamSpecUInt::builder("max-size-buffers")
+                    .nick("Max size buffers")
+                    .blurb("max number of buffers in each queue when decoupled (0=disable)")
+                    .default_value(DEFAULT_MAX_SIZE_BUFFERS)
+                    .mutable_ready()
+                    .build(),
+                glib::ParamSpecUInt64::builder("max-size-time")
+                    .nick("Max size time")
+                    .blurb("max amount of data in each queue when decoupled (in ns, 0=disable)")
+                    .default_value(DEFAULT_MAX_SIZE_TIME.nseconds())
+                    .mutable_ready()
+                    .build(),
+                glib::ParamSpecUInt64::builder("malformed-packets")
+                    .nick("Malformed packets")
+                    .blurb("number of metadata failed to encode klv")
+                    .read_only()
+                    .build(),
+            ]
+        });
+
+        PROPERTIES.as_ref()
+    }
+
+    fn set_property(&self, _id: usize, value: &glib::Value, pspec: &glib::ParamSpec) {
+        match pspec.name() {
+            "dataset" => {
+                let x = value.get::<RsDataset>().expect("type checked upstream");
+                gst::info!(CAT, imp: self, "set prop dataset to {:?}", x);
+                let mut settings = self.settings.lock().unwrap();
+                settings.dataset = x;
+            }
+            "error-policy" => {
+                let x = value
+                    .get::<KlvErrorPolicy>()
+                    .expect("type checked upstream");
+                gst::info!(CAT, imp: self, "set prop error-policy to {:?}", x);
+                let mut settings = self.settings.lock().unwrap();
+                settings.error_policy = x;
+            }
+            "static-meta-pads" => {
+                let x = value.get::<bool>().expect("type checked upstream");
+                gst::info!(CAT, imp: self, "set prop static-meta-pads to {:?}", x);
+                let mut settings = self.settings.lock().unwrap();
+                settings.static_meta_pads = x;
+            }
+            "extract" => {
+                let x = value.get::<ExtractFlags>().expect("type checked upstream");
+                gst::info!(CAT, imp: self, "set prop extract to {:?}", x);
+                let mut settings = self.settings.lock().unwrap();
+                settings.extract = x;
+            }
+            "strip" => {
+                let x = value.get::<bool>().expect("type checked upstream");
+                gst::info!(CAT, imp: self, "set prop strip to {:?}", x);
+                let mut settings = self.settings.lock().unwrap();
+                settings.strip = x;
+            }
+            "decouple" => {
+                let x = value.get::<bool>().expect("type checked upstream");
+                gst::info!(CAT, imp: self, "set prop decouple to {:?}", x);
+                let mut settings = self.settings.lock().unwrap();
+                settings.decouple = x;
+            }
+            "max-size-buffers" => {
+                let x = value.get::<u32>().expect("type checked upstream");
+                gst::info!(CAT, imp: self, "set prop max-size-buffers to {}", x);
+                let mut settings = self.settings.lock().unwrap();
+                settings.limits.max_buffers = x;
+            }
+            "max-size-time" => {
+                let x = gst::ClockTime::from_nseconds(
+                    value.get::<u64>().expect("type checked upstream"),
+                );
+                gst::info!(CAT, imp: self, "set prop max-size-time to {}", x);
+                let mut settings = self.settings.lock().unwrap();
+                settings.limits.max_time = x;
+            }
+            _ => unimplemented!(),
+        }
+    }
+
+    fn property(&self, _id: usize, pspec: &glib::ParamSpec) -> glib::Value {
+        match pspec.name() {
+            "dataset" => {
+                let settings = self.settings.lock().unwrap();
+                settings.dataset.to_value()
+            }
+            "error-policy" => {
+                let settings = self.settings.lock().unwrap();
+                settings.error_policy.to_value()
+            }
+            "static-meta-pads" => {
+                let settings = self.settings.lock().unwrap();
+                settings.static_meta_pads.to_value()
+            }
+            "extract" => {
+                let settings = self.settings.lock().unwrap();
+                settings.extract.to_value()
+            }
+            "strip" => {
+                let settings = self.settings.lock().unwrap();
+                settings.strip.to_value()
+            }
+            "decouple" => {
+                let settings = self.settings.lock().unwrap();
+                settings.decouple.to_value()
+            }
+            "max-size-buffers" => {
+                let settings = self.settings.lock().unwrap();
+                settings.limits.max_buffers.to_value()
+            }
+            "max-size-time" => {
+                let settings = self.settings.lock().unwrap();
+                settings.limits.max_time.nseconds().to_value()
+            }
+            "malformed-packets" => self.malformed.load(Ordering::Relaxed).to_value(),
+            _ => unimplemented!(),
+        }
+    }
+
     fn constructed(&self) {
         self.parent_constructed();
 
@@ -301,9 +954,335 @@ impl ObjectSubclass for MetaDemux {
         Self {
             sinkpad,
             srcpad,
-            klvsrcpad: Mutex::new(None),
+            klvsrcpads: Mutex::new(vec![]),
+            queues: Mutex::new(vec![]),
             flow_combiner: Mutex::new(flow_combiner),
             state: Mutex::new(State::default()),
+            settings: Mutex::new(Settings::default()),
+            malformed: AtomicU64::new(0),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::{Arc, Mutex};
+
+    use gst::prelude::*;
+
+    use crate::{test_has_elements as has_elements, test_run as run};
+
+    const WIDTH: u32 = 16;
+    const HEIGHT: u32 = 16;
+    const FRAMES: u64 = 30;
+    const FPS: u64 = 10;
+
+    // 生のvideoフレームをファイルに書き出す
+    fn write_raw_video(name: &str) -> std::path::PathBuf {
+        let path = std::env::temp_dir().join(format!("{}-{}.raw", name, std::process::id()));
+        let pipeline = gst::parse_launch(&format!(
+            "videotestsrc num-buffers={} ! video/x-raw,format=GRAY8,width={},height={},framerate={}/1 ! filesink location={}",
+            FRAMES, WIDTH, HEIGHT, FPS, path.display()
+        ))
+        .unwrap()
+        .downcast::<gst::Pipeline>()
+        .unwrap();
+        pipeline.set_state(gst::State::Playing).unwrap();
+        run(&pipeline, gst::MessageType::Eos);
+        pipeline.set_state(gst::State::Null).unwrap();
+        path
+    }
+
+    type Received = Arc<Mutex<Vec<gst::ClockTime>>>;
+
+    // ファイルを読んでmetaを付与し、metademuxで分けたvideoとklvのptsを記録するpipeline
+    fn demux_pipeline(path: &std::path::Path) -> (gst::Pipeline, Received, Received) {
+        let pipeline = gst::parse_launch(&format!(
+            "filesrc location={} ! rawvideoparse format=gray8 width={} height={} framerate={}/1 \
+             ! metatrans op=add ! metademux name=d \
+             ! queue ! fakesink name=video sync=false signal-handoffs=true \
+             d.meta_rs ! queue ! fakesink name=klv sync=false signal-handoffs=true",
+            path.display(),
+            WIDTH,
+            HEIGHT,
+            FPS
+        ))
+        .unwrap()
+        .downcast::<gst::Pipeline>()
+        .unwrap();
+        let received = |name: &str| {
+            let received: Received = Arc::default();
+            let r = received.clone();
+            pipeline
+                .by_name(name)
+                .unwrap()
+                .connect("handoff", false, move |args| {
+                    let buffer = args[1].get::<gst::Buffer>().unwrap();
+                    r.lock().unwrap().push(buffer.pts().unwrap());
+                    None
+                });
+            received
+        };
+        let video = received("video");
+        let klv = received("klv");
+        (pipeline, video, klv)
+    }
+
+    // PAUSEDでseekしてから再生し、seek位置以降のvideoとklvが揃っていることを確認する
+    fn seek_and_check(name: &str, seek_on: impl Fn(&gst::Pipeline, gst::Event) -> bool) {
+        if !has_elements(&["videotestsrc", "rawvideoparse"]) {
+            return;
+        }
+        let path = write_raw_video(name);
+        let (pipeline, video, klv) = demux_pipeline(&path);
+        pipeline.set_state(gst::State::Paused).unwrap();
+        run(&pipeline, gst::MessageType::AsyncDone);
+
+        let position = gst::ClockTime::from_seconds(1);
+        let seek = gst::event::Seek::new(
+            1.0,
+            gst::SeekFlags::FLUSH | gst::SeekFlags::ACCURATE,
+            gst::SeekType::Set,
+            position,
+            gst::SeekType::None,
+            gst::ClockTime::NONE,
+        );
+        assert!(seek_on(&pipeline, seek));
+        run(&pipeline, gst::MessageType::AsyncDone);
+        pipeline.set_state(gst::State::Playing).unwrap();
+        run(&pipeline, gst::MessageType::Eos);
+        pipeline.set_state(gst::State::Null).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let video = video.lock().unwrap().clone();
+        let klv = klv.lock().unwrap().clone();
+        let expected = (FPS..FRAMES)
+            .map(|i| gst::ClockTime::from_nseconds(i * gst::ClockTime::SECOND.nseconds() / FPS))
+            .collect::<Vec<_>>();
+        assert_eq!(video.first(), Some(&position));
+        assert_eq!(video, expected);
+        assert_eq!(klv, video);
+    }
+
+    #[test]
+    fn test_seek_pipeline() {
+        // pipelineのseekはvideoとklvの両方のsinkから上流に送られる
+        seek_and_check("metademux-seek-pipeline", |pipeline, seek| {
+            pipeline.send_event(seek)
+        });
+    }
+
+    #[test]
+    fn test_seek_from_meta_pad() {
+        seek_and_check("metademux-seek-meta", |pipeline, seek| {
+            pipeline.by_name("klv").unwrap().send_event(seek)
+        });
+    }
+
+    #[test]
+    fn test_dataset_values() {
+        crate::test_init();
+        // meta_rsから変換できないdatasetは選べない
+        let demux = gst::ElementFactory::make("metademux").build().unwrap();
+        let pspec = demux.find_property("dataset").unwrap();
+        let class = gst::glib::EnumClass::new(pspec.value_type()).unwrap();
+        let nicks = class
+            .values()
+            .iter()
+            .map(|value| value.nick())
+            .collect::<Vec<_>>();
+        assert_eq!(nicks, ["example", "uas-datalink"]);
+    }
+
+    #[test]
+    fn test_static_meta_pads() {
+        if !has_elements(&["videotestsrc"]) {
+            return;
+        }
+        // meta padを繋がなくてもvideoはEOSまで流れる
+        let pipeline = gst::parse_launch(
+            "videotestsrc num-buffers=5 ! metatrans op=add ! metademux name=d static-meta-pads=true ! fakesink",
+        )
+        .unwrap()
+        .downcast::<gst::Pipeline>()
+        .unwrap();
+        let demux = pipeline.by_name("d").unwrap();
+        assert!(demux.static_pad("meta_rs").is_none());
+
+        pipeline.set_state(gst::State::Paused).unwrap();
+        run(&pipeline, gst::MessageType::AsyncDone);
+        for name in super::MetaKind::ALL.iter().map(|kind| kind.pad_name()) {
+            let pad = demux.static_pad(name).unwrap();
+            assert!(pad.sticky_event::<gst::event::Caps>(0).is_some());
+            assert!(pad.sticky_event::<gst::event::Segment>(0).is_some());
+        }
+        pipeline.set_state(gst::State::Playing).unwrap();
+        run(&pipeline, gst::MessageType::Eos);
+
+        pipeline.set_state(gst::State::Null).unwrap();
+        assert!(demux.static_pad("meta_rs").is_none());
+    }
+
+    #[test]
+    fn test_pad_added_reentrant() {
+        if !has_elements(&["videotestsrc"]) {
+            return;
+        }
+        // pad-addedのハンドラから全padに転送されるイベントを送ってもデッドロックしない
+        let pipeline = gst::parse_launch(
+            "videotestsrc num-buffers=3 ! metatrans op=add ! metademux name=d ! fakesink",
+        )
+        .unwrap()
+        .downcast::<gst::Pipeline>()
+        .unwrap();
+        let demux = pipeline.by_name("d").unwrap();
+        let added = Arc::new(AtomicUsize::new(0));
+        let a = added.clone();
+        demux.connect_pad_added(move |demux, _| {
+            let gap = gst::event::Gap::builder(gst::ClockTime::ZERO).build();
+            demux.static_pad("sink").unwrap().send_event(gap);
+            a.fetch_add(1, Ordering::SeqCst);
+        });
+        pipeline.set_state(gst::State::Playing).unwrap();
+        run(&pipeline, gst::MessageType::Eos);
+        pipeline.set_state(gst::State::Null).unwrap();
+        assert_eq!(added.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn test_decouple() {
+        if !has_elements(&["videotestsrc"]) {
+            return;
+        }
+        // queueが無くてもmetamuxでvideoとklvを待ち合わせられる
+        let pipeline = gst::parse_launch(
+            "videotestsrc num-buffers=10 ! metatrans op=add \
+             ! metademux name=d static-meta-pads=true decouple=true max-size-buffers=2 \
+             ! metamux name=m ! fakesink d.meta_rs ! m.",
+        )
+        .unwrap()
+        .downcast::<gst::Pipeline>()
+        .unwrap();
+        pipeline.set_state(gst::State::Playing).unwrap();
+        run(&pipeline, gst::MessageType::Eos);
+        pipeline.set_state(gst::State::Null).unwrap();
+    }
+
+    #[test]
+    fn test_partial_meta_gap() {
+        if !has_elements(&["videotestsrc"]) {
+            return;
+        }
+        // metaのあるフレームとないフレームが交互に来てもmetamuxが止まらない
+        let pipeline = gst::parse_launch(
+            "videotestsrc num-buffers=6 ! video/x-raw,framerate=10/1 ! metatrans op=add \
+             ! metademux name=d static-meta-pads=true decouple=true extract=rs \
+             ! metamux name=m ! fakesink name=video signal-handoffs=true",
+        )
+        .unwrap()
+        .downcast::<gst::Pipeline>()
+        .unwrap();
+        let demux = pipeline.by_name("d").unwrap();
+        let count = AtomicUsize::new(0);
+        demux
+            .static_pad("sink")
+            .unwrap()
+            .add_probe(gst::PadProbeType::BUFFER, move |_, info| {
+                if count.fetch_add(1, Ordering::SeqCst) % 2 == 1 {
+                    if let Some(gst::PadProbeData::Buffer(ref mut buffer)) = info.data {
+                        ers_meta::ExampleRsMeta::remove(buffer.make_mut());
+                    }
+                }
+                gst::PadProbeReturn::Ok
+            });
+
+        // metamuxに届いたKLVとGAPの時刻を記録する
+        let klv: Arc<Mutex<Vec<(&str, gst::ClockTime)>>> = Arc::default();
+        let k = klv.clone();
+        let sinkpad = pipeline
+            .by_name("m")
+            .unwrap()
+            .request_pad_simple("sink_%u")
+            .unwrap();
+        sinkpad.add_probe(
+            gst::PadProbeType::BUFFER | gst::PadProbeType::EVENT_DOWNSTREAM,
+            move |_, info| {
+                match info.data {
+                    Some(gst::PadProbeData::Buffer(ref buffer)) => {
+                        k.lock().unwrap().push(("klv", buffer.pts().unwrap()))
+                    }
+                    Some(gst::PadProbeData::Event(ref event)) => {
+                        if let gst::EventView::Gap(gap) = event.view() {
+                            k.lock().unwrap().push(("gap", gap.get().0));
+                        }
+                    }
+                    _ => {}
+                }
+                gst::PadProbeReturn::Ok
+            },
+        );
+        demux.connect_pad_added(move |_, pad| {
+            if pad.name() == "meta_rs" {
+                pad.link(&sinkpad).unwrap();
+            }
+        });
+        let metas: Arc<Mutex<Vec<bool>>> = Arc::default();
+        let m = metas.clone();
+        pipeline
+            .by_name("video")
+            .unwrap()
+            .connect("handoff", false, move |args| {
+                let buffer = args[1].get::<gst::Buffer>().unwrap();
+                m.lock()
+                    .unwrap()
+                    .push(buffer.meta::<ers_meta::ExampleRsMeta>().is_some());
+                None
+            });
+        pipeline.set_state(gst::State::Playing).unwrap();
+        run(&pipeline, gst::MessageType::Eos);
+        pipeline.set_state(gst::State::Null).unwrap();
+
+        // metaのないフレームの時刻にはGAPが送られる
+        let expected = (0..6)
+            .map(|i| {
+                let kind = if i % 2 == 0 { "klv" } else { "gap" };
+                (kind, gst::ClockTime::from_mseconds(i * 100))
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(*klv.lock().unwrap(), expected);
+        assert_eq!(*metas.lock().unwrap(), [true, false].repeat(3));
+    }
+
+    #[test]
+    fn test_strip() {
+        if !has_elements(&["videotestsrc"]) {
+            return;
         }
+        // 抽出したExampleRsMetaだけが削除され、ExampleCMetaは残る
+        let pipeline = gst::parse_launch(
+            "videotestsrc num-buffers=3 ! metatrans op=add mtype=rs ! metatrans op=add mtype=c \
+             ! metademux extract=rs strip=true ! fakesink name=video signal-handoffs=true",
+        )
+        .unwrap()
+        .downcast::<gst::Pipeline>()
+        .unwrap();
+        let metas: Arc<Mutex<Vec<(bool, bool)>>> = Arc::default();
+        let m = metas.clone();
+        pipeline
+            .by_name("video")
+            .unwrap()
+            .connect("handoff", false, move |args| {
+                let buffer = args[1].get::<gst::Buffer>().unwrap();
+                m.lock().unwrap().push((
+                    buffer.meta::<ers_meta::ExampleRsMeta>().is_some(),
+                    buffer.meta::<ec_meta::ExampleCMeta>().is_some(),
+                ));
+                None
+            });
+        pipeline.set_state(gst::State::Playing).unwrap();
+        run(&pipeline, gst::MessageType::Eos);
+        pipeline.set_state(gst::State::Null).unwrap();
+        assert_eq!(*metas.lock().unwrap(), vec![(false, true); 3]);
     }
 }
@@ -7,6 +7,7 @@ const ELEMENT_NAME: &str = "metademux";
 const CLASS_NAME: &str = "MetaDemux";
 
 mod imp;
+mod queue;
 
 gst::glib::wrapper! {
     pub struct MetaDemux(ObjectSubclass<imp::MetaDemux>) @extends gst::Element, gst::Object;
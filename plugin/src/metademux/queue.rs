@@ -0,0 +1,211 @@
+//! metademuxのsrc padごとの出力キュー
+//!
+//! chainのスレッドではキューに積むだけにして、padごとのタスクから下流にpushする
+//! videoとklvを別のスレッドから出力するので、下流のmuxerがどちらかのpadで
+//! 待っていてもmetademuxのchainは止まらない
+use std::collections::VecDeque;
+use std::sync::{Arc, Condvar, Mutex};
+
+use gst::glib;
+use gst::prelude::*;
+
+/// キューの上限。0は無制限
+#[derive(Debug, Clone, Copy)]
+pub struct Limits {
+    pub max_buffers: u32,
+    pub max_time: gst::ClockTime,
+}
+
+enum Item {
+    Buffer(gst::Buffer),
+    Event(gst::Event),
+}
+
+struct Inner {
+    items: VecDeque<Item>,
+    buffers: u32,
+    flushing: bool,
+    // タスクが最後にpushした結果
+    flow: Result<gst::FlowSuccess, gst::FlowError>,
+}
+
+impl Inner {
+    // キューに入っているバッファの時間幅
+    fn time_level(&self) -> gst::ClockTime {
+        let mut ts = self.items.iter().filter_map(|item| match item {
+            Item::Buffer(b) => b.dts_or_pts(),
+            Item::Event(_) => None,
+        });
+        match (ts.next(), ts.last()) {
+            (Some(first), Some(last)) => last.saturating_sub(first),
+            _ => gst::ClockTime::ZERO,
+        }
+    }
+
+    fn is_full(&self, limits: &Limits) -> bool {
+        (limits.max_buffers > 0 && self.buffers >= limits.max_buffers)
+            || (limits.max_time > gst::ClockTime::ZERO && self.time_level() >= limits.max_time)
+    }
+
+    fn clear(&mut self) {
+        self.items.clear();
+        self.buffers = 0;
+    }
+}
+
+pub struct OutputQueue {
+    pad: gst::Pad,
+    limits: Limits,
+    inner: Mutex<Inner>,
+    cond: Condvar,
+}
+
+impl OutputQueue {
+    pub fn new(pad: &gst::Pad, limits: Limits) -> Arc<Self> {
+        Arc::new(Self {
+            pad: pad.clone(),
+            limits,
+            inner: Mutex::new(Inner {
+                items: VecDeque::new(),
+                buffers: 0,
+                flushing: true,
+                flow: Ok(gst::FlowSuccess::Ok),
+            }),
+            cond: Condvar::new(),
+        })
+    }
+
+    pub fn pad(&self) -> &gst::Pad {
+        &self.pad
+    }
+
+    /// キューを空にしてタスクを開始する
+    pub fn start(self: &Arc<Self>) -> Result<(), glib::BoolError> {
+        {
+            let mut inner = self.inner.lock().unwrap();
+            inner.clear();
+            inner.flushing = false;
+            inner.flow = Ok(gst::FlowSuccess::Ok);
+        }
+        // タスクはpadが持つので循環参照にならないようWeakで渡す
+        let this = Arc::downgrade(self);
+        let pad = self.pad.downgrade();
+        self.pad.start_task(move || match this.upgrade() {
+            Some(this) => this.iterate(),
+            None => {
+                if let Some(pad) = pad.upgrade() {
+                    let _ = pad.pause_task();
+                }
+            }
+        })
+    }
+
+    /// 待機中のchainとタスクを起こしてキューを空にする
+    ///
+    /// 下流にFLUSH_STARTを送った後にpauseでタスクを止める
+    pub fn flush_start(&self) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.flushing = true;
+        inner.clear();
+        self.cond.notify_all();
+    }
+
+    pub fn pause(&self) {
+        let _ = self.pad.pause_task();
+    }
+
+    /// タスクを終了する
+    pub fn stop(&self) {
+        self.flush_start();
+        let _ = self.pad.stop_task();
+    }
+
+    /// バッファを積む。上限に達している場合は空くまで待つ
+    ///
+    /// 下流の結果はタスクがpushした時に分かるので、最後の結果を返す
+    pub fn push_buffer(&self, buffer: gst::Buffer) -> Result<gst::FlowSuccess, gst::FlowError> {
+        let mut inner = self.inner.lock().unwrap();
+        loop {
+            if inner.flushing {
+                return Err(gst::FlowError::Flushing);
+            }
+            match inner.flow {
+                // 繋がっていないpadは後から繋がることがあるので積み続ける
+                Ok(_) | Err(gst::FlowError::NotLinked) => (),
+                Err(e) => return Err(e),
+            }
+            if !inner.is_full(&self.limits) {
+                break;
+            }
+            inner = self.cond.wait(inner).unwrap();
+        }
+        inner.items.push_back(Item::Buffer(buffer));
+        inner.buffers += 1;
+        self.cond.notify_all();
+        inner.flow
+    }
+
+    /// serializedなイベントをバッファと同じ順序で送るために積む
+    pub fn push_event(&self, event: gst::Event) -> bool {
+        let mut inner = self.inner.lock().unwrap();
+        if inner.flushing {
+            return false;
+        }
+        inner.items.push_back(Item::Event(event));
+        self.cond.notify_all();
+        true
+    }
+
+    fn iterate(&self) {
+        let item = {
+            let mut inner = self.inner.lock().unwrap();
+            loop {
+                if inner.flushing {
+                    drop(inner);
+                    self.pause();
+                    return;
+                }
+                if let Some(item) = inner.items.pop_front() {
+                    if let Item::Buffer(_) = item {
+                        inner.buffers -= 1;
+                    }
+                    break item;
+                }
+                inner = self.cond.wait(inner).unwrap();
+            }
+        };
+        // 空きができたので待っているchainを起こす
+        self.cond.notify_all();
+
+        let res = match item {
+            Item::Buffer(buffer) => self.pad.push(buffer),
+            Item::Event(event) => {
+                let eos = event.type_() == gst::EventType::Eos;
+                if !self.pad.push_event(event) {
+                    gst::trace!(super::imp::CAT, obj: self.pad, "event was not handled");
+                }
+                if eos {
+                    Err(gst::FlowError::Eos)
+                } else {
+                    Ok(gst::FlowSuccess::Ok)
+                }
+            }
+        };
+
+        let mut inner = self.inner.lock().unwrap();
+        if inner.flushing {
+            // flush中の結果は次のflush-stopで捨てる
+            return;
+        }
+        inner.flow = res;
+        match res {
+            Ok(_) | Err(gst::FlowError::NotLinked) => (),
+            Err(e) => {
+                gst::debug!(super::imp::CAT, obj: self.pad, "pausing task: {:?}", e);
+                self.cond.notify_all();
+                drop(inner);
+                self.pause();
+            }
+        }
+    }
+}
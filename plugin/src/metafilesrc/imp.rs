@@ -0,0 +1,557 @@
+//! ファイルに記録したメタデータをKLVとして再生するsource
+//!
+//! 各レコードはファイルに記録されたptsで出力し、rateで再生速度を変えられる
+//! loopでは最後のレコードの後に同じ間隔を空けて先頭から繰り返す
+//! is-liveでは再生を始めた時刻に先頭のレコードを出力するようにptsを先頭のレコードからの時間にする
+use std::path::PathBuf;
+use std::sync::{Mutex, RwLock};
+
+use gst::glib;
+use gst::prelude::*;
+use gst::subclass::prelude::*;
+use gst_base::prelude::BaseSrcExtManual;
+use gst_base::subclass::base_src::CreateSuccess;
+use gst_base::subclass::prelude::{BaseSrcImpl, PushSrcImpl};
+use gst_base::traits::BaseSrcExt;
+use once_cell::sync::Lazy;
+
+use crate::metaklv::{KlvDatasetType, KLV_CAPS};
+
+use super::record::{self, Format, Record};
+use super::CLASS_NAME;
+use super::ELEMENT_NAME;
+
+static CAT: Lazy<gst::DebugCategory> = Lazy::new(|| {
+    gst::DebugCategory::new(
+        ELEMENT_NAME,
+        gst::DebugColorFlags::empty(),
+        Some(CLASS_NAME),
+    )
+});
+
+const DEFAULT_RATE: f64 = 1.0;
+
+/// 読み込むファイルの形式
+#[derive(Default, Debug, PartialEq, Eq, Clone, Copy, glib::Enum)]
+#[repr(u32)]
+#[enum_type(name = "GstMetaFileSrcFormat")]
+enum FileFormat {
+    #[default]
+    #[enum_value(name = "Auto: csv by extension, otherwise json lines", nick = "auto")]
+    Auto = 0,
+    #[enum_value(name = "JsonLines: one json object per line", nick = "jsonl")]
+    JsonLines = 1,
+    #[enum_value(name = "Csv: comma separated values with header", nick = "csv")]
+    Csv = 2,
+}
+
+#[derive(Debug, Clone)]
+struct Settings {
+    location: Option<PathBuf>,
+    format: FileFormat,
+    dataset: KlvDatasetType,
+    looping: bool,
+    rate: f64,
+    is_live: bool,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            location: None,
+            format: FileFormat::default(),
+            dataset: KlvDatasetType::default(),
+            looping: false,
+            rate: DEFAULT_RATE,
+            is_live: false,
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct State {
+    records: Vec<Record>,
+    // 次に出力するレコード
+    index: usize,
+    // 先頭から繰り返した回数
+    iteration: u64,
+    // 繰り返す時の1周の長さ
+    period: gst::ClockTime,
+    // ptsの基準。liveでは先頭のレコードのpts
+    origin: gst::ClockTime,
+    // 出力したバッファの数
+    offset: u64,
+}
+
+#[derive(Default)]
+struct ClockWait {
+    clock_id: Option<gst::SingleShotClockId>,
+    flushing: bool,
+}
+
+#[derive(Default)]
+pub struct MetaFileSrc {
+    settings: RwLock<Settings>,
+    state: Mutex<State>,
+    clock_wait: Mutex<ClockWait>,
+}
+
+// rateで再生速度を変えた時刻
+fn scale(t: gst::ClockTime, rate: f64) -> gst::ClockTime {
+    gst::ClockTime::from_nseconds((t.nseconds() as f64 / rate).round() as u64)
+}
+
+// 最後のレコードの後にその前と同じ間隔を空けたものを1周とする
+fn period(records: &[Record]) -> gst::ClockTime {
+    match records {
+        [.., prev, last] => last.pts - records[0].pts + (last.pts - prev.pts),
+        _ => gst::ClockTime::ZERO,
+    }
+}
+
+impl MetaFileSrc {
+    fn load(&self, settings: &Settings) -> Result<Vec<Record>, gst::ErrorMessage> {
+        let location = settings
+            .location
+            .as_ref()
+            .ok_or_else(|| gst::error_msg!(gst::ResourceError::NotFound, ["No location set"]))?;
+        let text = std::fs::read_to_string(location).map_err(|e| {
+            gst::error_msg!(
+                gst::ResourceError::OpenRead,
+                ["Failed to read {}: {}", location.display(), e]
+            )
+        })?;
+        let format = match settings.format {
+            FileFormat::Auto => Format::from_path(location),
+            FileFormat::JsonLines => Format::JsonLines,
+            FileFormat::Csv => Format::Csv,
+        };
+        record::parse(&text, format, settings.dataset).map_err(|e| {
+            gst::error_msg!(
+                gst::StreamError::Decode,
+                ["Failed to parse {}: {}", location.display(), e]
+            )
+        })
+    }
+
+    // 実時間でバッファのptsになるまで待つ
+    fn wait(&self, pts: gst::ClockTime) -> Result<(), gst::FlowError> {
+        let (clock, base_time) = match Option::zip(self.obj().clock(), self.obj().base_time()) {
+            None => return Ok(()),
+            Some(res) => res,
+        };
+        let segment = self
+            .obj()
+            .segment()
+            .downcast::<gst::format::Time>()
+            .unwrap();
+        let wait_until = match segment.to_running_time(pts) {
+            Some(running_time) => running_time + base_time,
+            None => return Ok(()),
+        };
+
+        let mut clock_wait = self.clock_wait.lock().unwrap();
+        if clock_wait.flushing {
+            gst::debug!(CAT, imp: self, "clock_wait Flushing");
+            return Err(gst::FlowError::Flushing);
+        }
+        let id = clock.new_single_shot_id(wait_until);
+        clock_wait.clock_id = Some(id.clone());
+        drop(clock_wait);
+        gst::log!(CAT, imp: self, "Waiting until {}", wait_until);
+        let (res, jitter) = id.wait();
+        gst::log!(CAT, imp: self, "Waited res {:?} jitter {}", res, jitter);
+        self.clock_wait.lock().unwrap().clock_id.take();
+        if res == Err(gst::ClockError::Unscheduled) {
+            gst::debug!(CAT, imp: self, "Flushing");
+            return Err(gst::FlowError::Flushing);
+        }
+        Ok(())
+    }
+}
+
+impl ElementImpl for MetaFileSrc {
+    fn metadata() -> Option<&'static gst::subclass::ElementMetadata> {
+        static ELEMENT_METADATA: Lazy<gst::subclass::ElementMetadata> = Lazy::new(|| {
+            gst::subclass::ElementMetadata::new(
+                CLASS_NAME,
+                "Source/File",
+                "Replay metadata records in JSON Lines or CSV file as KLV",
+                "FUJINAKA Fumiya <uzuna.kf@gmail.com>",
+            )
+        });
+
+        Some(&*ELEMENT_METADATA)
+    }
+
+    fn pad_templates() -> &'static [gst::PadTemplate] {
+        static PAD_TEMPLATES: Lazy<Vec<gst::PadTemplate>> = Lazy::new(|| {
+            let src_pad_template = gst::PadTemplate::new(
+                "src",
+                gst::PadDirection::Src,
+                gst::PadPresence::Always,
+                &KLV_CAPS,
+            )
+            .unwrap();
+
+            vec![src_pad_template]
+        });
+
+        PAD_TEMPLATES.as_ref()
+    }
+
+    fn change_state(
+        &self,
+        transition: gst::StateChange,
+    ) -> Result<gst::StateChangeSuccess, gst::StateChangeError> {
+        gst::debug!(CAT, imp: self, "change_state {}", &transition);
+
+        if let gst::StateChange::ReadyToPaused = transition {
+            self.obj().set_live(self.settings.read().unwrap().is_live);
+        }
+
+        self.parent_change_state(transition)
+    }
+}
+
+impl ObjectImpl for MetaFileSrc {
+    fn constructed(&self) {
+        self.parent_constructed();
+        self.obj().set_format(gst::Format::Time);
+    }
+
+    fn properties() -> &'static [glib::ParamSpec] {
+        static PROPERTIES: Lazy<Vec<glib::ParamSpec>> = Lazy::new(|| {
+            vec![
+                glib::ParamSpecString::builder("location")
+                    .nick("Location")
+                    .blurb("path of JSON Lines or CSV file to read")
+                    .mutable_ready()
+                    .build(),
+                glib::ParamSpecEnum::builder::<FileFormat>("format", FileFormat::default())
+                    .nick("Format")
+                    .blurb("file format")
+                    .mutable_ready()
+                    .build(),
+                glib::ParamSpecEnum::builder::<KlvDatasetType>(
+                    "dataset",
+                    KlvDatasetType::default(),
+                )
+                .nick("Dataset")
+                .blurb("klv dataset for records without dataset field")
+                .mutable_ready()
+                .build(),
+                glib::ParamSpecBoolean::builder("loop")
+                    .nick("Loop")
+                    .blurb("repeat records from the beginning")
+                    .mutable_ready()
+                    .build(),
+                glib::ParamSpecDouble::builder("rate")
+                    .nick("Rate")
+                    .blurb("playback speed of recorded pts")
+                    .minimum(0.01)
+                    .maximum(100.0)
+                    .default_value(DEFAULT_RATE)
+                    .mutable_ready()
+                    .build(),
+                glib::ParamSpecBoolean::builder("is-live")
+                    .nick("Is Live")
+                    .blurb("(Pseudo) live output. pts starts from the first record")
+                    .mutable_ready()
+                    .build(),
+            ]
+        });
+
+        PROPERTIES.as_ref()
+    }
+
+    fn set_property(&self, _id: usize, value: &glib::Value, pspec: &glib::ParamSpec) {
+        let mut settings = self.settings.write().unwrap();
+        match pspec.name() {
+            "location" => {
+                let x = value
+                    .get::<Option<String>>()
+                    .expect("type checked upstream");
+                gst::info!(CAT, imp: self, "set prop location to {:?}", x);
+                settings.location = x.map(PathBuf::from);
+            }
+            "format" => {
+                let x = value.get::<FileFormat>().expect("type checked upstream");
+                gst::info!(CAT, imp: self, "set prop format to {:?}", x);
+                settings.format = x;
+            }
+            "dataset" => {
+                let x = value
+                    .get::<KlvDatasetType>()
+                    .expect("type checked upstream");
+                gst::info!(CAT, imp: self, "set prop dataset to {:?}", x);
+                settings.dataset = x;
+            }
+            "loop" => {
+                let x = value.get::<bool>().expect("type checked upstream");
+                gst::info!(CAT, imp: self, "set prop loop to {}", x);
+                settings.looping = x;
+            }
+            "rate" => {
+                let x = value.get::<f64>().expect("type checked upstream");
+                gst::info!(CAT, imp: self, "set prop rate to {}", x);
+                settings.rate = x;
+            }
+            "is-live" => {
+                let x = value.get::<bool>().expect("type checked upstream");
+                gst::info!(CAT, imp: self, "set prop is-live to {}", x);
+                settings.is_live = x;
+            }
+            _ => unimplemented!(),
+        }
+    }
+
+    fn property(&self, _id: usize, pspec: &glib::ParamSpec) -> glib::Value {
+        let settings = self.settings.read().unwrap();
+        match pspec.name() {
+            "location" => settings
+                .location
+                .as_ref()
+                .map(|path| path.to_string_lossy().to_string())
+                .to_value(),
+            "format" => settings.format.to_value(),
+            "dataset" => settings.dataset.to_value(),
+            "loop" => settings.looping.to_value(),
+            "rate" => settings.rate.to_value(),
+            "is-live" => settings.is_live.to_value(),
+            _ => unimplemented!(),
+        }
+    }
+}
+
+impl GstObjectImpl for MetaFileSrc {}
+
+#[glib::object_subclass]
+impl ObjectSubclass for MetaFileSrc {
+    const NAME: &'static str = CLASS_NAME;
+    type Type = super::MetaFileSrc;
+    type ParentType = gst_base::PushSrc;
+}
+
+impl BaseSrcImpl for MetaFileSrc {
+    // ファイルは小さい前提で全てのレコードを先に読む
+    fn start(&self) -> Result<(), gst::ErrorMessage> {
+        let settings = self.settings.read().unwrap().clone();
+        let records = self.load(&settings)?;
+        let period = period(&records);
+        if settings.looping && !records.is_empty() && period == gst::ClockTime::ZERO {
+            return Err(gst::error_msg!(
+                gst::LibraryError::Settings,
+                ["Loop requires at least 2 records with different pts"]
+            ));
+        }
+        gst::debug!(
+            CAT,
+            imp: self,
+            "loaded {} records, period {}",
+            records.len(),
+            period
+        );
+        // 記録した時刻のままだと先頭のレコードまで待ってしまう
+        let origin = match records.first() {
+            Some(first) if settings.is_live => first.pts,
+            _ => gst::ClockTime::ZERO,
+        };
+        *self.state.lock().unwrap() = State {
+            records,
+            period,
+            origin,
+            ..Default::default()
+        };
+        self.clock_wait.lock().unwrap().flushing = false;
+        Ok(())
+    }
+
+    fn stop(&self) -> Result<(), gst::ErrorMessage> {
+        *self.state.lock().unwrap() = State::default();
+        Ok(())
+    }
+
+    fn unlock(&self) -> Result<(), gst::ErrorMessage> {
+        gst::debug!(CAT, imp: self, "unlock");
+        let mut clock_wait = self.clock_wait.lock().unwrap();
+        if let Some(clock_id) = clock_wait.clock_id.take() {
+            clock_id.unschedule();
+        }
+        clock_wait.flushing = true;
+        Ok(())
+    }
+
+    fn unlock_stop(&self) -> Result<(), gst::ErrorMessage> {
+        gst::debug!(CAT, imp: self, "unlock_stop");
+        self.clock_wait.lock().unwrap().flushing = false;
+        Ok(())
+    }
+}
+
+impl PushSrcImpl for MetaFileSrc {
+    fn create(
+        &self,
+        _buffer: Option<&mut gst::BufferRef>,
+    ) -> Result<CreateSuccess, gst::FlowError> {
+        let (rate, looping, is_live) = {
+            let settings = self.settings.read().unwrap();
+            (settings.rate, settings.looping, settings.is_live)
+        };
+        let (pts, next, dataset, offset) = {
+            let mut state = self.state.lock().unwrap();
+            if state.index >= state.records.len() {
+                if !looping || state.records.is_empty() {
+                    gst::debug!(CAT, imp: self, "reached the end of records");
+                    return Err(gst::FlowError::Eos);
+                }
+                state.index = 0;
+                state.iteration += 1;
+            }
+            let base = state.period * state.iteration;
+            let origin = state.origin;
+            let record = &state.records[state.index];
+            let pts = record.pts - origin + base;
+            // 次のレコードまでをdurationにする
+            let next = match state.records.get(state.index + 1) {
+                Some(next) => Some(next.pts - origin + base),
+                None if looping => Some(state.records[0].pts - origin + base + state.period),
+                None => None,
+            };
+            let dataset = record.dataset.clone();
+            let offset = state.offset;
+            state.index += 1;
+            state.offset += 1;
+            (pts, next, dataset, offset)
+        };
+
+        let records = dataset.to_bytes().map_err(|e| {
+            gst::element_imp_error!(
+                self,
+                gst::StreamError::Encode,
+                ["Failed to encode klv: {}", e]
+            );
+            gst::FlowError::Error
+        })?;
+        let mut buffer = gst::Buffer::from_mut_slice(records);
+        let pts = scale(pts, rate);
+        {
+            let buffer = buffer.get_mut().unwrap();
+            buffer.set_pts(pts);
+            buffer.set_duration(next.map(|next| scale(next, rate) - pts));
+            buffer.set_offset(offset);
+        }
+
+        if is_live {
+            self.wait(pts)?;
+        }
+
+        gst::debug!(CAT, imp: self, "Produced buffer {:?}", buffer);
+        Ok(CreateSuccess::NewBuffer(buffer))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use gst::prelude::*;
+
+    use crate::metaklv::KlvDataset;
+    use crate::test_run as run;
+
+    #[test]
+    fn test_loop_and_rate() {
+        crate::test_init();
+        let path = std::env::temp_dir().join(format!("metafilesrc-{}.jsonl", std::process::id()));
+        std::fs::write(
+            &path,
+            r#"{"pts": 0.0, "label": "a", "index": 0}
+{"pts": 0.1, "label": "b", "index": 1}
+{"pts": 0.3, "label": "c", "index": 2}
+"#,
+        )
+        .unwrap();
+        // 1周は0.3秒に最後の間隔0.2秒を足した0.5秒で、rate=2なので半分の時間で再生する
+        let pipeline = gst::parse_launch(&format!(
+            "metafilesrc location={} loop=true rate=2.0 num-buffers=7 \
+             ! fakesink name=sink sync=false signal-handoffs=true",
+            path.display()
+        ))
+        .unwrap()
+        .downcast::<gst::Pipeline>()
+        .unwrap();
+        let received: Arc<Mutex<Vec<(gst::ClockTime, Option<gst::ClockTime>, i32)>>> =
+            Arc::default();
+        let r = received.clone();
+        pipeline
+            .by_name("sink")
+            .unwrap()
+            .connect("handoff", false, move |args| {
+                let buffer = args[1].get::<gst::Buffer>().unwrap();
+                let map = buffer.map_readable().unwrap();
+                let index = KlvDataset::from_bytes(map.as_slice())
+                    .unwrap()
+                    .to_example_rs()
+                    .unwrap()
+                    .index;
+                r.lock()
+                    .unwrap()
+                    .push((buffer.pts().unwrap(), buffer.duration(), index));
+                None
+            });
+        pipeline.set_state(gst::State::Playing).unwrap();
+        run(&pipeline, gst::MessageType::Eos);
+        pipeline.set_state(gst::State::Null).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let ms = gst::ClockTime::from_mseconds;
+        let expected = [(0, 50, 0), (50, 100, 1), (150, 100, 2), (250, 50, 0)]
+            .into_iter()
+            .chain([(300, 100, 1), (400, 100, 2), (500, 50, 0)])
+            .map(|(pts, duration, index)| (ms(pts), Some(ms(duration)), index))
+            .collect::<Vec<_>>();
+        assert_eq!(*received.lock().unwrap(), expected);
+    }
+
+    #[test]
+    fn test_live_origin() {
+        crate::test_init();
+        let path =
+            std::env::temp_dir().join(format!("metafilesrc-live-{}.csv", std::process::id()));
+        // 記録した時刻が大きくても待たずに先頭のレコードから出力する
+        std::fs::write(&path, "pts,label,index\n3600.0,a,0\n3600.5,b,1\n").unwrap();
+        let pipeline = gst::parse_launch(&format!(
+            "metafilesrc location={} is-live=true ! fakesink name=sink signal-handoffs=true",
+            path.display()
+        ))
+        .unwrap()
+        .downcast::<gst::Pipeline>()
+        .unwrap();
+        let received: Arc<Mutex<Vec<gst::ClockTime>>> = Arc::default();
+        let r = received.clone();
+        pipeline
+            .by_name("sink")
+            .unwrap()
+            .connect("handoff", false, move |args| {
+                let buffer = args[1].get::<gst::Buffer>().unwrap();
+                r.lock().unwrap().push(buffer.pts().unwrap());
+                None
+            });
+        pipeline.set_state(gst::State::Playing).unwrap();
+        run(&pipeline, gst::MessageType::Eos);
+        pipeline.set_state(gst::State::Null).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let ms = gst::ClockTime::from_mseconds;
+        assert_eq!(*received.lock().unwrap(), vec![ms(0), ms(500)]);
+    }
+
+    #[test]
+    fn test_missing_location() {
+        crate::test_init();
+        let src = gst::ElementFactory::make("metafilesrc").build().unwrap();
+        assert!(src.set_state(gst::State::Paused).is_err());
+        src.set_state(gst::State::Null).unwrap();
+    }
+}
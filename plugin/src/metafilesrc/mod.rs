@@ -0,0 +1,23 @@
+//! JSON LinesやCSVのレコードをKLVとして再生するエレメント
+
+use gst::glib;
+use gst::prelude::*;
+
+const ELEMENT_NAME: &str = "metafilesrc";
+const CLASS_NAME: &str = "MetaFileSrc";
+
+mod imp;
+mod record;
+
+gst::glib::wrapper! {
+    pub struct MetaFileSrc(ObjectSubclass<imp::MetaFileSrc>) @extends gst_base::PushSrc, gst_base::BaseSrc, gst::Element, gst::Object;
+}
+
+pub fn register(plugin: &gst::Plugin) -> Result<(), glib::BoolError> {
+    gst::Element::register(
+        Some(plugin),
+        ELEMENT_NAME,
+        gst::Rank::None,
+        MetaFileSrc::static_type(),
+    )
+}
@@ -0,0 +1,374 @@
+//! metafilesrcが読むファイルのレコード
+//!
+//! 1レコードは`pts`(秒)と`dataset`(KlvDatasetTypeのnick)と、datasetごとのフィールドからなる
+//!
+//! |dataset|フィールド|
+//! |---|---|
+//! |example|label, index, mode|
+//! |uas-datalink|timestamp(us), mission_id, latitude, longitude, altitude, heading, pitch, roll|
+//! |example-c|label, count, num|
+//! |custom|pts, dataset以外の全てのフィールド|
+//!
+//! JSON Linesは1行に1つのobject、CSVは1行目をフィールド名のヘッダとする
+//! datasetを省略したレコードはエレメントのdatasetプロパティの種類になる
+use std::fmt;
+
+use ers_meta::ExampleRsMetaParams;
+use gst::glib::{self, StaticType};
+use serde_json::{Map, Value};
+
+use crate::metaklv::{
+    CustomDataset, ExampleCDataset, ExampleDataset, KlvDataset, KlvDatasetType, UasDatalinkLS,
+};
+
+const PTS_FIELD: &str = "pts";
+const DATASET_FIELD: &str = "dataset";
+
+/// ファイルの形式
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Format {
+    JsonLines,
+    Csv,
+}
+
+impl Format {
+    /// 拡張子から形式を判別する。csv以外はJSON Linesとして読む
+    pub fn from_path(path: &std::path::Path) -> Self {
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some(ext) if ext.eq_ignore_ascii_case("csv") => Format::Csv,
+            _ => Format::JsonLines,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct RecordError {
+    // 1から始まる行番号
+    line: usize,
+    message: String,
+}
+
+impl RecordError {
+    fn new(line: usize, message: impl Into<String>) -> Self {
+        Self {
+            line,
+            message: message.into(),
+        }
+    }
+}
+
+impl fmt::Display for RecordError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+impl std::error::Error for RecordError {}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct Record {
+    pub pts: gst::ClockTime,
+    pub dataset: KlvDataset,
+}
+
+/// ファイルの内容をレコードにしてpts順に並べる
+pub fn parse(
+    text: &str,
+    format: Format,
+    default: KlvDatasetType,
+) -> Result<Vec<Record>, RecordError> {
+    let mut records = match format {
+        Format::JsonLines => parse_json_lines(text, default)?,
+        Format::Csv => parse_csv(text, default)?,
+    };
+    records.sort_by_key(|record| record.pts);
+    Ok(records)
+}
+
+fn parse_json_lines(text: &str, default: KlvDatasetType) -> Result<Vec<Record>, RecordError> {
+    text.lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty())
+        .map(|(i, line)| {
+            let line_no = i + 1;
+            match serde_json::from_str::<Value>(line) {
+                Ok(Value::Object(fields)) => Fields::new(line_no, fields, false).record(default),
+                Ok(_) => Err(RecordError::new(line_no, "record is not a json object")),
+                Err(e) => Err(RecordError::new(line_no, e.to_string())),
+            }
+        })
+        .collect()
+}
+
+fn parse_csv(text: &str, default: KlvDatasetType) -> Result<Vec<Record>, RecordError> {
+    let mut reader = csv::Reader::from_reader(text.as_bytes());
+    let headers = reader
+        .headers()
+        .map_err(|e| RecordError::new(1, e.to_string()))?
+        .clone();
+    reader
+        .records()
+        .enumerate()
+        .map(|(i, row)| {
+            // ヘッダの分だけずらす
+            let line_no = i + 2;
+            let row = row.map_err(|e| RecordError::new(line_no, e.to_string()))?;
+            // 空のセルはフィールドを省略したものとして扱う
+            let fields = headers
+                .iter()
+                .zip(row.iter())
+                .filter(|(_, v)| !v.is_empty())
+                .map(|(k, v)| (k.trim().to_string(), Value::from(v.trim())))
+                .collect();
+            Fields::new(line_no, fields, true).record(default)
+        })
+        .collect()
+}
+
+// CSVのセルは全て文字列なので、customのフィールドは数値と真偽値を判別する
+fn infer(v: &str) -> Value {
+    if let Ok(x) = v.parse::<i64>() {
+        Value::from(x)
+    } else if let Ok(x) = v.parse::<f64>() {
+        Value::from(x)
+    } else if let Ok(x) = v.parse::<bool>() {
+        Value::from(x)
+    } else {
+        Value::from(v)
+    }
+}
+
+// nickはdatasetプロパティと同じくKlvDatasetTypeの定義から引く
+fn dataset_type(nick: &str) -> Option<KlvDatasetType> {
+    let class = glib::EnumClass::new(KlvDatasetType::static_type())?;
+    let value = class.value_by_nick(nick)?.value();
+    class.to_value(value)?.get::<KlvDatasetType>().ok()
+}
+
+// 1レコード分のフィールド
+//
+// JSONの数値を文字列で書いたものやCSVのセルも受け付けるように型は緩く扱う
+struct Fields {
+    line: usize,
+    fields: Map<String, Value>,
+    // 文字列の値から型を推測する
+    infer: bool,
+}
+
+impl Fields {
+    fn new(line: usize, fields: Map<String, Value>, infer: bool) -> Self {
+        Self {
+            line,
+            fields,
+            infer,
+        }
+    }
+
+    fn error(&self, message: impl Into<String>) -> RecordError {
+        RecordError::new(self.line, message)
+    }
+
+    fn string(&self, key: &str) -> Option<String> {
+        match self.fields.get(key)? {
+            Value::String(s) => Some(s.clone()),
+            Value::Null => None,
+            v => Some(v.to_string()),
+        }
+    }
+
+    fn f64(&self, key: &str) -> Result<Option<f64>, RecordError> {
+        match self.fields.get(key) {
+            None | Some(Value::Null) => Ok(None),
+            Some(Value::Number(n)) => Ok(n.as_f64()),
+            Some(Value::String(s)) => s
+                .parse()
+                .map(Some)
+                .map_err(|_| self.error(format!("{} is not a number: {}", key, s))),
+            Some(v) => Err(self.error(format!("{} is not a number: {}", key, v))),
+        }
+    }
+
+    fn i64(&self, key: &str) -> Result<Option<i64>, RecordError> {
+        match self.fields.get(key) {
+            None | Some(Value::Null) => Ok(None),
+            Some(Value::Number(n)) => n
+                .as_i64()
+                .map(Some)
+                .ok_or_else(|| self.error(format!("{} is not an integer: {}", key, n))),
+            Some(Value::String(s)) => s
+                .parse()
+                .map(Some)
+                .map_err(|_| self.error(format!("{} is not an integer: {}", key, s))),
+            Some(v) => Err(self.error(format!("{} is not an integer: {}", key, v))),
+        }
+    }
+
+    fn record(&self, default: KlvDatasetType) -> Result<Record, RecordError> {
+        let pts = self
+            .f64(PTS_FIELD)?
+            .ok_or_else(|| self.error("missing pts"))?;
+        if !pts.is_finite() || pts < 0.0 {
+            return Err(self.error(format!("invalid pts {}", pts)));
+        }
+        let dataset_type = match self.string(DATASET_FIELD) {
+            Some(nick) => dataset_type(&nick)
+                .ok_or_else(|| self.error(format!("unknown dataset {}", nick)))?,
+            None => default,
+        };
+        Ok(Record {
+            pts: gst::ClockTime::from_nseconds((pts * 1_000_000_000.0).round() as u64),
+            dataset: self.dataset(dataset_type)?,
+        })
+    }
+
+    fn dataset(&self, dataset_type: KlvDatasetType) -> Result<KlvDataset, RecordError> {
+        let label = self.string("label").unwrap_or_default();
+        Ok(match dataset_type {
+            KlvDatasetType::Example => {
+                let params = ExampleRsMetaParams {
+                    label,
+                    index: self.i64("index")?.unwrap_or_default() as i32,
+                    mode: (self.i64("mode")?.unwrap_or_default() as u32).into(),
+                    region: None,
+                };
+                KlvDataset::Example(ExampleDataset::from(&params))
+            }
+            KlvDatasetType::UasDatalink => {
+                let mut ds = UasDatalinkLS::new(self.i64("timestamp")?.unwrap_or_default() as u64);
+                ds.mission_id = self.string("mission_id");
+                let setters: [(&str, fn(&mut UasDatalinkLS, f64)); 6] = [
+                    ("latitude", UasDatalinkLS::set_latitude),
+                    ("longitude", UasDatalinkLS::set_longitude),
+                    ("altitude", UasDatalinkLS::set_altitude),
+                    ("heading", UasDatalinkLS::set_heading),
+                    ("pitch", UasDatalinkLS::set_pitch),
+                    ("roll", UasDatalinkLS::set_roll),
+                ];
+                for (key, set) in setters {
+                    if let Some(v) = self.f64(key)? {
+                        set(&mut ds, v);
+                    }
+                }
+                KlvDataset::UasDatalink(ds)
+            }
+            KlvDatasetType::ExampleC => KlvDataset::ExampleC(ExampleCDataset::new(
+                label,
+                self.i64("count")?.unwrap_or_default(),
+                self.f64("num")?.unwrap_or_default() as f32,
+            )),
+            KlvDatasetType::Custom => {
+                KlvDataset::Custom(CustomDataset::from(self.structure().as_ref()))
+            }
+        })
+    }
+
+    // pts, dataset以外のフィールドをGstStructureにする
+    fn structure(&self) -> gst::Structure {
+        let mut s = gst::Structure::new_empty("fields");
+        for (key, value) in self
+            .fields
+            .iter()
+            .filter(|(key, _)| *key != PTS_FIELD && *key != DATASET_FIELD)
+        {
+            let value = match value {
+                Value::String(v) if self.infer => infer(v),
+                v => v.clone(),
+            };
+            match value {
+                Value::Bool(b) => s.set(key, b),
+                Value::Number(n) => match n.as_i64() {
+                    Some(i) => s.set(key, i),
+                    None => s.set(key, n.as_f64().unwrap_or_default()),
+                },
+                Value::String(v) => s.set(key, v.as_str()),
+                Value::Null => (),
+                // 配列やobjectはJSONの文字列のまま入れる
+                v => s.set(key, v.to_string()),
+            }
+        }
+        s
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ms(v: u64) -> gst::ClockTime {
+        gst::ClockTime::from_mseconds(v)
+    }
+
+    #[test]
+    fn test_parse_json_lines() {
+        gst::init().unwrap();
+        let text = r#"{"pts": 0.2, "dataset": "example-c", "label": "sensor", "count": 2, "num": 0.5}
+{"pts": 0.1, "label": "cam0", "index": 1}
+
+{"pts": 0.3, "dataset": "uas-datalink", "mission_id": "M1", "latitude": 35.5, "altitude": 120.0}
+{"pts": 0.4, "dataset": "custom", "label": "x", "speed": 12.5, "valid": true}
+"#;
+        let records = parse(text, Format::JsonLines, KlvDatasetType::Example).unwrap();
+        assert_eq!(
+            records.iter().map(|r| r.pts).collect::<Vec<_>>(),
+            vec![ms(100), ms(200), ms(300), ms(400)]
+        );
+        let params = records[0].dataset.to_example_rs().unwrap();
+        assert_eq!((params.label.as_str(), params.index), ("cam0", 1));
+        assert_eq!(
+            records[1].dataset,
+            KlvDataset::ExampleC(ExampleCDataset::new("sensor".to_string(), 2, 0.5))
+        );
+        match &records[2].dataset {
+            KlvDataset::UasDatalink(ds) => {
+                assert_eq!(ds.mission_id.as_deref(), Some("M1"));
+                assert!((ds.latitude().unwrap() - 35.5).abs() < 1e-6);
+                assert!(ds.longitude().is_none());
+            }
+            ds => panic!("unexpected dataset {:?}", ds),
+        }
+        match &records[3].dataset {
+            KlvDataset::Custom(ds) => {
+                let s = ds.structure().unwrap();
+                assert_eq!(s.get::<&str>("label").unwrap(), "x");
+                assert_eq!(s.get::<f64>("speed").unwrap(), 12.5);
+                assert!(s.get::<bool>("valid").unwrap());
+                assert!(!s.has_field("pts"));
+            }
+            ds => panic!("unexpected dataset {:?}", ds),
+        }
+    }
+
+    #[test]
+    fn test_parse_csv() {
+        // labelは数値のように見えても文字列のまま
+        let text = "pts,dataset,label,count,num\n0.0,example-c,001,1,1.5\n0.5,,cam,2,\n";
+        let records = parse(text, Format::Csv, KlvDatasetType::ExampleC).unwrap();
+        assert_eq!(
+            records,
+            vec![
+                Record {
+                    pts: ms(0),
+                    dataset: KlvDataset::ExampleC(ExampleCDataset::new("001".to_string(), 1, 1.5)),
+                },
+                Record {
+                    pts: ms(500),
+                    dataset: KlvDataset::ExampleC(ExampleCDataset::new("cam".to_string(), 2, 0.0)),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_error() {
+        let text = "{\"pts\": 0.1}\n{\"label\": \"no pts\"}\n";
+        assert_eq!(
+            parse(text, Format::JsonLines, KlvDatasetType::Example),
+            Err(RecordError::new(2, "missing pts"))
+        );
+        let text = "pts,dataset\n0.1,unknown\n";
+        assert_eq!(
+            parse(text, Format::Csv, KlvDatasetType::Example),
+            Err(RecordError::new(2, "unknown dataset unknown"))
+        );
+    }
+}
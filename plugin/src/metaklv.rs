@@ -1,10 +1,35 @@
 //! Ecample metaklv impl
+use std::fmt;
+use std::str::FromStr;
+
+use ec_meta::{ExampleCMeta, ExampleCMetaParams};
 use ers_meta::{ExampleRsMeta, ExampleRsMetaParams};
-use gst::Caps;
+use gst::{glib, Caps};
 use once_cell::sync::Lazy;
 use serde::{Deserialize, Serialize};
 
-#[derive(Debug, Serialize, Deserialize, PartialEq, Eq)]
+/// ExampleDatasetのUniversal Key
+pub const EXAMPLE_DATASET_KEY: &[u8; 16] = b"gstexamplers0000";
+/// MISB ST 0601 UAS Datalink Local SetのUniversal Key
+pub const UAS_DATALINK_LS_KEY: &[u8; 16] = &[
+    0x06, 0x0e, 0x2b, 0x34, 0x02, 0x0b, 0x01, 0x01, 0x0e, 0x01, 0x03, 0x01, 0x01, 0x00, 0x00, 0x00,
+];
+/// ExampleCDatasetのUniversal Key
+pub const EXAMPLE_C_DATASET_KEY: &[u8; 16] = b"gstexamplec00000";
+/// CustomDatasetのUniversal Key
+pub const CUSTOM_DATASET_KEY: &[u8; 16] = b"gstexamplecm0000";
+/// 対応しているST 0601のバージョン(Tag 65)
+pub const UAS_DATALINK_LS_VERSION: u8 = 17;
+
+// checksum(Tag 1)はパケット末尾に置かれ tag + length + value(2byte) の4byteとなる
+const CHECKSUM_TAG: u8 = 1;
+const CHECKSUM_LEN: u8 = 2;
+
+/// ExampleRsMetaを運ぶDataset
+///
+/// 飛行の状態は省略可能で、含まない場合は従来と同じパケットになる
+/// 値は固定小数点の整数で保持し、FlightTelemetryとの変換で物理量にする
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Clone, Default)]
 #[serde(rename = "gstexamplers0000")]
 pub struct ExampleDataset {
     #[serde(rename = "2")]
@@ -13,6 +38,78 @@ pub struct ExampleDataset {
     mode: u32,
     #[serde(rename = "16")]
     label: String,
+    /// Precision Time Stamp: UNIX epochからのマイクロ秒
+    #[serde(rename = "4", skip_serializing_if = "Option::is_none")]
+    timestamp: Option<u64>,
+    /// 1e-7 [deg]
+    #[serde(rename = "5", skip_serializing_if = "Option::is_none")]
+    latitude: Option<i32>,
+    /// 1e-7 [deg]
+    #[serde(rename = "6", skip_serializing_if = "Option::is_none")]
+    longitude: Option<i32>,
+    /// [mm]
+    #[serde(rename = "7", skip_serializing_if = "Option::is_none")]
+    altitude: Option<i32>,
+    /// 1e-6 [deg]
+    #[serde(rename = "8", skip_serializing_if = "Option::is_none")]
+    heading: Option<u32>,
+    /// [mm/s]
+    #[serde(rename = "9", skip_serializing_if = "Option::is_none")]
+    speed: Option<u32>,
+    /// 機首方向からの相対角 1e-6 [deg]
+    #[serde(rename = "10", skip_serializing_if = "Option::is_none")]
+    sensor_azimuth: Option<u32>,
+    /// 水平からの角度 1e-6 [deg]
+    #[serde(rename = "11", skip_serializing_if = "Option::is_none")]
+    sensor_elevation: Option<i32>,
+}
+
+/// ExampleDatasetに載せる飛行の状態
+#[derive(Debug, PartialEq, Clone, Copy, Default)]
+pub struct FlightTelemetry {
+    /// UNIX epochからのマイクロ秒
+    pub timestamp: u64,
+    /// [deg]
+    pub latitude: f64,
+    /// [deg]
+    pub longitude: f64,
+    /// [m]
+    pub altitude: f64,
+    /// 0..360 [deg]
+    pub heading: f64,
+    /// [m/s]
+    pub speed: f64,
+    /// 0..360 [deg]
+    pub sensor_azimuth: f64,
+    /// -90..90 [deg]
+    pub sensor_elevation: f64,
+}
+
+impl ExampleDataset {
+    pub fn set_flight(&mut self, t: &FlightTelemetry) {
+        self.timestamp = Some(t.timestamp);
+        self.latitude = Some((t.latitude.clamp(-90.0, 90.0) * 1e7).round() as i32);
+        self.longitude = Some((t.longitude.clamp(-180.0, 180.0) * 1e7).round() as i32);
+        self.altitude = Some((t.altitude * 1e3).round() as i32);
+        self.heading = Some((t.heading.rem_euclid(360.0) * 1e6).round() as u32);
+        self.speed = Some((t.speed.max(0.0) * 1e3).round() as u32);
+        self.sensor_azimuth = Some((t.sensor_azimuth.rem_euclid(360.0) * 1e6).round() as u32);
+        self.sensor_elevation = Some((t.sensor_elevation.clamp(-90.0, 90.0) * 1e6).round() as i32);
+    }
+
+    /// 飛行の状態。一部でも欠けていればNone
+    pub fn flight(&self) -> Option<FlightTelemetry> {
+        Some(FlightTelemetry {
+            timestamp: self.timestamp?,
+            latitude: self.latitude? as f64 / 1e7,
+            longitude: self.longitude? as f64 / 1e7,
+            altitude: self.altitude? as f64 / 1e3,
+            heading: self.heading? as f64 / 1e6,
+            speed: self.speed? as f64 / 1e3,
+            sensor_azimuth: self.sensor_azimuth? as f64 / 1e6,
+            sensor_elevation: self.sensor_elevation? as f64 / 1e6,
+        })
+    }
 }
 
 impl From<&ExampleRsMeta> for ExampleDataset {
@@ -21,6 +118,7 @@ impl From<&ExampleRsMeta> for ExampleDataset {
             index: meta.index(),
             mode: meta.mode() as u32,
             label: meta.label().to_string(),
+            ..Default::default()
         }
     }
 }
@@ -31,6 +129,7 @@ impl From<&ExampleRsMetaParams> for ExampleDataset {
             index: params.index,
             mode: params.mode as u32,
             label: params.label.to_string(),
+            ..Default::default()
         }
     }
 }
@@ -42,12 +141,626 @@ impl Into<ExampleRsMetaParams> for ExampleDataset {
             index: self.index,
             mode: self.mode.into(),
             label: self.label,
+            region: None,
+        }
+    }
+}
+
+/// ExampleCMetaを運ぶDataset
+///
+/// numのf32はビット列のままu32として運ぶ
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Clone)]
+#[serde(rename = "gstexamplec00000")]
+pub struct ExampleCDataset {
+    #[serde(rename = "2")]
+    count: i64,
+    #[serde(rename = "3")]
+    num: u32,
+    #[serde(rename = "16")]
+    label: String,
+}
+
+impl ExampleCDataset {
+    pub fn new(label: String, count: i64, num: f32) -> Self {
+        Self {
+            count,
+            num: num.to_bits(),
+            label,
+        }
+    }
+
+    pub fn label(&self) -> &str {
+        &self.label
+    }
+
+    pub fn count(&self) -> i64 {
+        self.count
+    }
+
+    pub fn num(&self) -> f32 {
+        f32::from_bits(self.num)
+    }
+}
+
+impl From<&ExampleCMeta> for ExampleCDataset {
+    fn from(meta: &ExampleCMeta) -> Self {
+        Self::new(
+            meta.label().unwrap_or_default().to_string(),
+            meta.count(),
+            meta.num(),
+        )
+    }
+}
+
+#[allow(clippy::from_over_into)]
+impl Into<ExampleCMetaParams> for ExampleCDataset {
+    fn into(self) -> ExampleCMetaParams {
+        let num = self.num();
+        ExampleCMetaParams::new(self.label, self.count, num)
+    }
+}
+
+/// GstCustomMetaのGstStructureを文字列で運ぶDataset
+///
+/// フィールドは任意なのでKLVのタグには分解せずにGstStructureの文字列表現をそのまま入れる
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Clone)]
+#[serde(rename = "gstexamplecm0000")]
+pub struct CustomDataset {
+    #[serde(rename = "1")]
+    fields: String,
+}
+
+impl From<&gst::StructureRef> for CustomDataset {
+    fn from(s: &gst::StructureRef) -> Self {
+        Self {
+            fields: s.to_string(),
+        }
+    }
+}
+
+impl CustomDataset {
+    /// 運んでいるGstStructureを復元する
+    pub fn structure(&self) -> Result<gst::Structure, glib::BoolError> {
+        gst::Structure::from_str(&self.fields)
+    }
+}
+
+/// MISB ST 0601 UAS Datalink Local Set
+///
+/// 必須のPrecision Time Stamp(Tag 2)とChecksum(Tag 1)に加えて
+/// よく使われる位置、姿勢のタグを扱う。値はST 0601の整数表現のまま保持し
+/// 物理量への変換はアクセサで行う
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Clone, Default)]
+#[serde(rename = "\x06\x0e\x2b\x34\x02\x0b\x01\x01\x0e\x01\x03\x01\x01\x00\x00\x00")]
+pub struct UasDatalinkLS {
+    /// Precision Time Stamp: UNIX epochからのマイクロ秒
+    #[serde(rename = "2")]
+    pub timestamp: u64,
+    #[serde(rename = "3", skip_serializing_if = "Option::is_none")]
+    pub mission_id: Option<String>,
+    #[serde(rename = "5", skip_serializing_if = "Option::is_none")]
+    pub platform_heading: Option<u16>,
+    #[serde(rename = "6", skip_serializing_if = "Option::is_none")]
+    pub platform_pitch: Option<i16>,
+    #[serde(rename = "7", skip_serializing_if = "Option::is_none")]
+    pub platform_roll: Option<i16>,
+    #[serde(rename = "13", skip_serializing_if = "Option::is_none")]
+    pub sensor_latitude: Option<i32>,
+    #[serde(rename = "14", skip_serializing_if = "Option::is_none")]
+    pub sensor_longitude: Option<i32>,
+    #[serde(rename = "15", skip_serializing_if = "Option::is_none")]
+    pub sensor_true_altitude: Option<u16>,
+    #[serde(rename = "65", skip_serializing_if = "Option::is_none")]
+    pub version: Option<u8>,
+    /// 末尾に置く必要があるのでフィールドの最後に定義する
+    #[serde(rename = "1")]
+    checksum: u16,
+}
+
+impl UasDatalinkLS {
+    pub fn new(timestamp: u64) -> Self {
+        Self {
+            timestamp,
+            version: Some(UAS_DATALINK_LS_VERSION),
+            ..Default::default()
+        }
+    }
+
+    /// 現在時刻をPrecision Time Stampとして生成する
+    pub fn now() -> Self {
+        let timestamp = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|d| d.as_micros() as u64)
+            .unwrap_or_default();
+        Self::new(timestamp)
+    }
+
+    /// decodeしたパケットのchecksum
+    pub fn checksum(&self) -> u16 {
+        self.checksum
+    }
+
+    /// Tag 5: 0..360 [deg]
+    pub fn heading(&self) -> Option<f64> {
+        self.platform_heading
+            .map(|v| v as f64 * 360.0 / u16::MAX as f64)
+    }
+
+    pub fn set_heading(&mut self, deg: f64) {
+        let deg = deg.rem_euclid(360.0);
+        self.platform_heading = Some((deg / 360.0 * u16::MAX as f64).round() as u16);
+    }
+
+    /// Tag 6: -20..20 [deg]
+    pub fn pitch(&self) -> Option<f64> {
+        self.platform_pitch
+            .and_then(|v| map_signed_from_i16(v, 20.0))
+    }
+
+    pub fn set_pitch(&mut self, deg: f64) {
+        self.platform_pitch = Some(map_signed_to_i16(deg, 20.0));
+    }
+
+    /// Tag 7: -50..50 [deg]
+    pub fn roll(&self) -> Option<f64> {
+        self.platform_roll
+            .and_then(|v| map_signed_from_i16(v, 50.0))
+    }
+
+    pub fn set_roll(&mut self, deg: f64) {
+        self.platform_roll = Some(map_signed_to_i16(deg, 50.0));
+    }
+
+    /// Tag 13: -90..90 [deg]
+    pub fn latitude(&self) -> Option<f64> {
+        self.sensor_latitude
+            .and_then(|v| map_signed_from_i32(v, 90.0))
+    }
+
+    pub fn set_latitude(&mut self, deg: f64) {
+        self.sensor_latitude = Some(map_signed_to_i32(deg, 90.0));
+    }
+
+    /// Tag 14: -180..180 [deg]
+    pub fn longitude(&self) -> Option<f64> {
+        self.sensor_longitude
+            .and_then(|v| map_signed_from_i32(v, 180.0))
+    }
+
+    pub fn set_longitude(&mut self, deg: f64) {
+        self.sensor_longitude = Some(map_signed_to_i32(deg, 180.0));
+    }
+
+    /// Tag 15: -900..19000 [m]
+    pub fn altitude(&self) -> Option<f64> {
+        self.sensor_true_altitude
+            .map(|v| v as f64 * 19900.0 / u16::MAX as f64 - 900.0)
+    }
+
+    pub fn set_altitude(&mut self, meter: f64) {
+        let v = (meter.clamp(-900.0, 19000.0) + 900.0) / 19900.0 * u16::MAX as f64;
+        self.sensor_true_altitude = Some(v.round() as u16);
+    }
+
+    /// checksumを計算してKLVパケットにする
+    pub fn to_bytes(&self) -> Result<Vec<u8>, KlvError> {
+        let mut records = serde_klv::to_bytes(self).map_err(|e| KlvError::Codec(e.to_string()))?;
+        let len = records.len();
+        if len < 4 || records[len - 4] != CHECKSUM_TAG || records[len - 3] != CHECKSUM_LEN {
+            return Err(KlvError::Codec("checksum is not the last item".to_string()));
+        }
+        let checksum = bcc16(&records[..len - 2]);
+        records[len - 2..].copy_from_slice(&checksum.to_be_bytes());
+        Ok(records)
+    }
+
+    /// checksumを検証してからdecodeする
+    pub fn from_bytes(buf: &[u8]) -> Result<Self, KlvError> {
+        let len = buf.len();
+        if len < UAS_DATALINK_LS_KEY.len() + 4 {
+            return Err(KlvError::TooShort(len));
+        }
+        if buf[len - 4] != CHECKSUM_TAG || buf[len - 3] != CHECKSUM_LEN {
+            return Err(KlvError::Codec("checksum is not the last item".to_string()));
+        }
+        let expected = u16::from_be_bytes([buf[len - 2], buf[len - 1]]);
+        let actual = bcc16(&buf[..len - 2]);
+        if expected != actual {
+            return Err(KlvError::Checksum { expected, actual });
+        }
+        serde_klv::from_bytes(buf).map_err(|e| KlvError::Codec(e.to_string()))
+    }
+}
+
+impl UasDatalinkLS {
+    /// ExampleRsMetaのlabelをMission IDとして持つパケットを生成する
+    ///
+    /// ExampleRsMetaは時刻を持たないので、Precision Time Stampはバッファの時刻などから
+    /// 呼び出し側で決める。index, mode, regionに対応するタグはないので失われる
+    pub fn from_example_rs(meta: &ExampleRsMeta, timestamp: u64) -> Self {
+        let mut ds = Self::new(timestamp);
+        ds.mission_id = Some(meta.label().to_string());
+        ds
+    }
+}
+
+/// Mission IDをlabelとするExampleRsMetaParamsに変換する
+///
+/// ExampleRsMetaで表現できるのはMission IDのみなので、
+/// Precision Time Stamp、姿勢、位置、高度は失われる。
+/// 対応する値がないindexは0、modeはCopy、regionはNoneになる
+#[allow(clippy::from_over_into)]
+impl Into<ExampleRsMetaParams> for UasDatalinkLS {
+    fn into(self) -> ExampleRsMetaParams {
+        ExampleRsMetaParams {
+            label: self.mission_id.unwrap_or_default(),
+            index: 0,
+            mode: ers_meta::TransformMode::Copy,
+            region: None,
+        }
+    }
+}
+
+/// エレメントのプロパティで生成するKLVの種類を選択する
+#[derive(Default, Debug, PartialEq, Eq, Clone, Copy, glib::Enum)]
+#[repr(u32)]
+#[enum_type(name = "GstExampleKlvDatasetType")]
+pub enum KlvDatasetType {
+    #[default]
+    #[enum_value(name = "Example: ExampleDataset", nick = "example")]
+    Example = 0,
+    #[enum_value(
+        name = "UasDatalink: MISB ST 0601 UAS Datalink LS",
+        nick = "uas-datalink"
+    )]
+    UasDatalink = 1,
+    #[enum_value(name = "Custom: GstStructure of GstCustomMeta", nick = "custom")]
+    Custom = 2,
+    #[enum_value(name = "ExampleC: ExampleCDataset", nick = "example-c")]
+    ExampleC = 3,
+}
+
+/// 壊れたKLVパケットを扱う時のエレメントの動作
+#[derive(Default, Debug, PartialEq, Eq, Clone, Copy, glib::Enum)]
+#[repr(u32)]
+#[enum_type(name = "GstExampleKlvErrorPolicy")]
+pub enum KlvErrorPolicy {
+    #[enum_value(name = "Drop: drop the video frame", nick = "drop")]
+    Drop = 0,
+    #[default]
+    #[enum_value(
+        name = "Pass: pass the video frame without the malformed metadata",
+        nick = "pass"
+    )]
+    Pass = 1,
+    #[enum_value(name = "Error: post error message and stop streaming", nick = "error")]
+    Error = 2,
+}
+
+/// 壊れたパケットをエレメントのwarningメッセージに添付するためのStructure
+pub fn malformed_details(data: &[u8]) -> gst::Structure {
+    gst::Structure::builder("malformed-klv")
+        .field("data", glib::Bytes::from(data))
+        .build()
+}
+
+/// KLVに変換できなかったdatasetをエレメントのwarningメッセージに添付するためのStructure
+///
+/// 変換に失敗したのでKLVのバイト列はなく、datasetのDebug表現を文字列で添付する
+pub fn unencodable_details(dataset: &KlvDataset) -> gst::Structure {
+    gst::Structure::builder("unencodable-klv")
+        .field("dataset", format!("{:?}", dataset))
+        .build()
+}
+
+/// meta/x-klvに流れるパケットの種類
+///
+/// Universal Keyで判別してdecodeする
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum KlvDataset {
+    Example(ExampleDataset),
+    UasDatalink(UasDatalinkLS),
+    Custom(CustomDataset),
+    ExampleC(ExampleCDataset),
+}
+
+impl KlvDataset {
+    pub fn from_bytes(buf: &[u8]) -> Result<Self, KlvError> {
+        if buf.len() < 16 {
+            return Err(KlvError::TooShort(buf.len()));
+        }
+        match &buf[..16] {
+            key if key == EXAMPLE_DATASET_KEY => serde_klv::from_bytes(buf)
+                .map(Self::Example)
+                .map_err(|e| KlvError::Codec(e.to_string())),
+            key if key == UAS_DATALINK_LS_KEY => {
+                UasDatalinkLS::from_bytes(buf).map(Self::UasDatalink)
+            }
+            key if key == CUSTOM_DATASET_KEY => serde_klv::from_bytes(buf)
+                .map(Self::Custom)
+                .map_err(|e| KlvError::Codec(e.to_string())),
+            key if key == EXAMPLE_C_DATASET_KEY => serde_klv::from_bytes(buf)
+                .map(Self::ExampleC)
+                .map_err(|e| KlvError::Codec(e.to_string())),
+            key => Err(KlvError::UnknownKey(key.to_vec())),
+        }
+    }
+
+    pub fn to_bytes(&self) -> Result<Vec<u8>, KlvError> {
+        match self {
+            Self::Example(ds) => {
+                serde_klv::to_bytes(ds).map_err(|e| KlvError::Codec(e.to_string()))
+            }
+            Self::UasDatalink(ds) => ds.to_bytes(),
+            Self::Custom(ds) => serde_klv::to_bytes(ds).map_err(|e| KlvError::Codec(e.to_string())),
+            Self::ExampleC(ds) => {
+                serde_klv::to_bytes(ds).map_err(|e| KlvError::Codec(e.to_string()))
+            }
+        }
+    }
+
+    /// ExampleRsMetaとして付与する値に変換する
+    ///
+    /// Customは対応するフィールドが決まっていないので変換できない
+    pub fn to_example_rs(&self) -> Option<ExampleRsMetaParams> {
+        match self {
+            Self::Example(ds) => Some(ds.clone().into()),
+            Self::UasDatalink(ds) => Some(ds.clone().into()),
+            Self::ExampleC(ds) => Some(ExampleRsMetaParams::new(
+                ds.label().to_string(),
+                ds.count() as i32,
+                ers_meta::TransformMode::Copy,
+            )),
+            Self::Custom(_) => None,
+        }
+    }
+
+    /// ExampleCMetaとして付与する値に変換する
+    ///
+    /// ExampleC以外はExampleRsMetaのlabelとindexを引き継ぐ
+    pub fn to_example_c(&self) -> Option<ExampleCMetaParams> {
+        match self {
+            Self::ExampleC(ds) => Some(ds.clone().into()),
+            ds => ds
+                .to_example_rs()
+                .map(|params| ExampleCMetaParams::new(params.label, params.index as i64, 0.0)),
         }
     }
 }
 
+#[derive(Debug, PartialEq, Eq)]
+pub enum KlvError {
+    TooShort(usize),
+    UnknownKey(Vec<u8>),
+    Checksum { expected: u16, actual: u16 },
+    Codec(String),
+}
+
+impl fmt::Display for KlvError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::TooShort(len) => write!(f, "packet too short: {} bytes", len),
+            Self::UnknownKey(key) => write!(f, "unknown universal key: {:02x?}", key),
+            Self::Checksum { expected, actual } => write!(
+                f,
+                "checksum mismatch: expected {:#06x}, actual {:#06x}",
+                expected, actual
+            ),
+            Self::Codec(msg) => write!(f, "codec error: {}", msg),
+        }
+    }
+}
+
+impl std::error::Error for KlvError {}
+
+/// ST 0601 8.2 Checksumの16bit block character check
+fn bcc16(buf: &[u8]) -> u16 {
+    buf.iter().enumerate().fold(0u16, |bcc, (i, b)| {
+        bcc.wrapping_add((*b as u16) << (8 * ((i + 1) % 2)))
+    })
+}
+
+// ST 0601では符号付きの最小値はエラー表現として予約されている
+fn map_signed_from_i16(v: i16, range: f64) -> Option<f64> {
+    if v == i16::MIN {
+        None
+    } else {
+        Some(v as f64 * range / i16::MAX as f64)
+    }
+}
+
+fn map_signed_to_i16(v: f64, range: f64) -> i16 {
+    (v.clamp(-range, range) / range * i16::MAX as f64).round() as i16
+}
+
+fn map_signed_from_i32(v: i32, range: f64) -> Option<f64> {
+    if v == i32::MIN {
+        None
+    } else {
+        Some(v as f64 * range / i32::MAX as f64)
+    }
+}
+
+fn map_signed_to_i32(v: f64, range: f64) -> i32 {
+    (v.clamp(-range, range) / range * i32::MAX as f64).round() as i32
+}
+
 pub static KLV_CAPS: Lazy<Caps> = Lazy::new(|| {
     gst::Caps::builder("meta/x-klv")
         .field("parsed", true)
         .build()
 });
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bcc16() {
+        // 偶数番目のbyteは上位、奇数番目のbyteは下位に加算される
+        assert_eq!(bcc16(&[0x06, 0x0e]), 0x060e);
+        assert_eq!(bcc16(&[0x06, 0x0e, 0x2b]), 0x310e);
+    }
+
+    #[test]
+    fn test_uas_datalink_roundtrip() {
+        let mut ds = UasDatalinkLS::new(1_231_798_102_000_000);
+        ds.mission_id = Some("MISSION01".to_string());
+        ds.set_heading(159.97);
+        ds.set_pitch(-0.43);
+        ds.set_roll(3.41);
+        ds.set_latitude(60.176822966978335);
+        ds.set_longitude(128.42675904204452);
+        ds.set_altitude(14190.72);
+
+        let records = ds.to_bytes().unwrap();
+        assert_eq!(&records[..16], UAS_DATALINK_LS_KEY);
+
+        let decoded = UasDatalinkLS::from_bytes(&records).unwrap();
+        assert_eq!(decoded.timestamp, ds.timestamp);
+        assert_eq!(decoded.mission_id, ds.mission_id);
+        assert_eq!(decoded.platform_heading, ds.platform_heading);
+        assert!((decoded.latitude().unwrap() - 60.176822966978335).abs() < 1e-6);
+        assert!((decoded.longitude().unwrap() - 128.42675904204452).abs() < 1e-6);
+        assert!((decoded.altitude().unwrap() - 14190.72).abs() < 0.5);
+
+        assert_eq!(
+            KlvDataset::from_bytes(&records).unwrap(),
+            KlvDataset::UasDatalink(decoded)
+        );
+    }
+
+    #[test]
+    fn test_custom_dataset_roundtrip() {
+        gst::init().unwrap();
+        let s =
+            gst::Structure::from_str("fields, label=(string)cam0, altitude=(double)100.5").unwrap();
+        let records = KlvDataset::Custom(CustomDataset::from(s.as_ref()))
+            .to_bytes()
+            .unwrap();
+        assert_eq!(&records[..16], CUSTOM_DATASET_KEY);
+
+        let decoded = match KlvDataset::from_bytes(&records).unwrap() {
+            KlvDataset::Custom(ds) => ds.structure().unwrap(),
+            ds => panic!("unexpected dataset {:?}", ds),
+        };
+        assert_eq!(decoded, s);
+    }
+
+    #[test]
+    fn test_example_c_dataset_roundtrip() {
+        let ds = ExampleCDataset::new("cam0".to_string(), -3, 1.5);
+        let records = KlvDataset::ExampleC(ds.clone()).to_bytes().unwrap();
+        assert_eq!(&records[..16], EXAMPLE_C_DATASET_KEY);
+
+        let decoded = KlvDataset::from_bytes(&records).unwrap();
+        assert_eq!(decoded, KlvDataset::ExampleC(ds));
+        match decoded {
+            KlvDataset::ExampleC(ds) => {
+                let params: ExampleCMetaParams = ds.into();
+                assert_eq!(params, ExampleCMetaParams::new("cam0".to_string(), -3, 1.5));
+            }
+            ds => panic!("unexpected dataset {:?}", ds),
+        }
+    }
+
+    #[test]
+    fn test_convert_meta_params() {
+        let ds = KlvDataset::ExampleC(ExampleCDataset::new("sensor".to_string(), 7, 1.5));
+        let params = ds.to_example_rs().unwrap();
+        assert_eq!((params.label.as_str(), params.index), ("sensor", 7));
+        assert_eq!(
+            ds.to_example_c(),
+            Some(ExampleCMetaParams::new("sensor".to_string(), 7, 1.5))
+        );
+
+        let mut uas = UasDatalinkLS::new(0);
+        uas.mission_id = Some("platform".to_string());
+        assert_eq!(
+            KlvDataset::UasDatalink(uas).to_example_c(),
+            Some(ExampleCMetaParams::new("platform".to_string(), 0, 0.0))
+        );
+
+        gst::init().unwrap();
+        let s = gst::Structure::builder("fields")
+            .field("label", "x")
+            .build();
+        let custom = KlvDataset::Custom(CustomDataset::from(s.as_ref()));
+        assert!(custom.to_example_rs().is_none());
+        assert!(custom.to_example_c().is_none());
+    }
+
+    #[test]
+    fn test_example_dataset_flight() {
+        let params = ExampleRsMetaParams::new("uav".to_string(), 3, ers_meta::TransformMode::Copy);
+        let plain = KlvDataset::Example(ExampleDataset::from(&params))
+            .to_bytes()
+            .unwrap();
+
+        let flight = FlightTelemetry {
+            timestamp: 1_672_531_200_000_000,
+            latitude: 35.681236,
+            longitude: 139.767125,
+            altitude: 120.5,
+            heading: 271.25,
+            speed: 22.4,
+            sensor_azimuth: 90.0,
+            sensor_elevation: -12.5,
+        };
+        let mut ds = ExampleDataset::from(&params);
+        assert!(ds.flight().is_none());
+        ds.set_flight(&flight);
+        let records = KlvDataset::Example(ds).to_bytes().unwrap();
+        // 飛行の状態の分だけ長くなり、無い場合は従来と同じ
+        assert!(records.len() > plain.len());
+
+        let decoded = match KlvDataset::from_bytes(&records).unwrap() {
+            KlvDataset::Example(ds) => ds,
+            ds => panic!("unexpected dataset {:?}", ds),
+        };
+        let params: ExampleRsMetaParams = decoded.clone().into();
+        assert_eq!((params.label.as_str(), params.index), ("uav", 3));
+        let decoded = decoded.flight().unwrap();
+        assert_eq!(decoded.timestamp, flight.timestamp);
+        for (a, b) in [
+            (decoded.latitude, flight.latitude),
+            (decoded.longitude, flight.longitude),
+            (decoded.altitude, flight.altitude),
+            (decoded.heading, flight.heading),
+            (decoded.speed, flight.speed),
+            (decoded.sensor_azimuth, flight.sensor_azimuth),
+            (decoded.sensor_elevation, flight.sensor_elevation),
+        ] {
+            assert!((a - b).abs() < 1e-6, "{} != {}", a, b);
+        }
+    }
+
+    #[test]
+    fn test_uas_datalink_from_example_rs() {
+        gst::init().unwrap();
+        let mut buffer = gst::Buffer::new();
+        let params = ExampleRsMetaParams::new("uav".to_string(), 7, ers_meta::TransformMode::Copy);
+        ExampleRsMeta::add(buffer.get_mut().unwrap(), params);
+        let meta = buffer.meta::<ExampleRsMeta>().unwrap();
+
+        // 時刻は呼び出し側で決めたものが入る
+        let ds = UasDatalinkLS::from_example_rs(&meta, 1_000_000);
+        assert_eq!(ds.timestamp, 1_000_000);
+        assert_eq!(ds.mission_id.as_deref(), Some("uav"));
+
+        // labelだけが戻り、indexは失われる
+        let params: ExampleRsMetaParams = ds.into();
+        assert_eq!((params.label.as_str(), params.index), ("uav", 0));
+    }
+
+    #[test]
+    fn test_uas_datalink_checksum_mismatch() {
+        let mut records = UasDatalinkLS::new(0).to_bytes().unwrap();
+        let len = records.len();
+        records[len - 1] ^= 0xff;
+        assert!(matches!(
+            UasDatalinkLS::from_bytes(&records),
+            Err(KlvError::Checksum { .. })
+        ));
+    }
+}
@@ -1,20 +1,35 @@
 //! MetaMuxer
 //!
 //! MetaDemuxerでvideo + klvに分解されたデータをvideo + metadataに復元する
+//!
+//! live時はtoleranceとKLVの間隔に加えてAggregatorのlatencyプロパティの時間だけKLVを待ち、
+//! 間に合わなければtimeoutプロパティに従ってvideoのフレームを出力する
+//!
+//! KLV以外のsinkはvideo/x-rawに限らずencode済みのvideoやaudioでもよい
+//! B-frameなどでptsの順序が入れ替わっても到着順ではなくptsでKLVを照合する
+//!
+//! KLVのsinkは複数繋げられ、それぞれのKLVが別のmetaとして同じフレームに付与される
+//! 付与する順序はsink padを追加した順で、padのmeta-typeプロパティで付与するmetaの種類を指定できる
+use std::collections::VecDeque;
+use std::ffi::NulError;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Mutex;
 
-use ers_meta::ExampleRsMetaParams;
 use gst::glib;
 use gst::prelude::*;
 use gst::subclass::prelude::*;
 use gst::traits::PadExt;
 use gst_base::prelude::AggregatorExtManual;
 use gst_base::prelude::AggregatorPadExtManual;
-use gst_base::subclass::prelude::{AggregatorImpl, AggregatorImplExt};
-use gst_base::traits::AggregatorPadExt;
+use gst_base::subclass::prelude::{AggregatorImpl, AggregatorImplExt, AggregatorPadImpl};
+use gst_base::traits::{AggregatorExt, AggregatorPadExt};
 use once_cell::sync::Lazy;
 
-use crate::metaklv::ExampleDataset;
+#[cfg(feature = "v1_20")]
+use crate::custommeta;
+use crate::metaklv::{
+    malformed_details, unencodable_details, CustomDataset, KlvDataset, KlvErrorPolicy,
+};
 
 use super::CLASS_NAME;
 use super::ELEMENT_NAME;
@@ -27,34 +42,243 @@ static CAT: Lazy<gst::DebugCategory> = Lazy::new(|| {
     )
 });
 
-// AggregatorPadに流れてきたどれがvideoでどれがcapsか識別するEnum
-#[derive(Debug, PartialEq, Eq)]
+const DEFAULT_TOLERANCE: gst::ClockTime = gst::ClockTime::from_mseconds(1);
+
+/// 照合するKLVがないフレームの扱い
+#[derive(Default, Debug, PartialEq, Eq, Clone, Copy, glib::Enum)]
+#[repr(u32)]
+#[enum_type(name = "GstMetaMuxNoMatchPolicy")]
+enum NoMatchPolicy {
+    #[default]
+    #[enum_value(name = "None: no metadata", nick = "none")]
+    None = 0,
+    #[enum_value(name = "HoldLast: last received metadata", nick = "hold-last")]
+    HoldLast = 1,
+    #[enum_value(name = "Nearest: nearest metadata in time", nick = "nearest")]
+    Nearest = 2,
+}
+
+/// 1フレームに複数のKLVが照合された場合の扱い
+#[derive(Default, Debug, PartialEq, Eq, Clone, Copy, glib::Enum)]
+#[repr(u32)]
+#[enum_type(name = "GstMetaMuxExtraMetaPolicy")]
+enum ExtraMetaPolicy {
+    #[default]
+    #[enum_value(name = "Drop: attach only the closest metadata", nick = "drop")]
+    Drop = 0,
+    #[enum_value(name = "Merge: attach all matched metadata", nick = "merge")]
+    Merge = 1,
+}
+
+/// live時にKLVが間に合わなかったフレームの扱い
+#[derive(Default, Debug, PartialEq, Eq, Clone, Copy, glib::Enum)]
+#[repr(u32)]
+#[enum_type(name = "GstMetaMuxTimeoutPolicy")]
+enum TimeoutPolicy {
+    #[default]
+    #[enum_value(name = "None: push video frame without metadata", nick = "none")]
+    None = 0,
+    #[enum_value(
+        name = "HoldLast: push video frame with last metadata",
+        nick = "hold-last"
+    )]
+    HoldLast = 1,
+}
+
+#[allow(clippy::from_over_into)]
+impl Into<NoMatchPolicy> for TimeoutPolicy {
+    fn into(self) -> NoMatchPolicy {
+        match self {
+            TimeoutPolicy::None => NoMatchPolicy::None,
+            TimeoutPolicy::HoldLast => NoMatchPolicy::HoldLast,
+        }
+    }
+}
+
+/// KLVのsink padごとに付与するmetaの種類
+///
+/// glib::Enumはcfgを付けたvariantを扱えないので`v1_20`の有無で定義を分ける
+#[cfg(feature = "v1_20")]
+#[derive(Default, Debug, PartialEq, Eq, Clone, Copy, glib::Enum)]
+#[repr(u32)]
+#[enum_type(name = "GstMetaMuxMetaType")]
+enum MetaType {
+    #[default]
+    #[enum_value(name = "Auto: meta corresponding to klv dataset", nick = "auto")]
+    Auto = 0,
+    #[enum_value(name = "Rs: ExampleRsMeta", nick = "rs")]
+    Rs = 1,
+    #[enum_value(name = "C: ExampleCMeta", nick = "c")]
+    C = 2,
+    #[enum_value(name = "Custom: GstCustomMeta", nick = "custom")]
+    Custom = 3,
+}
+
+#[cfg(not(feature = "v1_20"))]
+#[derive(Default, Debug, PartialEq, Eq, Clone, Copy, glib::Enum)]
+#[repr(u32)]
+#[enum_type(name = "GstMetaMuxMetaType")]
+enum MetaType {
+    #[default]
+    #[enum_value(name = "Auto: meta corresponding to klv dataset", nick = "auto")]
+    Auto = 0,
+    #[enum_value(name = "Rs: ExampleRsMeta", nick = "rs")]
+    Rs = 1,
+    #[enum_value(name = "C: ExampleCMeta", nick = "c")]
+    C = 2,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Settings {
+    tolerance: gst::ClockTime,
+    no_match: NoMatchPolicy,
+    extra_meta: ExtraMetaPolicy,
+    timeout: TimeoutPolicy,
+    error_policy: KlvErrorPolicy,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            tolerance: DEFAULT_TOLERANCE,
+            no_match: NoMatchPolicy::default(),
+            extra_meta: ExtraMetaPolicy::default(),
+            timeout: TimeoutPolicy::default(),
+            error_policy: KlvErrorPolicy::default(),
+        }
+    }
+}
+
+// AggregatorPadに流れてきたどれがmetaを付与するストリームでどれがKLVか識別するEnum
+//
+// Mainはvideo/x-rawに限らずencode済みのvideoやaudioでもよい
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
 enum CapsType {
-    Video,
+    Main,
     Meta,
 }
 
+impl CapsType {
+    fn from_caps(caps: &gst::CapsRef) -> Self {
+        match caps.structure(0).map(|s| s.name()) {
+            Some("meta/x-klv") => CapsType::Meta,
+            _ => CapsType::Main,
+        }
+    }
+}
+
 #[derive(Debug)]
 struct Stream {
     /// Sink pad for this stream.
     sinkpad: gst_base::AggregatorPad,
     capstype: CapsType,
+    // 処理中のフレームに照合されたKLV
+    // 続きのKLVを待つ間もaggregateを跨いで保持する
+    matched: Vec<(gst::ClockTime, KlvDataset)>,
+    // 読んだがまだどのフレームにも照合していないKLV。壊れたKLVはNone
+    pending: VecDeque<(gst::ClockTime, Option<KlvDataset>)>,
+    // 最後に読んだKLVの時刻
+    read_upto: Option<gst::ClockTime>,
+    // 最後に受け取ったKLV
+    last: Option<(gst::ClockTime, KlvDataset)>,
+    // timeoutで処理中のフレームのKLVを待たなかった
+    late: bool,
+    // 処理中のフレームに壊れたKLVが照合された
+    malformed: bool,
 }
 
-// 一度識別したら後は同じものPadが使えるので識別した情報を保持する
+impl Stream {
+    fn new(sinkpad: gst_base::AggregatorPad, capstype: CapsType) -> Self {
+        Self {
+            sinkpad,
+            capstype,
+            matched: vec![],
+            pending: VecDeque::new(),
+            read_upto: None,
+            last: None,
+            late: false,
+            malformed: false,
+        }
+    }
+
+    // flush後は照合中のKLVを引き継がない
+    fn reset(&mut self) {
+        *self = Self::new(self.sinkpad.clone(), self.capstype);
+    }
+}
+
+// 識別したPadの情報を保持する。padの追加や解放に合わせてaggregateごとに更新する
 #[derive(Debug, Default)]
 struct State {
     streams: Vec<Stream>,
+    // KLV以外のsinkの最新のcaps。解像度の変更などでsrcを再ネゴシエーションする
+    main_caps: Option<gst::Caps>,
 }
 
 #[derive(Default, Debug)]
 pub struct MetaMux {
     state: Mutex<State>,
+    settings: Mutex<Settings>,
+    malformed: AtomicU64,
+    // 受け取ったKLVの最大の間隔。latencyの計算に使う
+    klv_interval: Mutex<Option<gst::ClockTime>>,
+}
+
+fn distance(a: gst::ClockTime, b: gst::ClockTime) -> gst::ClockTime {
+    if a > b {
+        a - b
+    } else {
+        b - a
+    }
+}
+
+/// KLVを照合するフレームの時刻
+#[derive(Debug, Clone, Copy)]
+struct FrameWindow {
+    pts: gst::ClockTime,
+    dts: Option<gst::ClockTime>,
+    duration: Option<gst::ClockTime>,
+    tolerance: gst::ClockTime,
+}
+
+impl FrameWindow {
+    // 照合範囲の上限以下か。範囲は`pts ± tolerance`だが次のフレームの方が近いKLVは含めない
+    fn in_upper(&self, t: gst::ClockTime) -> bool {
+        t <= self.pts + self.tolerance
+            && self.duration.map_or(true, |d| {
+                t < self.pts + gst::ClockTime::from_nseconds(d.nseconds() / 2)
+            })
+    }
+
+    fn contains(&self, t: gst::ClockTime) -> bool {
+        t >= self.pts.saturating_sub(self.tolerance) && self.in_upper(t)
+    }
+
+    // 以降のフレームのptsはこのフレームのdts以上なので、これより前のKLVは照合されない
+    fn horizon(&self) -> gst::ClockTime {
+        self.dts
+            .map_or(self.pts, |dts| dts.min(self.pts))
+            .saturating_sub(self.tolerance)
+    }
+}
+
+// 保留中のKLVから照合範囲のものを取り出し、以降のフレームで照合されないKLVを捨てる
+fn take_matched<T>(
+    pending: &mut VecDeque<(gst::ClockTime, T)>,
+    window: &FrameWindow,
+) -> Vec<(gst::ClockTime, T)> {
+    let (matched, rest): (Vec<_>, Vec<_>) =
+        pending.drain(..).partition(|(t, _)| window.contains(*t));
+    pending.extend(rest.into_iter().filter(|(t, _)| *t >= window.horizon()));
+    matched
 }
 
 impl MetaMux {
-    // 初回の識別
-    fn create_streams(&self, state: &mut State) -> Result<(), gst::FlowError> {
+    // sink padの追加や解放、capsの変更に合わせてstreamsを更新する
+    //
+    // 既存のpadは照合中のKLVを引き継ぎ、KLVとそれ以外が入れ替わった場合は作り直す
+    fn sync_streams(&self, state: &mut State) -> Result<(), gst::FlowError> {
+        let mut streams = vec![];
         for pad in self
             .obj()
             .sink_pads()
@@ -64,49 +288,417 @@ impl MetaMux {
             let caps = match pad.current_caps() {
                 Some(caps) => caps,
                 None => {
-                    gst::warning!(CAT, obj: pad, "Skipping pad without caps");
+                    gst::trace!(CAT, obj: pad, "Skipping pad without caps");
                     continue;
                 }
             };
-
-            let capstype = match caps.structure(0).unwrap().name() {
-                "video/x-raw" => CapsType::Video,
-                "meta/x-klv" => CapsType::Meta,
-                _ => unreachable!(),
+            let capstype = CapsType::from_caps(&caps);
+            let stream = match state
+                .streams
+                .iter()
+                .position(|stream| stream.sinkpad == pad && stream.capstype == capstype)
+            {
+                Some(i) => state.streams.swap_remove(i),
+                None => {
+                    gst::debug!(CAT, obj: pad, "new {:?} stream {}", capstype, caps);
+                    Stream::new(pad, capstype)
+                }
             };
+            streams.push(stream);
+        }
+        // metaを付与する先は1つに限る
+        let mut mains = streams
+            .iter()
+            .filter(|stream| stream.capstype == CapsType::Main);
+        if let (Some(_), Some(extra)) = (mains.next(), mains.next()) {
+            gst::element_imp_error!(
+                self,
+                gst::CoreError::Negotiation,
+                [
+                    "Multiple non-klv streams, unsupported caps {:?} on {}",
+                    extra.sinkpad.current_caps(),
+                    extra.sinkpad.name()
+                ]
+            );
+            return Err(gst::FlowError::NotNegotiated);
+        }
+        state.streams = streams;
+        Ok(())
+    }
 
-            state.streams.push(Stream {
-                sinkpad: pad,
-                capstype,
-            });
+    #[cfg(feature = "v1_20")]
+    fn add_custom_meta(&self, buffer: &mut gst::BufferRef, ds: &CustomDataset) {
+        let res = ds
+            .structure()
+            .and_then(|fields| custommeta::add(buffer, &fields));
+        if let Err(e) = res {
+            gst::warning!(CAT, imp: self, "failed to add custom meta: {}", e);
+        }
+    }
+
+    #[cfg(not(feature = "v1_20"))]
+    fn add_custom_meta(&self, _buffer: &mut gst::BufferRef, _ds: &CustomDataset) {
+        gst::warning!(CAT, imp: self, "custom meta requires GStreamer 1.20 (v1_20 feature)");
+    }
+
+    // KLVを復元する。壊れたパケットは記録してwarningを送りNoneを返す
+    fn decode(&self, metabuffer: &gst::Buffer) -> Result<Option<KlvDataset>, gst::FlowError> {
+        let b = metabuffer
+            .map_readable()
+            .map_err(|_| gst::FlowError::Error)?;
+        // Universal Keyでdatasetの種類を判別する
+        match KlvDataset::from_bytes(b.as_slice()) {
+            Ok(dataset) => Ok(Some(dataset)),
+            Err(e) => {
+                let count = self.malformed.fetch_add(1, Ordering::Relaxed) + 1;
+                gst::warning!(
+                    CAT,
+                    imp: self,
+                    "malformed klv ({} total) {:?}: {}",
+                    count,
+                    metabuffer.pts(),
+                    e
+                );
+                gst::element_imp_warning!(
+                    self,
+                    gst::StreamError::Decode,
+                    ["Malformed KLV packet: {}", e],
+                    details: malformed_details(b.as_slice())
+                );
+                Ok(None)
+            }
+        }
+    }
+
+    // 復元したKLVをvideoのバッファに付与する
+    //
+    // Autoはdatasetに対応するmetaに、それ以外は指定されたmetaに変換して付与する
+    // ExampleCMetaのlabelはC文字列なので、NULを含む場合は付与せずにErrを返す
+    fn attach(
+        &self,
+        buffer: &mut gst::BufferRef,
+        dataset: &KlvDataset,
+        meta_type: MetaType,
+    ) -> Result<(), NulError> {
+        match (meta_type, dataset) {
+            (MetaType::Auto, KlvDataset::Example(ds)) => {
+                ers_meta::ExampleRsMeta::add(buffer, ds.clone().into());
+            }
+            (MetaType::Auto, KlvDataset::UasDatalink(ds)) => {
+                ers_meta::ExampleRsMeta::add(buffer, ds.clone().into());
+            }
+            (MetaType::Auto, KlvDataset::Custom(ds)) => self.add_custom_meta(buffer, ds),
+            #[cfg(feature = "v1_20")]
+            (MetaType::Custom, KlvDataset::Custom(ds)) => self.add_custom_meta(buffer, ds),
+            (MetaType::Auto, KlvDataset::ExampleC(ds)) => {
+                ec_meta::ExampleCMeta::add(buffer, ds.clone().into())?;
+            }
+            (MetaType::Rs, dataset) => match dataset.to_example_rs() {
+                Some(params) => {
+                    ers_meta::ExampleRsMeta::add(buffer, params);
+                }
+                None => self.unsupported(dataset, meta_type),
+            },
+            (MetaType::C, dataset) => match dataset.to_example_c() {
+                Some(params) => {
+                    ec_meta::ExampleCMeta::add(buffer, params)?;
+                }
+                None => self.unsupported(dataset, meta_type),
+            },
+            #[cfg(feature = "v1_20")]
+            (MetaType::Custom, dataset) => self.unsupported(dataset, meta_type),
         }
         Ok(())
     }
 
+    // metaに変換できなかったKLVを壊れたパケットとして記録してwarningを送る
+    fn report_unattachable(&self, dataset: &KlvDataset, err: &NulError) {
+        let count = self.malformed.fetch_add(1, Ordering::Relaxed) + 1;
+        gst::warning!(
+            CAT,
+            imp: self,
+            "can not attach klv ({} total) {:?}: {}",
+            count,
+            dataset,
+            err
+        );
+        let details = match dataset.to_bytes() {
+            Ok(data) => malformed_details(&data),
+            Err(_) => unencodable_details(dataset),
+        };
+        gst::element_imp_warning!(
+            self,
+            gst::StreamError::Decode,
+            ["Malformed KLV packet: {}", err],
+            details: details
+        );
+    }
+
+    // 壊れたKLVが照合されたフレームをerror-policyに従って扱う。フレームを出力する場合はtrue
+    fn pass_malformed(
+        &self,
+        policy: KlvErrorPolicy,
+        pts: gst::ClockTime,
+    ) -> Result<bool, gst::FlowError> {
+        match policy {
+            KlvErrorPolicy::Drop => {
+                gst::debug!(CAT, imp: self, "drop video frame {}", pts);
+                Ok(false)
+            }
+            KlvErrorPolicy::Pass => Ok(true),
+            KlvErrorPolicy::Error => {
+                gst::element_imp_error!(
+                    self,
+                    gst::StreamError::Decode,
+                    ["Malformed KLV packet for video frame {}", pts]
+                );
+                Err(gst::FlowError::Error)
+            }
+        }
+    }
+
+    fn unsupported(&self, dataset: &KlvDataset, meta_type: MetaType) {
+        gst::warning!(
+            CAT,
+            imp: self,
+            "can not attach {:?} as {:?} meta",
+            dataset,
+            meta_type
+        );
+    }
+
+    // 先頭のフレームの時刻に照合するKLVを集める
+    //
+    // B-frameなどでフレームのptsの順序は入れ替わるので、読んだKLVは
+    // 以降のフレームで照合されなくなるまでpendingに保留する
+    // 範囲の終わりまでKLVが揃っていなければfalseを返して続きを待つ
+    fn collect_meta(
+        &self,
+        stream: &mut Stream,
+        window: &FrameWindow,
+    ) -> Result<bool, gst::FlowError> {
+        // 既に範囲より後のKLVを読んでいれば揃っている
+        let mut ready = stream.read_upto.map_or(false, |t| !window.in_upper(t));
+        while !ready {
+            let metabuffer = match stream.sinkpad.peek_buffer() {
+                Some(metabuffer) => metabuffer,
+                None => {
+                    ready = stream.sinkpad.is_eos();
+                    break;
+                }
+            };
+            let metapts = match metabuffer.pts() {
+                Some(metapts) => metapts,
+                None => {
+                    gst::debug!(CAT, obj: stream.sinkpad, "drop klv without pts");
+                    stream.sinkpad.drop_buffer();
+                    continue;
+                }
+            };
+            if !window.in_upper(metapts) {
+                // 次のフレーム以降のKLV
+                ready = true;
+                break;
+            }
+            stream.sinkpad.drop_buffer();
+            // metademuxなどが送るGAPイベントはAggregatorで空のバッファになる
+            // KLVがないことを示すだけなのでdecodeしない
+            if metabuffer.flags().contains(gst::BufferFlags::GAP) {
+                gst::trace!(CAT, obj: stream.sinkpad, "gap {}", metapts);
+                continue;
+            }
+            // 間隔は上流のdurationか、なければ前のKLVとの差から求める
+            let interval = metabuffer
+                .duration()
+                .or_else(|| stream.read_upto.map(|t| metapts.saturating_sub(t)));
+            if let Some(interval) = interval {
+                self.observe_interval(interval);
+            }
+            stream.read_upto = Some(metapts);
+            let dataset = self.decode(&metabuffer)?;
+            if let Some(ref dataset) = dataset {
+                stream.last = Some((metapts, dataset.clone()));
+            }
+            stream.pending.push_back((metapts, dataset));
+        }
+        for (metapts, dataset) in take_matched(&mut stream.pending, window) {
+            match dataset {
+                Some(dataset) => stream.matched.push((metapts, dataset)),
+                // 壊れている場合は取り出した時に報告済み
+                None => stream.malformed = true,
+            }
+        }
+        Ok(ready)
+    }
+
+    // 照合結果と設定からフレームに付与するKLVを選ぶ
+    fn select_meta(
+        stream: &mut Stream,
+        pts: gst::ClockTime,
+        settings: &Settings,
+    ) -> Vec<KlvDataset> {
+        let mut matched = std::mem::take(&mut stream.matched);
+        let late = std::mem::take(&mut stream.late);
+        if !matched.is_empty() {
+            return match settings.extra_meta {
+                ExtraMetaPolicy::Merge => matched.into_iter().map(|(_, b)| b).collect(),
+                ExtraMetaPolicy::Drop => {
+                    matched.sort_by_key(|(t, _)| distance(*t, pts));
+                    matched.into_iter().take(1).map(|(_, b)| b).collect()
+                }
+            };
+        }
+        let no_match = if late {
+            settings.timeout.into()
+        } else {
+            settings.no_match
+        };
+        match no_match {
+            NoMatchPolicy::None => vec![],
+            NoMatchPolicy::HoldLast => stream.last.iter().map(|(_, b)| b.clone()).collect(),
+            NoMatchPolicy::Nearest => {
+                // 前に受け取ったKLVと次のKLVのうち近い方
+                // 壊れている場合は取り出した時に報告するのでここでは無視する
+                let next = stream.sinkpad.peek_buffer().and_then(|b| {
+                    let t = b.pts()?;
+                    let map = b.map_readable().ok()?;
+                    KlvDataset::from_bytes(map.as_slice())
+                        .ok()
+                        .map(|ds| (t, ds))
+                });
+                // 保留中のKLVも候補にする
+                let pending = stream
+                    .pending
+                    .iter()
+                    .filter_map(|(t, ds)| ds.clone().map(|ds| (*t, ds)));
+                stream
+                    .last
+                    .clone()
+                    .into_iter()
+                    .chain(pending)
+                    .chain(next)
+                    .min_by_key(|(t, _)| distance(*t, pts))
+                    .map(|(_, b)| b)
+                    .into_iter()
+                    .collect()
+            }
+        }
+    }
+
+    // フレームの出力はpts + toleranceより後のKLVが届くまで待つので、
+    // toleranceとKLVの間隔を合わせた時間をlatencyとして報告する
+    // 間隔はKLVを受け取るまで分からないので、それまではtoleranceだけを報告する
+    // liveのsourceの遅れを待つ時間はAggregatorのlatencyプロパティで指定する
+    fn update_latency(&self) {
+        let tolerance = self.settings.lock().unwrap().tolerance;
+        let interval = self
+            .klv_interval
+            .lock()
+            .unwrap()
+            .unwrap_or(gst::ClockTime::ZERO);
+        let latency = tolerance + interval;
+        gst::debug!(CAT, imp: self, "latency {}", latency);
+        self.obj().set_latency(latency, Some(latency));
+    }
+
+    // KLVの間隔が広がった場合はlatencyを更新する
+    fn observe_interval(&self, interval: gst::ClockTime) {
+        {
+            let mut klv_interval = self.klv_interval.lock().unwrap();
+            if klv_interval.map_or(false, |current| current >= interval) {
+                return;
+            }
+            *klv_interval = Some(interval);
+        }
+        self.update_latency();
+    }
+
     // Aggregatorにデータが揃ってSrcに送る為にバッファをマージする
-    fn drain(&self, state: &mut State) -> Result<gst::Buffer, gst::FlowError> {
-        let mut buffer = None;
-        for stream in state.streams.iter_mut() {
-            match stream.capstype {
-                CapsType::Video => {
-                    gst::info!(CAT, "video");
-                    buffer = Some(stream.sinkpad.pop_buffer().unwrap());
+    //
+    // KLVが揃っていなければNoneを返して次のaggregateを待つ
+    // timeoutの場合は揃っていなくてもvideoのフレームを出力する
+    fn drain(
+        &self,
+        state: &mut State,
+        timeout: bool,
+    ) -> Result<Option<gst::Buffer>, gst::FlowError> {
+        let settings = *self.settings.lock().unwrap();
+        let videopad = state
+            .streams
+            .iter()
+            .find(|stream| stream.capstype == CapsType::Main)
+            .map(|stream| stream.sinkpad.clone())
+            .ok_or(gst::FlowError::NotNegotiated)?;
+        let (pts, dts, duration) = match videopad.peek_buffer() {
+            Some(buffer) => (buffer.pts(), buffer.dts(), buffer.duration()),
+            None if videopad.is_eos() => return Err(gst::FlowError::Eos),
+            None => return Ok(None),
+        };
+        // 時刻のないフレームは照合できないのでそのまま出力する
+        let pts = match pts {
+            Some(pts) => pts,
+            None => return Ok(videopad.pop_buffer()),
+        };
+        let window = FrameWindow {
+            pts,
+            dts,
+            duration,
+            tolerance: settings.tolerance,
+        };
+
+        for stream in state
+            .streams
+            .iter_mut()
+            .filter(|stream| stream.capstype == CapsType::Meta)
+        {
+            if !self.collect_meta(stream, &window)? {
+                if !timeout {
+                    gst::trace!(CAT, obj: stream.sinkpad, "waiting klv for {}", pts);
+                    return Ok(None);
+                }
+                // 遅れて届いたKLVは次のフレームで範囲外として捨てられる
+                gst::debug!(CAT, obj: stream.sinkpad, "klv for {} is late", pts);
+                stream.late = true;
+            }
+        }
+
+        let mut buffer = videopad.pop_buffer().unwrap();
+        let mut datasets = vec![];
+        let mut malformed = false;
+        for stream in state
+            .streams
+            .iter_mut()
+            .filter(|stream| stream.capstype == CapsType::Meta)
+        {
+            // 照合済みのKLVはPassでも取り出して次のフレームに持ち越さない
+            let selected = Self::select_meta(stream, pts, &settings);
+            if std::mem::take(&mut stream.malformed) {
+                malformed = true;
+                // Passは壊れたKLVが照合されたstreamのmetaだけを付与しない
+                if settings.error_policy == KlvErrorPolicy::Pass {
+                    gst::debug!(CAT, obj: stream.sinkpad, "skip meta for {}", pts);
+                    continue;
                 }
-                CapsType::Meta => {
-                    if let Some(ref mut buffer) = buffer {
-                        let metabuffer = stream.sinkpad.pop_buffer().unwrap();
-                        let param: ExampleRsMetaParams = {
-                            let b = metabuffer.map_readable().unwrap();
-                            let v = serde_klv::from_bytes::<ExampleDataset>(b.as_slice()).unwrap();
-                            v.into()
-                        };
-                        let wb = buffer.make_mut();
-                        ers_meta::ExampleRsMeta::add(wb, param);
+            }
+            let meta_type = stream.sinkpad.property::<MetaType>("meta-type");
+            datasets.extend(selected.into_iter().map(|dataset| (dataset, meta_type)));
+        }
+        if malformed && !self.pass_malformed(settings.error_policy, pts)? {
+            return Ok(None);
+        }
+        {
+            let wb = buffer.make_mut();
+            for (dataset, meta_type) in datasets {
+                // Passは変換できなかったmetaだけを付与しない
+                if let Err(e) = self.attach(wb, &dataset, meta_type) {
+                    self.report_unattachable(&dataset, &e);
+                    if !self.pass_malformed(settings.error_policy, pts)? {
+                        return Ok(None);
                     }
                 }
             }
         }
-        Ok(buffer.unwrap())
+        Ok(Some(buffer))
     }
 }
 
@@ -124,16 +716,38 @@ impl ElementImpl for MetaMux {
         Some(&*ELEMENT_METADATA)
     }
 
+    // gst-launchからsink_%u::meta-typeを指定できるようにchild proxyに通知する
+    fn request_new_pad(
+        &self,
+        templ: &gst::PadTemplate,
+        name: Option<&str>,
+        caps: Option<&gst::Caps>,
+    ) -> Option<gst::Pad> {
+        let pad = self.parent_request_new_pad(templ, name, caps)?;
+        self.obj().child_added(&pad, &pad.name());
+        Some(pad)
+    }
+
+    // 再生中に解放されたpadのKLVは待たない
+    fn release_pad(&self, pad: &gst::Pad) {
+        gst::debug!(CAT, obj: pad, "release pad");
+        self.state
+            .lock()
+            .unwrap()
+            .streams
+            .retain(|stream| stream.sinkpad.upcast_ref::<gst::Pad>() != pad);
+        self.obj().child_removed(pad, &pad.name());
+        self.parent_release_pad(pad);
+    }
+
     fn pad_templates() -> &'static [gst::PadTemplate] {
         static PAD_TEMPLATES: Lazy<Vec<gst::PadTemplate>> = Lazy::new(|| {
-            // フレーム順序が復元されるdecode後にマージするためvideo/x-rawに制限する
+            // KLVはptsで照合するのでencode済みのvideoやaudioにも付与できる
             let src_pad_template = gst::PadTemplate::new(
                 "src",
                 gst::PadDirection::Src,
                 gst::PadPresence::Always,
-                &[gst::Structure::builder("video/x-raw").build()]
-                    .into_iter()
-                    .collect::<gst::Caps>(),
+                &gst::Caps::new_any(),
             )
             .unwrap();
 
@@ -141,15 +755,8 @@ impl ElementImpl for MetaMux {
                 "sink_%u",
                 gst::PadDirection::Sink,
                 gst::PadPresence::Request,
-                &[
-                    gst::Structure::builder("video/x-raw").build(),
-                    gst::Structure::builder("meta/x-klv")
-                        .field("parsed", true)
-                        .build(),
-                ]
-                .into_iter()
-                .collect::<gst::Caps>(),
-                gst_base::AggregatorPad::static_type(),
+                &gst::Caps::new_any(),
+                super::MetaMuxPad::static_type(),
             )
             .unwrap();
 
@@ -160,7 +767,109 @@ impl ElementImpl for MetaMux {
     }
 }
 
-impl ObjectImpl for MetaMux {}
+impl ObjectImpl for MetaMux {
+    fn properties() -> &'static [glib::ParamSpec] {
+        static PROPERTIES: Lazy<Vec<glib::ParamSpec>> = Lazy::new(|| {
+            vec![
+                glib::ParamSpecUInt64::builder("tolerance")
+                    .nick("Tolerance")
+                    .blurb("max difference of pts to match klv to video frame in nanoseconds")
+                    .default_value(DEFAULT_TOLERANCE.nseconds())
+                    .mutable_playing()
+                    .build(),
+                glib::ParamSpecEnum::builder::<NoMatchPolicy>("no-match", NoMatchPolicy::default())
+                    .nick("No match policy")
+                    .blurb("metadata for video frame without matched klv")
+                    .mutable_playing()
+                    .build(),
+                glib::ParamSpecEnum::builder::<ExtraMetaPolicy>(
+                    "extra-meta",
+                    ExtraMetaPolicy::default(),
+                )
+                .nick("Extra meta policy")
+                .blurb("handling of multiple klv matched to one video frame")
+                .mutable_playing()
+                .build(),
+                glib::ParamSpecEnum::builder::<TimeoutPolicy>("timeout", TimeoutPolicy::default())
+                    .nick("Timeout policy")
+                    .blurb("metadata for video frame when klv is late in live. klv is waited for tolerance, klv interval and latency property")
+                    .mutable_playing()
+                    .build(),
+                glib::ParamSpecEnum::builder::<KlvErrorPolicy>(
+                    "error-policy",
+                    KlvErrorPolicy::default(),
+                )
+                .nick("Error policy")
+                .blurb("handling of video frame matched to malformed klv")
+                .mutable_playing()
+                .build(),
+                glib::ParamSpecUInt64::builder("malformed-packets")
+                    .nick("Malformed packets")
+                    .blurb("number of malformed klv packets")
+                    .read_only()
+                    .build(),
+            ]
+        });
+
+        PROPERTIES.as_ref()
+    }
+
+    fn set_property(&self, _id: usize, value: &glib::Value, pspec: &glib::ParamSpec) {
+        let mut settings = self.settings.lock().unwrap();
+        match pspec.name() {
+            "tolerance" => {
+                let x = value.get::<u64>().expect("type checked upstream");
+                gst::info!(CAT, imp: self, "set prop tolerance to {}", x);
+                settings.tolerance = gst::ClockTime::from_nseconds(x);
+                drop(settings);
+                self.update_latency();
+            }
+            "no-match" => {
+                let x = value.get::<NoMatchPolicy>().expect("type checked upstream");
+                gst::info!(CAT, imp: self, "set prop no-match to {:?}", x);
+                settings.no_match = x;
+            }
+            "extra-meta" => {
+                let x = value
+                    .get::<ExtraMetaPolicy>()
+                    .expect("type checked upstream");
+                gst::info!(CAT, imp: self, "set prop extra-meta to {:?}", x);
+                settings.extra_meta = x;
+            }
+            "timeout" => {
+                let x = value.get::<TimeoutPolicy>().expect("type checked upstream");
+                gst::info!(CAT, imp: self, "set prop timeout to {:?}", x);
+                settings.timeout = x;
+            }
+            "error-policy" => {
+                let x = value
+                    .get::<KlvErrorPolicy>()
+                    .expect("type checked upstream");
+                gst::info!(CAT, imp: self, "set prop error-policy to {:?}", x);
+                settings.error_policy = x;
+            }
+            _ => unimplemented!(),
+        }
+    }
+
+    fn property(&self, _id: usize, pspec: &glib::ParamSpec) -> glib::Value {
+        let settings = self.settings.lock().unwrap();
+        match pspec.name() {
+            "tolerance" => settings.tolerance.nseconds().to_value(),
+            "no-match" => settings.no_match.to_value(),
+            "extra-meta" => settings.extra_meta.to_value(),
+            "timeout" => settings.timeout.to_value(),
+            "error-policy" => settings.error_policy.to_value(),
+            "malformed-packets" => self.malformed.load(Ordering::Relaxed).to_value(),
+            _ => unimplemented!(),
+        }
+    }
+
+    fn constructed(&self) {
+        self.parent_constructed();
+        self.update_latency();
+    }
+}
 impl GstObjectImpl for MetaMux {}
 
 #[glib::object_subclass]
@@ -170,36 +879,75 @@ impl ObjectSubclass for MetaMux {
     // VideoとMetaの2ストリームなのでAggregatorを使う
     // 特殊な順序付けや画像同士というものでもないのでGstVideoAggregatorを使わなかった
     type ParentType = gst_base::Aggregator;
+    type Interfaces = (gst::ChildProxy,);
+}
+
+impl ChildProxyImpl for MetaMux {
+    fn child_by_index(&self, index: u32) -> Option<glib::Object> {
+        self.obj()
+            .sink_pads()
+            .into_iter()
+            .nth(index as usize)
+            .map(|pad| pad.upcast())
+    }
+
+    fn child_by_name(&self, name: &str) -> Option<glib::Object> {
+        self.obj().static_pad(name).map(|pad| pad.upcast())
+    }
+
+    fn children_count(&self) -> u32 {
+        self.obj().sink_pads().len() as u32
+    }
 }
 
 impl AggregatorImpl for MetaMux {
     // sinkにバッファが揃ったらここが呼ばれる
+    // live時はlatencyまでにKLVが揃わなければtimeoutとして呼ばれる
     fn aggregate(&self, timeout: bool) -> Result<gst::FlowSuccess, gst::FlowError> {
-        // TODO timeoutが発生した場合の処理を決める
-        gst::debug!(CAT, "aggregate timeout {}", timeout);
+        gst::debug!(CAT, imp: self, "aggregate timeout {}", timeout);
         let buffer = {
             let mut state = self.state.lock().unwrap();
 
-            // Create streams
-            if state.streams.is_empty() {
-                self.create_streams(&mut state)?;
-            }
+            self.sync_streams(&mut state)?;
 
             let all_eos = state.streams.iter().all(|stream| stream.sinkpad.is_eos());
             if all_eos {
                 gst::debug!(CAT, imp: self, "All streams are EOS now");
                 Err(gst::FlowError::Eos)
             } else {
-                self.drain(&mut state)
+                self.drain(&mut state, timeout)
             }
         }?;
-        // aggregatorの場合はpushではなくfinish_bufferを使う
-        // このタイミングでaggregatorがstart_streamやsegmentのイベント送信などを行う
-        self.obj().finish_buffer(buffer)
+        match buffer {
+            // aggregatorの場合はpushではなくfinish_bufferを使う
+            // このタイミングでaggregatorがstart_streamやsegmentのイベント送信などを行う
+            Some(buffer) => self.obj().finish_buffer(buffer),
+            None => Ok(gst::FlowSuccess::Ok),
+        }
+    }
+
+    // live時は先頭のvideoのフレームの時刻からlatencyが過ぎたらtimeoutとしてaggregateが呼ばれる
+    // Noneを返すと全てのsinkにバッファが揃うまで待つので、KLVが止まると出力できなくなる
+    fn next_time(&self) -> Option<gst::ClockTime> {
+        let videopad = self
+            .obj()
+            .sink_pads()
+            .into_iter()
+            .map(|pad| pad.downcast::<gst_base::AggregatorPad>().unwrap())
+            .find(|pad| {
+                pad.current_caps()
+                    .map_or(false, |caps| CapsType::from_caps(&caps) == CapsType::Main)
+            })?;
+        match videopad.peek_buffer() {
+            // clipでrunning timeに変換済み
+            Some(buffer) => buffer.pts(),
+            // KLVを待たずにEOSを送る
+            None if videopad.is_eos() => Some(gst::ClockTime::ZERO),
+            None => None,
+        }
     }
 
-    // もしもソース毎にpts等が異なる場合はこれで合わせこみを行う。
-    // 今回の利用ケースでは同じptsなので気にしなくて良いが記録のために残しておく
+    // ソース毎にsegmentが異なってもrunning timeで照合できるようにptsを変換する
     fn clip(
         &self,
         aggregator_pad: &gst_base::AggregatorPad,
@@ -217,35 +965,744 @@ impl AggregatorImpl for MetaMux {
         self.parent_clip(aggregator_pad, buffer)
     }
 
+    // KLV以外のcapsが変わったらsrcを再ネゴシエーションする
+    fn sink_event(&self, aggregator_pad: &gst_base::AggregatorPad, event: gst::Event) -> bool {
+        if let gst::EventView::Caps(c) = event.view() {
+            let caps = c.caps_owned();
+            if CapsType::from_caps(&caps) == CapsType::Main {
+                gst::debug!(CAT, obj: aggregator_pad, "main caps {}", caps);
+                self.state.lock().unwrap().main_caps = Some(caps);
+                self.obj().src_pad().mark_reconfigure();
+            }
+        }
+        self.parent_sink_event(aggregator_pad, event)
+    }
+
+    // flushing seekでは照合中や保留中のKLVを捨てる
+    fn flush(&self) -> Result<gst::FlowSuccess, gst::FlowError> {
+        gst::debug!(CAT, imp: self, "flush");
+        for stream in self.state.lock().unwrap().streams.iter_mut() {
+            stream.reset();
+        }
+        self.parent_flush()
+    }
+
+    fn stop(&self) -> Result<(), gst::ErrorMessage> {
+        *self.state.lock().unwrap() = State::default();
+        *self.klv_interval.lock().unwrap() = None;
+        self.update_latency();
+        self.parent_stop()
+    }
+
     // Aggretatorの場合はSinkからcapsが来てからsrcと再ネゴシエーションする
     // これがなければximagesinkが1x1のデフォルトで再生してしまう
     fn update_src_caps(&self, caps: &gst::Caps) -> Result<gst::Caps, gst::FlowError> {
         gst::debug!(CAT, imp: self, "update_src_caps {:?}", caps);
-        // TODO create streamと重複している処理があるので共通化を検討する
-        for pad in self
-            .obj()
-            .sink_pads()
+        match self.state.lock().unwrap().main_caps.clone() {
+            // 下流がANYならvideoのcapsをそのまま使う
+            Some(main_caps) if caps.is_any() => self.parent_update_src_caps(&main_caps),
+            // videoのcapsの順序を優先して下流が受け取れるものに絞る
+            Some(main_caps) => {
+                let best_caps = main_caps.intersect_with_mode(caps, gst::CapsIntersectMode::First);
+                gst::debug!(CAT, imp: self, "best_caps {:?}", &best_caps);
+                if best_caps.is_empty() {
+                    gst::warning!(CAT, imp: self, "{} is not accepted by downstream", main_caps);
+                    return Err(gst::FlowError::NotNegotiated);
+                }
+                self.parent_update_src_caps(&best_caps)
+            }
+            None => self.parent_update_src_caps(caps),
+        }
+    }
+}
+
+/// KLVのsink pad
+#[derive(Default, Debug)]
+pub struct MetaMuxPad {
+    meta_type: Mutex<MetaType>,
+}
+
+impl ObjectImpl for MetaMuxPad {
+    fn properties() -> &'static [glib::ParamSpec] {
+        static PROPERTIES: Lazy<Vec<glib::ParamSpec>> = Lazy::new(|| {
+            vec![
+                glib::ParamSpecEnum::builder::<MetaType>("meta-type", MetaType::default())
+                    .nick("Meta type")
+                    .blurb("meta to attach klv of this pad")
+                    .mutable_playing()
+                    .build(),
+            ]
+        });
+
+        PROPERTIES.as_ref()
+    }
+
+    fn set_property(&self, _id: usize, value: &glib::Value, pspec: &glib::ParamSpec) {
+        match pspec.name() {
+            "meta-type" => {
+                let x = value.get::<MetaType>().expect("type checked upstream");
+                gst::info!(CAT, imp: self, "set prop meta-type to {:?}", x);
+                *self.meta_type.lock().unwrap() = x;
+            }
+            _ => unimplemented!(),
+        }
+    }
+
+    fn property(&self, _id: usize, pspec: &glib::ParamSpec) -> glib::Value {
+        match pspec.name() {
+            "meta-type" => self.meta_type.lock().unwrap().to_value(),
+            _ => unimplemented!(),
+        }
+    }
+}
+impl GstObjectImpl for MetaMuxPad {}
+impl PadImpl for MetaMuxPad {}
+impl AggregatorPadImpl for MetaMuxPad {}
+
+#[glib::object_subclass]
+impl ObjectSubclass for MetaMuxPad {
+    const NAME: &'static str = "MetaMuxPad";
+    type Type = super::MetaMuxPad;
+    type ParentType = gst_base::AggregatorPad;
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::metaklv::UAS_DATALINK_LS_KEY;
+    use crate::{test_has_elements as has_elements, test_run as run};
+
+    fn ms(v: u64) -> gst::ClockTime {
+        gst::ClockTime::from_mseconds(v)
+    }
+
+    #[test]
+    fn test_take_matched_reordered() {
+        // 10fpsのKLVとB-frameを含むdecode順のフレーム
+        let mut pending = (1..=5).map(|i| (ms(i * 100), i)).collect::<VecDeque<_>>();
+        let frames = [(100, 0), (400, 100), (200, 200), (300, 300), (500, 400)];
+        let matched = frames
+            .iter()
+            .map(|(pts, dts)| {
+                let window = FrameWindow {
+                    pts: ms(*pts),
+                    dts: Some(ms(*dts)),
+                    duration: Some(ms(100)),
+                    tolerance: ms(1),
+                };
+                take_matched(&mut pending, &window)
+                    .into_iter()
+                    .map(|(_, i)| i)
+                    .collect::<Vec<_>>()
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(matched, vec![vec![1], vec![4], vec![2], vec![3], vec![5]]);
+        assert!(pending.is_empty());
+    }
+
+    #[test]
+    fn test_take_matched_drop_unmatched() {
+        // どのフレームにも照合されないKLVはdtsより前になったら捨てる
+        let mut pending = [(ms(50), 0), (ms(100), 1)]
             .into_iter()
-            .map(|pad| pad.downcast::<gst_base::AggregatorPad>().unwrap())
-        {
-            let mut video_caps = match pad.current_caps() {
-                Some(caps) => caps,
-                None => {
-                    gst::warning!(CAT, obj: pad, "Skipping pad without caps");
-                    continue;
+            .collect::<VecDeque<_>>();
+        let window = FrameWindow {
+            pts: ms(100),
+            dts: None,
+            duration: None,
+            tolerance: ms(1),
+        };
+        assert_eq!(take_matched(&mut pending, &window), vec![(ms(100), 1)]);
+        assert!(pending.is_empty());
+    }
+
+    type Received = Arc<Mutex<Vec<(i32, bool)>>>;
+
+    // fakesinkに届いたフレームの幅とExampleRsMetaの有無を記録する
+    fn receive(sink: &gst::Element) -> Received {
+        let received: Received = Arc::default();
+        let r = received.clone();
+        sink.connect("handoff", false, move |args| {
+            let buffer = args[1].get::<gst::Buffer>().unwrap();
+            let width = args[2]
+                .get::<gst::Pad>()
+                .unwrap()
+                .current_caps()
+                .and_then(|caps| caps.structure(0)?.get::<i32>("width").ok())
+                .unwrap_or(0);
+            r.lock()
+                .unwrap()
+                .push((width, buffer.meta::<ers_meta::ExampleRsMeta>().is_some()));
+            None
+        });
+        received
+    }
+
+    // videoとKLVのframerateを変えて照合し、各フレームに付与されたExampleRsMetaのindexを返す
+    fn matched_indices(video: (i32, u32), klv: (i32, u32), props: &str) -> Vec<Vec<i32>> {
+        let pipeline = gst::parse_launch(&format!(
+            "videotestsrc num-buffers={} ! video/x-raw,framerate={}/1 \
+             ! metamux name=m {} ! fakesink name=sink signal-handoffs=true \
+             klvtestsrc name=klv num-buffers={} fps={}/1",
+            video.1, video.0, props, klv.1, klv.0
+        ))
+        .unwrap()
+        .downcast::<gst::Pipeline>()
+        .unwrap();
+        let sinkpad = pipeline
+            .by_name("m")
+            .unwrap()
+            .request_pad_simple("sink_%u")
+            .unwrap();
+        pipeline
+            .by_name("klv")
+            .unwrap()
+            .static_pad("src")
+            .unwrap()
+            .link(&sinkpad)
+            .unwrap();
+
+        let received: Arc<Mutex<Vec<Vec<i32>>>> = Arc::default();
+        let r = received.clone();
+        pipeline
+            .by_name("sink")
+            .unwrap()
+            .connect("handoff", false, move |args| {
+                let buffer = args[1].get::<gst::Buffer>().unwrap();
+                let mut indices = buffer
+                    .iter_meta::<ers_meta::ExampleRsMeta>()
+                    .map(|meta| meta.index())
+                    .collect::<Vec<_>>();
+                indices.sort_unstable();
+                r.lock().unwrap().push(indices);
+                None
+            });
+        pipeline.set_state(gst::State::Playing).unwrap();
+        run(&pipeline, gst::MessageType::Eos);
+        pipeline.set_state(gst::State::Null).unwrap();
+        let received = received.lock().unwrap().clone();
+        received
+    }
+
+    #[test]
+    fn test_no_match_policy() {
+        if !has_elements(&["videotestsrc"]) {
+            return;
+        }
+        // 30fpsのvideoに10fpsのKLVを照合すると3フレームに1つだけ一致する
+        let each = |indices: [i32; 9]| indices.map(|i| vec![i]).to_vec();
+        let none = vec![
+            vec![0],
+            vec![],
+            vec![],
+            vec![1],
+            vec![],
+            vec![],
+            vec![2],
+            vec![],
+            vec![],
+        ];
+        let cases = [
+            ("none", none),
+            ("hold-last", each([0, 0, 0, 1, 1, 1, 2, 2, 2])),
+            // 100msのフレーム間では前後のKLVのうち近い方を付与する
+            ("nearest", each([0, 0, 1, 1, 1, 2, 2, 2, 2])),
+        ];
+        for (policy, expected) in cases {
+            let received = matched_indices((30, 9), (10, 3), &format!("no-match={}", policy));
+            assert_eq!(received, expected, "no-match={}", policy);
+        }
+    }
+
+    #[test]
+    fn test_extra_meta_policy() {
+        if !has_elements(&["videotestsrc"]) {
+            return;
+        }
+        // 10fpsのvideoに30fpsのKLVをtolerance 50msで照合すると1フレームに複数が一致する
+        // 最後のKLVはどのフレームにも照合されない
+        let tolerance = gst::ClockTime::from_mseconds(50).nseconds();
+        let cases = [
+            ("drop", vec![vec![0], vec![3], vec![6]]),
+            ("merge", vec![vec![0, 1], vec![2, 3, 4], vec![5, 6, 7]]),
+        ];
+        for (policy, expected) in cases {
+            let props = format!("tolerance={} extra-meta={}", tolerance, policy);
+            let received = matched_indices((10, 3), (30, 9), &props);
+            assert_eq!(received, expected, "extra-meta={}", policy);
+        }
+    }
+
+    // EOSかErrorまで実行し、終了したメッセージの種類と途中のwarningのdetailsを返す
+    fn run_warnings(pipeline: &gst::Pipeline) -> (gst::MessageType, Vec<gst::Structure>) {
+        let bus = pipeline.bus().unwrap();
+        let mut warnings = vec![];
+        loop {
+            let msg = bus
+                .timed_pop_filtered(
+                    gst::ClockTime::from_seconds(10),
+                    &[
+                        gst::MessageType::Eos,
+                        gst::MessageType::Error,
+                        gst::MessageType::Warning,
+                    ],
+                )
+                .expect("timeout");
+            match msg.view() {
+                gst::MessageView::Warning(w) => {
+                    warnings.extend(w.details().map(|details| details.to_owned()))
                 }
-            };
-            if video_caps
-                .structure(0)
+                _ => return (msg.type_(), warnings),
+            }
+        }
+    }
+
+    #[test]
+    fn test_malformed_klv() {
+        if !has_elements(&["videotestsrc"]) {
+            return;
+        }
+        type Frame = (Option<String>, Option<i64>);
+        let label = || Some("KlvTestSrcLabel".to_string());
+        let cases: [(&str, gst::MessageType, Vec<Frame>); 3] = [
+            (
+                "drop",
+                gst::MessageType::Eos,
+                vec![(label(), Some(0)), (label(), Some(2))],
+            ),
+            // 壊れたKLVのstreamのmetaだけを付与しない
+            (
+                "pass",
+                gst::MessageType::Eos,
+                vec![(label(), Some(0)), (None, Some(1)), (label(), Some(2))],
+            ),
+            ("error", gst::MessageType::Error, vec![(label(), Some(0))]),
+        ];
+        for (policy, until, expected) in cases {
+            // 機体のKLVの2つ目だけが壊れている
+            let pipeline = gst::parse_launch(&format!(
+                "videotestsrc num-buffers=3 ! video/x-raw,framerate=10/1 \
+                 ! metamux name=m error-policy={} ! fakesink name=sink signal-handoffs=true \
+                 klvtestsrc name=platform num-buffers=3 fps=10/1 dataset=uas-datalink \
+                 klvtestsrc name=sensor num-buffers=3 fps=10/1 dataset=example",
+                policy
+            ))
+            .unwrap()
+            .downcast::<gst::Pipeline>()
+            .unwrap();
+            let mux = pipeline.by_name("m").unwrap();
+            for (name, meta_type) in [("platform", MetaType::Rs), ("sensor", MetaType::C)] {
+                let sinkpad = mux.request_pad_simple("sink_%u").unwrap();
+                sinkpad.set_property("meta-type", meta_type);
+                let src = pipeline.by_name(name).unwrap().static_pad("src").unwrap();
+                src.link(&sinkpad).unwrap();
+            }
+            let count = std::sync::atomic::AtomicUsize::new(0);
+            pipeline
+                .by_name("platform")
                 .unwrap()
-                .name()
-                .starts_with("video/x-raw")
-            {
-                video_caps.merge(caps.clone());
-                gst::debug!(CAT, imp: self, "best_caps {:?}", &video_caps);
-                return self.parent_update_src_caps(&video_caps);
-            };
+                .static_pad("src")
+                .unwrap()
+                .add_probe(gst::PadProbeType::BUFFER, move |_, info| {
+                    if count.fetch_add(1, Ordering::SeqCst) == 1 {
+                        if let Some(gst::PadProbeData::Buffer(ref mut buffer)) = info.data {
+                            // 途中で切れたパケットにする
+                            let mut corrupt =
+                                gst::Buffer::from_slice(UAS_DATALINK_LS_KEY[..8].to_vec());
+                            {
+                                let corrupt = corrupt.get_mut().unwrap();
+                                corrupt.set_pts(buffer.pts());
+                                corrupt.set_duration(buffer.duration());
+                            }
+                            *buffer = corrupt;
+                        }
+                    }
+                    gst::PadProbeReturn::Ok
+                });
+
+            let received: Arc<Mutex<Vec<Frame>>> = Arc::default();
+            let r = received.clone();
+            pipeline
+                .by_name("sink")
+                .unwrap()
+                .connect("handoff", false, move |args| {
+                    let buffer = args[1].get::<gst::Buffer>().unwrap();
+                    r.lock().unwrap().push((
+                        buffer
+                            .meta::<ers_meta::ExampleRsMeta>()
+                            .map(|meta| meta.label().to_string()),
+                        buffer
+                            .meta::<ec_meta::ExampleCMeta>()
+                            .map(|meta| meta.count()),
+                    ));
+                    None
+                });
+            pipeline.set_state(gst::State::Playing).unwrap();
+            let (msg_type, warnings) = run_warnings(&pipeline);
+            pipeline.set_state(gst::State::Null).unwrap();
+
+            assert_eq!(msg_type, until, "error-policy={}", policy);
+            assert_eq!(
+                *received.lock().unwrap(),
+                expected,
+                "error-policy={}",
+                policy
+            );
+            assert_eq!(mux.property::<u64>("malformed-packets"), 1);
+            // 壊れたパケットのバイト列がwarningに添付される
+            assert_eq!(warnings.len(), 1);
+            assert_eq!(warnings[0].name(), "malformed-klv");
+            let data = warnings[0].get::<glib::Bytes>("data").unwrap();
+            assert_eq!(&data[..], &UAS_DATALINK_LS_KEY[..8]);
+        }
+    }
+
+    #[test]
+    fn test_nul_label() {
+        if !has_elements(&["videotestsrc"]) {
+            return;
         }
-        self.parent_update_src_caps(caps)
+        // KLVとしては正しいがExampleCMetaのlabelにできないパケット
+        let packet = KlvDataset::ExampleC(crate::metaklv::ExampleCDataset::new(
+            "a\0b".to_string(),
+            1,
+            0.0,
+        ))
+        .to_bytes()
+        .unwrap();
+        let cases = [
+            ("drop", gst::MessageType::Eos, vec![Some(0), Some(2)]),
+            ("pass", gst::MessageType::Eos, vec![Some(0), None, Some(2)]),
+            ("error", gst::MessageType::Error, vec![Some(0)]),
+        ];
+        for (policy, until, expected) in cases {
+            let pipeline = gst::parse_launch(&format!(
+                "videotestsrc num-buffers=3 ! video/x-raw,framerate=10/1 \
+                 ! metamux name=m error-policy={} ! fakesink name=sink signal-handoffs=true \
+                 klvtestsrc name=klv num-buffers=3 fps=10/1 dataset=example-c ! m.",
+                policy
+            ))
+            .unwrap()
+            .downcast::<gst::Pipeline>()
+            .unwrap();
+            let count = std::sync::atomic::AtomicUsize::new(0);
+            let p = packet.clone();
+            pipeline
+                .by_name("klv")
+                .unwrap()
+                .static_pad("src")
+                .unwrap()
+                .add_probe(gst::PadProbeType::BUFFER, move |_, info| {
+                    if count.fetch_add(1, Ordering::SeqCst) == 1 {
+                        if let Some(gst::PadProbeData::Buffer(ref mut buffer)) = info.data {
+                            let mut replaced = gst::Buffer::from_slice(p.clone());
+                            {
+                                let replaced = replaced.get_mut().unwrap();
+                                replaced.set_pts(buffer.pts());
+                                replaced.set_duration(buffer.duration());
+                            }
+                            *buffer = replaced;
+                        }
+                    }
+                    gst::PadProbeReturn::Ok
+                });
+            let received: Arc<Mutex<Vec<Option<i64>>>> = Arc::default();
+            let r = received.clone();
+            pipeline
+                .by_name("sink")
+                .unwrap()
+                .connect("handoff", false, move |args| {
+                    let buffer = args[1].get::<gst::Buffer>().unwrap();
+                    r.lock().unwrap().push(
+                        buffer
+                            .meta::<ec_meta::ExampleCMeta>()
+                            .map(|meta| meta.count()),
+                    );
+                    None
+                });
+            pipeline.set_state(gst::State::Playing).unwrap();
+            let (msg_type, warnings) = run_warnings(&pipeline);
+            pipeline.set_state(gst::State::Null).unwrap();
+
+            // panicせずにerror-policyに従う
+            assert_eq!(msg_type, until, "error-policy={}", policy);
+            assert_eq!(
+                *received.lock().unwrap(),
+                expected,
+                "error-policy={}",
+                policy
+            );
+            let mux = pipeline.by_name("m").unwrap();
+            assert_eq!(mux.property::<u64>("malformed-packets"), 1);
+            assert_eq!(warnings.len(), 1);
+            let data = warnings[0].get::<glib::Bytes>("data").unwrap();
+            assert_eq!(&data[..], &packet[..]);
+        }
+    }
+
+    #[test]
+    fn test_caps_change() {
+        if !has_elements(&["videotestsrc"]) {
+            return;
+        }
+        let pipeline = gst::parse_launch(
+            "videotestsrc num-buffers=10 ! capsfilter name=cf caps=video/x-raw,width=32,height=32 \
+             ! metatrans op=add ! metademux name=d static-meta-pads=true decouple=true extract=rs \
+             ! metamux name=m ! fakesink name=sink signal-handoffs=true",
+        )
+        .unwrap()
+        .downcast::<gst::Pipeline>()
+        .unwrap();
+        // READYに戻るとmetademuxのklv padは作り直されるので毎回繋ぐ
+        let sinkpad = pipeline
+            .by_name("m")
+            .unwrap()
+            .request_pad_simple("sink_%u")
+            .unwrap();
+        pipeline
+            .by_name("d")
+            .unwrap()
+            .connect_pad_added(move |_, pad| {
+                if pad.name() == "meta_rs" {
+                    pad.link(&sinkpad).unwrap();
+                }
+            });
+        let received = receive(&pipeline.by_name("sink").unwrap());
+        // 途中で解像度を変える
+        let cf = pipeline.by_name("cf").unwrap();
+        let r = received.clone();
+        pipeline
+            .by_name("sink")
+            .unwrap()
+            .connect("handoff", false, move |_| {
+                if r.lock().unwrap().len() == 3 {
+                    let caps = gst::Caps::builder("video/x-raw")
+                        .field("width", 16)
+                        .field("height", 16)
+                        .build();
+                    cf.set_property("caps", &caps);
+                }
+                None
+            });
+
+        pipeline.set_state(gst::State::Playing).unwrap();
+        run(&pipeline, gst::MessageType::Eos);
+        {
+            let received = received.lock().unwrap();
+            assert_eq!(received.len(), 10);
+            assert_eq!(received.first(), Some(&(32, true)));
+            assert_eq!(received.last(), Some(&(16, true)));
+            assert!(received.iter().all(|(_, meta)| *meta));
+        }
+
+        // READYを経由しても前回の状態を引き継がない
+        pipeline.set_state(gst::State::Ready).unwrap();
+        received.lock().unwrap().clear();
+        pipeline.set_state(gst::State::Playing).unwrap();
+        run(&pipeline, gst::MessageType::Eos);
+        pipeline.set_state(gst::State::Null).unwrap();
+        assert_eq!(*received.lock().unwrap(), vec![(16, true); 10]);
+    }
+
+    #[test]
+    fn test_live_timeout() {
+        if !has_elements(&["videotestsrc"]) {
+            return;
+        }
+        let pipeline = gst::parse_launch(
+            "videotestsrc is-live=true num-buffers=15 ! video/x-raw,framerate=30/1 \
+             ! metamux name=m no-match=none timeout=hold-last \
+             ! fakesink name=sink sync=false signal-handoffs=true \
+             klvtestsrc name=klv is-live=true fps=10/1",
+        )
+        .unwrap()
+        .downcast::<gst::Pipeline>()
+        .unwrap();
+        let mux = pipeline.by_name("m").unwrap();
+        let sinkpad = mux.request_pad_simple("sink_%u").unwrap();
+        let srcpad = pipeline.by_name("klv").unwrap().static_pad("src").unwrap();
+        srcpad.link(&sinkpad).unwrap();
+        // 3つ目以降のKLVを捨てて、EOSにならないまま止まったsourceにする
+        let count = std::sync::atomic::AtomicUsize::new(0);
+        srcpad.add_probe(gst::PadProbeType::BUFFER, move |_, _| {
+            if count.fetch_add(1, Ordering::SeqCst) < 3 {
+                gst::PadProbeReturn::Ok
+            } else {
+                gst::PadProbeReturn::Drop
+            }
+        });
+
+        let received: Arc<Mutex<Vec<Vec<i32>>>> = Arc::default();
+        let r = received.clone();
+        pipeline
+            .by_name("sink")
+            .unwrap()
+            .connect("handoff", false, move |args| {
+                let buffer = args[1].get::<gst::Buffer>().unwrap();
+                r.lock().unwrap().push(
+                    buffer
+                        .iter_meta::<ers_meta::ExampleRsMeta>()
+                        .map(|meta| meta.index())
+                        .collect(),
+                );
+                None
+            });
+        pipeline.set_state(gst::State::Playing).unwrap();
+        run(&pipeline, gst::MessageType::Eos);
+
+        // 10fpsのKLVの間隔だけ待つことをlatencyとして報告する
+        let mut q = gst::query::Latency::new();
+        assert!(mux.static_pad("src").unwrap().query(&mut q));
+        let (live, min, _) = q.result();
+        assert!(live);
+        assert!(min >= ms(101), "latency {}", min);
+        pipeline.set_state(gst::State::Null).unwrap();
+
+        // KLVの間のフレームはtimeoutせずにno-matchとして扱われる
+        // 止まった後のフレームはtimeoutとして最後のKLVを付与する
+        let mut expected = vec![vec![0], vec![], vec![], vec![1], vec![], vec![], vec![2]];
+        expected.resize(15, vec![2]);
+        assert_eq!(*received.lock().unwrap(), expected);
+    }
+
+    type Frames = Arc<(Mutex<Vec<bool>>, std::sync::Condvar)>;
+
+    // 届いたフレームが条件を満たすまで待ち、その時点のフレーム数を返す
+    fn wait_frames(frames: &Frames, cond: impl Fn(&[bool]) -> bool) -> usize {
+        let (lock, cvar) = &**frames;
+        let (received, res) = cvar
+            .wait_timeout_while(
+                lock.lock().unwrap(),
+                std::time::Duration::from_secs(10),
+                |received| !cond(received),
+            )
+            .unwrap();
+        assert!(!res.timed_out(), "timeout {:?}", *received);
+        received.len()
+    }
+
+    #[test]
+    fn test_request_release_pad() {
+        if !has_elements(&["videotestsrc"]) {
+            return;
+        }
+        let pipeline = gst::parse_launch(
+            "videotestsrc is-live=true num-buffers=45 ! video/x-raw,framerate=30/1 \
+             ! metamux name=m no-match=hold-last timeout=hold-last ! fakesink name=sink sync=false signal-handoffs=true",
+        )
+        .unwrap()
+        .downcast::<gst::Pipeline>()
+        .unwrap();
+        let frames: Frames = Arc::default();
+        let f = frames.clone();
+        pipeline
+            .by_name("sink")
+            .unwrap()
+            .connect("handoff", false, move |args| {
+                let buffer = args[1].get::<gst::Buffer>().unwrap();
+                let (lock, cvar) = &*f;
+                lock.lock()
+                    .unwrap()
+                    .push(buffer.meta::<ers_meta::ExampleRsMeta>().is_some());
+                cvar.notify_all();
+                None
+            });
+        let mux = pipeline.by_name("m").unwrap();
+        pipeline.set_state(gst::State::Playing).unwrap();
+        let before = wait_frames(&frames, |received| received.len() >= 3);
+
+        // 再生中にKLVのsourceを追加する
+        let src = gst::ElementFactory::make("klvtestsrc")
+            .property("is-live", true)
+            .build()
+            .unwrap();
+        pipeline.add(&src).unwrap();
+        let sinkpad = mux.request_pad_simple("sink_%u").unwrap();
+        src.static_pad("src").unwrap().link(&sinkpad).unwrap();
+        src.sync_state_with_parent().unwrap();
+        // KLVが付与され始めてから数フレーム進める
+        wait_frames(&frames, |received| {
+            received
+                .iter()
+                .position(|meta| *meta)
+                .map_or(false, |first| received.len() >= first + 3)
+        });
+
+        // 止めてからpadを解放する。以降のフレームはKLVを待たない
+        src.set_state(gst::State::Null).unwrap();
+        src.static_pad("src").unwrap().unlink(&sinkpad).unwrap();
+        mux.release_request_pad(&sinkpad);
+        pipeline.remove(&src).unwrap();
+        assert_eq!(
+            mux.dynamic_cast_ref::<gst::ChildProxy>()
+                .unwrap()
+                .children_count(),
+            1
+        );
+
+        run(&pipeline, gst::MessageType::Eos);
+        pipeline.set_state(gst::State::Null).unwrap();
+        let received = frames.0.lock().unwrap();
+        assert_eq!(received.len(), 45);
+        // 追加する前のフレームにはmetaがなく、解放した後はmetaのないフレームだけが続く
+        assert!(received[..before].iter().all(|meta| !*meta));
+        let first = received.iter().position(|meta| *meta).unwrap();
+        let end = first + received[first..].iter().position(|meta| !*meta).unwrap();
+        assert!(received[end..].iter().all(|meta| !*meta));
+    }
+
+    #[test]
+    fn test_multiple_klv_pads() {
+        if !has_elements(&["videotestsrc"]) {
+            return;
+        }
+        // 機体とセンサのKLVを別のmetaとして同じフレームに付与する
+        let pipeline = gst::parse_launch(
+            "videotestsrc num-buffers=10 ! video/x-raw,framerate=10/1 \
+             ! metamux name=m ! fakesink name=sink signal-handoffs=true \
+             klvtestsrc name=platform num-buffers=10 fps=10/1 dataset=uas-datalink \
+             klvtestsrc name=sensor num-buffers=10 fps=10/1 dataset=example",
+        )
+        .unwrap()
+        .downcast::<gst::Pipeline>()
+        .unwrap();
+        let mux = pipeline.by_name("m").unwrap();
+        for (name, meta_type) in [("platform", MetaType::Rs), ("sensor", MetaType::C)] {
+            let sinkpad = mux.request_pad_simple("sink_%u").unwrap();
+            sinkpad.set_property("meta-type", meta_type);
+            let src = pipeline.by_name(name).unwrap().static_pad("src").unwrap();
+            src.link(&sinkpad).unwrap();
+        }
+        let child_proxy = mux.dynamic_cast_ref::<gst::ChildProxy>().unwrap();
+        assert_eq!(child_proxy.children_count(), 3);
+        assert!(child_proxy.child_by_name("sink_2").is_some());
+
+        let received: Arc<Mutex<Vec<(Option<String>, Option<i64>)>>> = Arc::default();
+        let r = received.clone();
+        pipeline
+            .by_name("sink")
+            .unwrap()
+            .connect("handoff", false, move |args| {
+                let buffer = args[1].get::<gst::Buffer>().unwrap();
+                r.lock().unwrap().push((
+                    buffer
+                        .meta::<ers_meta::ExampleRsMeta>()
+                        .map(|meta| meta.label().to_string()),
+                    buffer
+                        .meta::<ec_meta::ExampleCMeta>()
+                        .map(|meta| meta.count()),
+                ));
+                None
+            });
+        pipeline.set_state(gst::State::Playing).unwrap();
+        run(&pipeline, gst::MessageType::Eos);
+        pipeline.set_state(gst::State::Null).unwrap();
+
+        let expected = (0..10)
+            .map(|i| (Some("KlvTestSrcLabel".to_string()), Some(i)))
+            .collect::<Vec<_>>();
+        assert_eq!(*received.lock().unwrap(), expected);
     }
 }
@@ -9,7 +9,12 @@ const CLASS_NAME: &str = "MetaMux";
 mod imp;
 
 gst::glib::wrapper! {
-    pub struct MetaMux(ObjectSubclass<imp::MetaMux>) @extends gst_base::Aggregator, gst::Element, gst::Object;
+    pub struct MetaMux(ObjectSubclass<imp::MetaMux>) @extends gst_base::Aggregator, gst::Element, gst::Object, @implements gst::ChildProxy;
+}
+
+// KLVを付与するmetaの種類をpadごとに指定するためのpad
+gst::glib::wrapper! {
+    pub struct MetaMuxPad(ObjectSubclass<imp::MetaMuxPad>) @extends gst_base::AggregatorPad, gst::Pad, gst::Object;
 }
 
 pub fn register(plugin: &gst::Plugin) -> Result<(), glib::BoolError> {
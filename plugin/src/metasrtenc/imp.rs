@@ -0,0 +1,589 @@
+//! MetaSrtEnc
+//!
+//! Videoに付与されたExampleRsMetaもしくはmeta/x-klvのストリームをSRT字幕に変換する
+//! 一般的なプレイヤーで録画を開いた時にテレメトリを字幕として確認するため
+use std::sync::Mutex;
+
+use ers_meta::{ExampleRsMeta, ExampleRsMetaParams};
+use gst::prelude::{ElementClassExt, ParamSpecBuilderExt, ToValue};
+use gst::subclass::prelude::{
+    ElementImpl, ElementImplExt, GstObjectImpl, ObjectImpl, ObjectImplExt, ObjectSubclass,
+    ObjectSubclassExt,
+};
+use gst::traits::{ElementExt, PadExt};
+use gst::{glib, Caps, ClockTime, EventView};
+use once_cell::sync::Lazy;
+
+use crate::metaklv::{ExampleCDataset, ExampleDataset, KlvDataset, UasDatalinkLS};
+
+use super::CLASS_NAME;
+use super::ELEMENT_NAME;
+
+static CAT: Lazy<gst::DebugCategory> = Lazy::new(|| {
+    gst::DebugCategory::new(
+        ELEMENT_NAME,
+        gst::DebugColorFlags::empty(),
+        Some(CLASS_NAME),
+    )
+});
+
+static SRT_CAPS: Lazy<Caps> = Lazy::new(|| gst::Caps::builder("application/x-subtitle").build());
+
+const DEFAULT_TEMPLATE: &str = "{label}";
+// durationが分からないままEOSになったcueの表示時間
+const LAST_CUE_DURATION: ClockTime = ClockTime::SECOND;
+
+// sinkに流れてくるのがVideoかKLVかをcapsで識別する
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+enum InputType {
+    Video,
+    Klv,
+}
+
+#[derive(Debug)]
+struct Settings {
+    template: String,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            template: DEFAULT_TEMPLATE.to_string(),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Cue {
+    pts: ClockTime,
+    // SRTに書き込む時刻はstream time
+    start: ClockTime,
+    text: String,
+}
+
+#[derive(Default)]
+struct State {
+    input: Option<InputType>,
+    segment: gst::FormattedSegment<ClockTime>,
+    // SRTのcue番号は1から始まる
+    index: u64,
+    // durationを持たないcueは次のバッファのptsで終了する
+    pending: Option<Cue>,
+}
+
+impl State {
+    fn finish(&mut self, cue: Cue, end: ClockTime) -> gst::Buffer {
+        self.index += 1;
+        let end = end.max(cue.start);
+        let record = format!(
+            "{}\n{} --> {}\n{}\n\n",
+            self.index,
+            srt_time(cue.start),
+            srt_time(end),
+            cue.text
+        );
+        let mut buffer = gst::Buffer::from_mut_slice(record.into_bytes());
+        {
+            let buffer = buffer.get_mut().unwrap();
+            buffer.set_pts(cue.pts);
+            buffer.set_duration(end - cue.start);
+            buffer.set_offset(self.index);
+        }
+        buffer
+    }
+}
+
+/// SRTの時刻表現 `HH:MM:SS,mmm`
+fn srt_time(t: ClockTime) -> String {
+    let ms = t.mseconds();
+    format!(
+        "{:02}:{:02}:{:02},{:03}",
+        ms / 3_600_000,
+        ms / 60_000 % 60,
+        ms / 1000 % 60,
+        ms % 1000
+    )
+}
+
+/// templateの`{name}`をフィールドの値で置き換える
+///
+/// 存在しないフィールドはそのまま残す。gst-launchから改行を指定できるように`\n`を改行として扱う
+fn render(template: &str, fields: &[(String, String)]) -> String {
+    let template = template.replace("\\n", "\n");
+    let mut out = String::with_capacity(template.len());
+    let mut rest = template.as_str();
+    while let Some(start) = rest.find('{') {
+        out.push_str(&rest[..start]);
+        let Some(end) = rest[start..].find('}') else {
+            rest = &rest[start..];
+            break;
+        };
+        let name = &rest[start + 1..start + end];
+        match fields.iter().find(|(k, _)| k == name) {
+            Some((_, v)) => out.push_str(v),
+            None => out.push_str(&rest[start..=start + end]),
+        }
+        rest = &rest[start + end + 1..];
+    }
+    out.push_str(rest);
+    out
+}
+
+fn fields_from_params(params: &ExampleRsMetaParams) -> Vec<(String, String)> {
+    let region = params
+        .region
+        .map(|r| format!("{},{},{},{}", r.x, r.y, r.width, r.height))
+        .unwrap_or_default();
+    vec![
+        ("label".to_string(), params.label.clone()),
+        ("index".to_string(), params.index.to_string()),
+        ("mode".to_string(), format!("{:?}", params.mode)),
+        ("region".to_string(), region),
+    ]
+}
+
+// 飛行の状態があればUAS Datalinkと同じ名前で追加する
+fn fields_from_example(ds: ExampleDataset) -> Vec<(String, String)> {
+    let flight = ds.flight();
+    let mut fields = fields_from_params(&ds.into());
+    if let Some(f) = flight {
+        fields.extend([
+            ("timestamp".to_string(), f.timestamp.to_string()),
+            ("latitude".to_string(), format!("{:.6}", f.latitude)),
+            ("longitude".to_string(), format!("{:.6}", f.longitude)),
+            ("altitude".to_string(), format!("{:.1}", f.altitude)),
+            ("heading".to_string(), format!("{:.2}", f.heading)),
+            ("speed".to_string(), format!("{:.1}", f.speed)),
+            (
+                "sensor_azimuth".to_string(),
+                format!("{:.2}", f.sensor_azimuth),
+            ),
+            (
+                "sensor_elevation".to_string(),
+                format!("{:.2}", f.sensor_elevation),
+            ),
+        ]);
+    }
+    fields
+}
+
+fn fields_from_uas(ds: &UasDatalinkLS) -> Vec<(String, String)> {
+    let f = |v: Option<f64>, precision: usize| {
+        v.map(|v| format!("{:.*}", precision, v))
+            .unwrap_or_default()
+    };
+    vec![
+        ("timestamp".to_string(), ds.timestamp.to_string()),
+        (
+            "mission_id".to_string(),
+            ds.mission_id.clone().unwrap_or_default(),
+        ),
+        ("latitude".to_string(), f(ds.latitude(), 6)),
+        ("longitude".to_string(), f(ds.longitude(), 6)),
+        ("altitude".to_string(), f(ds.altitude(), 1)),
+        ("heading".to_string(), f(ds.heading(), 2)),
+        ("pitch".to_string(), f(ds.pitch(), 2)),
+        ("roll".to_string(), f(ds.roll(), 2)),
+    ]
+}
+
+fn fields_from_example_c(ds: &ExampleCDataset) -> Vec<(String, String)> {
+    vec![
+        ("label".to_string(), ds.label().to_string()),
+        ("count".to_string(), ds.count().to_string()),
+        ("num".to_string(), ds.num().to_string()),
+    ]
+}
+
+fn fields_from_structure(s: &gst::StructureRef) -> Vec<(String, String)> {
+    use gst::prelude::GstValueExt;
+    s.iter()
+        .map(|(name, value)| {
+            // 文字列はクォートせずにそのまま表示する
+            let v = match value.get::<String>() {
+                Ok(v) => v,
+                Err(_) => value.serialize().map(|v| v.to_string()).unwrap_or_default(),
+            };
+            (name.to_string(), v)
+        })
+        .collect()
+}
+
+pub struct MetaSrtEnc {
+    sinkpad: gst::Pad,
+    srcpad: gst::Pad,
+    state: Mutex<State>,
+    settings: Mutex<Settings>,
+}
+
+impl MetaSrtEnc {
+    fn sink_chain(
+        &self,
+        pad: &gst::Pad,
+        buffer: gst::Buffer,
+    ) -> Result<gst::FlowSuccess, gst::FlowError> {
+        gst::trace!(CAT, obj: pad, "Handling buffer {:?}", buffer);
+        let pts = match buffer.pts() {
+            Some(pts) => pts,
+            None => {
+                gst::debug!(CAT, obj: pad, "skip buffer without pts");
+                return Ok(gst::FlowSuccess::Ok);
+            }
+        };
+        let input = self.state.lock().unwrap().input;
+        let fields = match input {
+            Some(InputType::Video) => buffer
+                .meta::<ExampleRsMeta>()
+                .map(|meta| fields_from_params(&meta.to_params())),
+            Some(InputType::Klv) => self.klv_fields(&buffer),
+            None => return Err(gst::FlowError::NotNegotiated),
+        };
+        let text = fields.map(|mut fields| {
+            fields.push(("pts".to_string(), pts.to_string()));
+            render(&self.settings.lock().unwrap().template, &fields)
+        });
+
+        let mut outbufs = vec![];
+        {
+            let mut state = self.state.lock().unwrap();
+            let start = state.segment.to_stream_time(pts).unwrap_or(pts);
+            // 前のcueはこのバッファの時刻で終了する
+            if let Some(cue) = state.pending.take() {
+                outbufs.push(state.finish(cue, start));
+            }
+            if let Some(text) = text {
+                let cue = Cue { pts, start, text };
+                match buffer.duration() {
+                    Some(duration) => outbufs.push(state.finish(cue, start + duration)),
+                    None => state.pending = Some(cue),
+                }
+            }
+        }
+        for outbuf in outbufs {
+            self.srcpad.push(outbuf)?;
+        }
+        Ok(gst::FlowSuccess::Ok)
+    }
+
+    fn klv_fields(&self, buffer: &gst::BufferRef) -> Option<Vec<(String, String)>> {
+        let b = buffer.map_readable().ok()?;
+        match KlvDataset::from_bytes(b.as_slice()) {
+            Ok(KlvDataset::Example(ds)) => Some(fields_from_example(ds)),
+            Ok(KlvDataset::UasDatalink(ds)) => Some(fields_from_uas(&ds)),
+            Ok(KlvDataset::ExampleC(ds)) => Some(fields_from_example_c(&ds)),
+            Ok(KlvDataset::Custom(ds)) => match ds.structure() {
+                Ok(s) => Some(fields_from_structure(&s)),
+                Err(e) => {
+                    gst::warning!(CAT, imp: self, "failed to parse custom dataset: {}", e);
+                    None
+                }
+            },
+            Err(e) => {
+                gst::warning!(CAT, imp: self, "failed to decode klv: {}", e);
+                None
+            }
+        }
+    }
+
+    fn sink_event(&self, pad: &gst::Pad, event: gst::Event) -> bool {
+        gst::trace!(CAT, obj: pad, "Handling event {:?}", event);
+        match event.view() {
+            // 入力の種類を記録し、srcには字幕のcapsを流す
+            EventView::Caps(c) => {
+                let input = match c.caps().structure(0).map(|s| s.name()) {
+                    Some("video/x-raw") => InputType::Video,
+                    Some("meta/x-klv") => InputType::Klv,
+                    _ => return false,
+                };
+                gst::debug!(CAT, obj: pad, "input type {:?}", input);
+                self.state.lock().unwrap().input = Some(input);
+                self.srcpad.push_event(gst::event::Caps::new(&SRT_CAPS))
+            }
+            EventView::Segment(seg) => {
+                match seg.segment().downcast_ref::<ClockTime>() {
+                    Some(segment) => self.state.lock().unwrap().segment = segment.clone(),
+                    None => {
+                        gst::element_imp_error!(
+                            self,
+                            gst::StreamError::Format,
+                            ["Only time segments are supported"]
+                        );
+                        return false;
+                    }
+                }
+                self.srcpad.push_event(event)
+            }
+            EventView::FlushStop(..) => {
+                let mut state = self.state.lock().unwrap();
+                state.pending = None;
+                state.segment = gst::FormattedSegment::new();
+                drop(state);
+                self.srcpad.push_event(event)
+            }
+            // 終了時間の決まっていないcueを出力してからEOSを流す
+            EventView::Eos(..) => {
+                let outbuf = {
+                    let mut state = self.state.lock().unwrap();
+                    state.pending.take().map(|cue| {
+                        let end = cue.start + LAST_CUE_DURATION;
+                        state.finish(cue, end)
+                    })
+                };
+                if let Some(outbuf) = outbuf {
+                    if let Err(e) = self.srcpad.push(outbuf) {
+                        gst::debug!(CAT, obj: pad, "failed to push last cue {:?}", e);
+                    }
+                }
+                self.srcpad.push_event(event)
+            }
+            _ => gst::Pad::event_default(pad, Some(&*self.obj()), event),
+        }
+    }
+}
+
+impl ElementImpl for MetaSrtEnc {
+    fn metadata() -> Option<&'static gst::subclass::ElementMetadata> {
+        static ELEMENT_METADATA: Lazy<gst::subclass::ElementMetadata> = Lazy::new(|| {
+            gst::subclass::ElementMetadata::new(
+                CLASS_NAME,
+                "Codec/Encoder/Subtitle",
+                "Encode metadata to SRT subtitle",
+                "FUJINAKA Fumiya <uzuna.kf@gmail.com>",
+            )
+        });
+
+        Some(&*ELEMENT_METADATA)
+    }
+
+    fn pad_templates() -> &'static [gst::PadTemplate] {
+        static PAD_TEMPLATES: Lazy<Vec<gst::PadTemplate>> = Lazy::new(|| {
+            let sink_caps = [
+                gst::Structure::builder("video/x-raw").build(),
+                gst::Structure::builder("meta/x-klv")
+                    .field("parsed", true)
+                    .build(),
+            ]
+            .into_iter()
+            .collect::<gst::Caps>();
+            let sink_pad_template = gst::PadTemplate::new(
+                "sink",
+                gst::PadDirection::Sink,
+                gst::PadPresence::Always,
+                &sink_caps,
+            )
+            .unwrap();
+
+            let src_pad_template = gst::PadTemplate::new(
+                "src",
+                gst::PadDirection::Src,
+                gst::PadPresence::Always,
+                &SRT_CAPS,
+            )
+            .unwrap();
+
+            vec![src_pad_template, sink_pad_template]
+        });
+
+        PAD_TEMPLATES.as_ref()
+    }
+
+    fn change_state(
+        &self,
+        transition: gst::StateChange,
+    ) -> Result<gst::StateChangeSuccess, gst::StateChangeError> {
+        gst::trace!(CAT, imp: self, "Changing state {:?}", transition);
+        if let gst::StateChange::PausedToReady = transition {
+            *self.state.lock().unwrap() = State::default();
+        }
+        self.parent_change_state(transition)
+    }
+}
+
+impl ObjectImpl for MetaSrtEnc {
+    fn properties() -> &'static [glib::ParamSpec] {
+        static PROPERTIES: Lazy<Vec<glib::ParamSpec>> = Lazy::new(|| {
+            vec![glib::ParamSpecString::builder("template")
+                .nick("Template")
+                .blurb("cue text template. {name} is replaced by the field of metadata. e.g. \"{label} lat={latitude}\\nlon={longitude}\"")
+                .default_value(Some(DEFAULT_TEMPLATE))
+                .build()]
+        });
+
+        PROPERTIES.as_ref()
+    }
+
+    fn set_property(&self, _id: usize, value: &glib::Value, pspec: &glib::ParamSpec) {
+        match pspec.name() {
+            "template" => {
+                let x = value
+                    .get::<Option<String>>()
+                    .expect("type checked upstream")
+                    .unwrap_or_default();
+                gst::info!(CAT, imp: self, "set prop template to {:?}", x);
+                let mut settings = self.settings.lock().unwrap();
+                settings.template = x;
+            }
+            _ => unimplemented!(),
+        }
+    }
+
+    fn property(&self, _id: usize, pspec: &glib::ParamSpec) -> glib::Value {
+        match pspec.name() {
+            "template" => {
+                let settings = self.settings.lock().unwrap();
+                settings.template.to_value()
+            }
+            _ => unimplemented!(),
+        }
+    }
+
+    fn constructed(&self) {
+        self.parent_constructed();
+
+        let obj = self.obj();
+        obj.add_pad(&self.sinkpad).unwrap();
+        obj.add_pad(&self.srcpad).unwrap();
+    }
+}
+impl GstObjectImpl for MetaSrtEnc {}
+
+#[glib::object_subclass]
+impl ObjectSubclass for MetaSrtEnc {
+    const NAME: &'static str = CLASS_NAME;
+    type Type = super::MetaSrtEnc;
+    type ParentType = gst::Element;
+
+    fn with_class(klass: &Self::Class) -> Self {
+        let sinkpad = {
+            let templ = klass.pad_template("sink").unwrap();
+            gst::Pad::builder_with_template(&templ, Some("sink"))
+                .chain_function(|pad, parent, buffer| {
+                    Self::catch_panic_pad_function(
+                        parent,
+                        || Err(gst::FlowError::Error),
+                        |enc| enc.sink_chain(pad, buffer),
+                    )
+                })
+                .event_function(|pad, parent, event| {
+                    Self::catch_panic_pad_function(
+                        parent,
+                        || false,
+                        |enc| enc.sink_event(pad, event),
+                    )
+                })
+                .build()
+        };
+        let srcpad = {
+            let templ = klass.pad_template("src").unwrap();
+            gst::Pad::builder_with_template(&templ, Some("src")).build()
+        };
+        Self {
+            sinkpad,
+            srcpad,
+            state: Mutex::new(State::default()),
+            settings: Mutex::new(Settings::default()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use gst::prelude::*;
+
+    use super::*;
+    use crate::metaklv::KLV_CAPS;
+
+    // metasrtencの前後にpadを繋ぎ、(pts, duration)のKLVを流して出力されたcueを返す
+    fn encode_klv(inputs: &[(ClockTime, Option<ClockTime>)]) -> Vec<(u64, String)> {
+        crate::test_init();
+        let enc = gst::ElementFactory::make("metasrtenc").build().unwrap();
+        let cues: Arc<Mutex<Vec<(u64, String)>>> = Arc::default();
+        let c = cues.clone();
+        let srcpad = gst::Pad::new(Some("src"), gst::PadDirection::Src);
+        let sinkpad = gst::Pad::builder(Some("sink"), gst::PadDirection::Sink)
+            .chain_function(move |_, _, buffer| {
+                let map = buffer.map_readable().unwrap();
+                let text = std::str::from_utf8(map.as_slice()).unwrap().to_string();
+                c.lock().unwrap().push((buffer.offset(), text));
+                Ok(gst::FlowSuccess::Ok)
+            })
+            .build();
+        srcpad.link(&enc.static_pad("sink").unwrap()).unwrap();
+        enc.static_pad("src").unwrap().link(&sinkpad).unwrap();
+        srcpad.set_active(true).unwrap();
+        sinkpad.set_active(true).unwrap();
+        enc.set_state(gst::State::Playing).unwrap();
+
+        assert!(srcpad.push_event(gst::event::StreamStart::new("metasrtenc-test")));
+        assert!(srcpad.push_event(gst::event::Caps::new(&KLV_CAPS)));
+        let segment = gst::FormattedSegment::<ClockTime>::new();
+        assert!(srcpad.push_event(gst::event::Segment::new(&segment)));
+        for (i, (pts, duration)) in inputs.iter().enumerate() {
+            let params = ExampleRsMetaParams::new(
+                format!("cue{}", i),
+                i as i32,
+                ers_meta::TransformMode::Copy,
+            );
+            let data = KlvDataset::Example(ExampleDataset::from(&params))
+                .to_bytes()
+                .unwrap();
+            let mut buffer = gst::Buffer::from_mut_slice(data);
+            {
+                let buffer = buffer.get_mut().unwrap();
+                buffer.set_pts(*pts);
+                buffer.set_duration(*duration);
+            }
+            srcpad.push(buffer).unwrap();
+        }
+        assert!(srcpad.push_event(gst::event::Eos::new()));
+
+        enc.set_state(gst::State::Null).unwrap();
+        let cues = cues.lock().unwrap().clone();
+        cues
+    }
+
+    #[test]
+    fn test_klv_cues() {
+        let s = ClockTime::SECOND;
+        let cues = encode_klv(&[
+            (ClockTime::ZERO, None),
+            (s, None),
+            // durationがあれば次のバッファを待たずに終了する
+            (2 * s, Some(ClockTime::from_mseconds(500))),
+            (3 * s, None),
+        ]);
+        assert_eq!(
+            cues,
+            vec![
+                (1, "1\n00:00:00,000 --> 00:00:01,000\ncue0\n\n".to_string()),
+                (2, "2\n00:00:01,000 --> 00:00:02,000\ncue1\n\n".to_string()),
+                (3, "3\n00:00:02,000 --> 00:00:02,500\ncue2\n\n".to_string()),
+                // 最後のcueはEOSで閉じる
+                (4, "4\n00:00:03,000 --> 00:00:04,000\ncue3\n\n".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_srt_time() {
+        assert_eq!(srt_time(ClockTime::ZERO), "00:00:00,000");
+        assert_eq!(
+            srt_time(ClockTime::from_mseconds(3_723_045)),
+            "01:02:03,045"
+        );
+    }
+
+    #[test]
+    fn test_render() {
+        let fields = vec![
+            ("label".to_string(), "cam0".to_string()),
+            ("index".to_string(), "3".to_string()),
+        ];
+        assert_eq!(render("{label} #{index}", &fields), "cam0 #3");
+        // 改行の指定と存在しないフィールド
+        assert_eq!(render("{label}\\n{unknown}", &fields), "cam0\n{unknown}");
+        assert_eq!(render("{label} {", &fields), "cam0 {");
+    }
+}
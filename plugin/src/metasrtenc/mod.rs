@@ -0,0 +1,22 @@
+//! ExampleRsMetaやKLVをSRT字幕に変換するエレメント
+
+use gst::glib;
+use gst::prelude::*;
+
+const ELEMENT_NAME: &str = "metasrtenc";
+const CLASS_NAME: &str = "MetaSrtEnc";
+
+mod imp;
+
+gst::glib::wrapper! {
+    pub struct MetaSrtEnc(ObjectSubclass<imp::MetaSrtEnc>) @extends gst::Element, gst::Object;
+}
+
+pub fn register(plugin: &gst::Plugin) -> Result<(), glib::BoolError> {
+    gst::Element::register(
+        Some(plugin),
+        ELEMENT_NAME,
+        gst::Rank::None,
+        MetaSrtEnc::static_type(),
+    )
+}
@@ -0,0 +1,724 @@
+//! MetaTextMux
+//!
+//! text/x-rawのストリームをVideoフレームのrunning timeで照合し、ExampleRsMetaのlabelとして付与する
+//! subparseで読んだ字幕ファイルなどの注釈をmetatransやmetademuxと同じ経路で扱うため
+use std::collections::VecDeque;
+use std::sync::{Condvar, Mutex, MutexGuard};
+
+use ers_meta::{ExampleRsMeta, ExampleRsMetaParams, TransformMode};
+use gst::prelude::{ElementClassExt, PadExtManual};
+use gst::subclass::prelude::{
+    ElementImpl, ElementImplExt, GstObjectImpl, ObjectImpl, ObjectImplExt, ObjectSubclass,
+    ObjectSubclassExt,
+};
+use gst::traits::{ElementExt, PadExt};
+use gst::{glib, ClockTime, EventView};
+use once_cell::sync::Lazy;
+
+use super::CLASS_NAME;
+use super::ELEMENT_NAME;
+
+static CAT: Lazy<gst::DebugCategory> = Lazy::new(|| {
+    gst::DebugCategory::new(
+        ELEMENT_NAME,
+        gst::DebugColorFlags::empty(),
+        Some(CLASS_NAME),
+    )
+});
+
+// textが先行しすぎないように保持するcueの上限
+// 現在のcueと次のcueがあれば現在のcueの終わりを判断できる
+const MAX_QUEUED_CUES: usize = 2;
+
+/// running timeで表したテキストの有効範囲
+///
+/// GAPはテキストのない区間なので空のtextを持つcueとして扱う
+#[derive(Debug)]
+struct TextCue {
+    start: ClockTime,
+    // durationがない場合は次のcueが来るまで有効
+    end: Option<ClockTime>,
+    text: String,
+}
+
+impl TextCue {
+    fn contains(&self, t: ClockTime) -> bool {
+        self.start <= t && self.end.map_or(true, |end| t < end)
+    }
+}
+
+#[derive(Default)]
+struct State {
+    video_segment: gst::FormattedSegment<ClockTime>,
+    text_segment: gst::FormattedSegment<ClockTime>,
+    cues: VecDeque<TextCue>,
+    text_eos: bool,
+    video_flushing: bool,
+    text_flushing: bool,
+    // 新しく付与するmetaのindex
+    count: i32,
+}
+
+impl State {
+    // running time `t`より前に終わったcueを捨てる
+    //
+    // subparseの字幕は重なることがあるので先頭に限らず全てのcueを調べる
+    fn drop_expired(&mut self, t: ClockTime) -> bool {
+        let len = self.cues.len();
+        // durationのないcueは後のcueが始まったら終わる
+        let mut later_started = false;
+        let mut expired = vec![false; len];
+        for (i, cue) in self.cues.iter().enumerate().rev() {
+            expired[i] = match cue.end {
+                Some(end) => end <= t,
+                None => later_started,
+            };
+            later_started |= cue.start <= t;
+        }
+        let mut expired = expired.into_iter();
+        self.cues.retain(|_| !expired.next().unwrap());
+        self.cues.len() != len
+    }
+
+    // `t`に有効なテキストを決めるのに十分なcueを受け取っているか
+    //
+    // cueは開始順に届くので、`t`より後に始まるかdurationで`t`を含むcueがあれば決められる
+    fn is_ready(&self, t: ClockTime) -> bool {
+        self.text_eos
+            || self
+                .cues
+                .iter()
+                .any(|cue| cue.start > t || cue.end.map_or(false, |end| end > t))
+    }
+}
+
+pub struct MetaTextMux {
+    videosinkpad: gst::Pad,
+    textsinkpad: gst::Pad,
+    srcpad: gst::Pad,
+    state: Mutex<State>,
+    cond: Condvar,
+}
+
+impl MetaTextMux {
+    fn video_chain(
+        &self,
+        pad: &gst::Pad,
+        mut buffer: gst::Buffer,
+    ) -> Result<gst::FlowSuccess, gst::FlowError> {
+        gst::trace!(CAT, obj: pad, "Handling buffer {:?}", buffer);
+        let text = {
+            let mut state = self.state.lock().unwrap();
+            let running_time = buffer
+                .pts()
+                .and_then(|pts| state.video_segment.to_running_time(pts));
+            match running_time {
+                Some(running_time) => {
+                    // textが来ていなければ追いつくまで待つ
+                    loop {
+                        if state.video_flushing {
+                            return Err(gst::FlowError::Flushing);
+                        }
+                        if state.drop_expired(running_time) {
+                            self.cond.notify_all();
+                        }
+                        if !self.textsinkpad.is_linked() || state.is_ready(running_time) {
+                            break;
+                        }
+                        gst::trace!(CAT, obj: pad, "waiting text for {}", running_time);
+                        state = self.cond.wait(state).unwrap();
+                    }
+                    // 空のcueの区間にはmetaを付与しない
+                    let text = state
+                        .cues
+                        .iter()
+                        .find(|cue| cue.contains(running_time))
+                        .filter(|cue| !cue.text.is_empty())
+                        .map(|cue| cue.text.clone());
+                    text.map(|text| {
+                        state.count = state.count.wrapping_add(1);
+                        (text, state.count)
+                    })
+                }
+                None => None,
+            }
+        };
+
+        if let Some((text, count)) = text {
+            gst::trace!(CAT, obj: pad, "attach text {:?} ({:?})", text, buffer.pts());
+            let buffer = buffer.make_mut();
+            // 既にmetaがある場合はlabelだけを置き換える
+            let params = match ExampleRsMeta::remove(buffer) {
+                Some(mut params) => {
+                    params.label = text;
+                    params
+                }
+                None => ExampleRsMetaParams::new(text, count, TransformMode::default()),
+            };
+            ExampleRsMeta::add(buffer, params);
+        }
+        self.srcpad.push(buffer)
+    }
+
+    fn text_chain(
+        &self,
+        pad: &gst::Pad,
+        buffer: gst::Buffer,
+    ) -> Result<gst::FlowSuccess, gst::FlowError> {
+        gst::trace!(CAT, obj: pad, "Handling text buffer {:?}", buffer);
+        let text = {
+            let map = buffer.map_readable().map_err(|_| gst::FlowError::Error)?;
+            // subparseなどが付けるNULや末尾の改行は取り除く
+            String::from_utf8_lossy(map.as_slice())
+                .trim_end_matches(|c: char| c == '\0' || c.is_whitespace())
+                .to_string()
+        };
+
+        let state = self.state.lock().unwrap();
+        let (start, end) = match buffer.pts() {
+            Some(pts) => (
+                state.text_segment.to_running_time(pts),
+                buffer
+                    .duration()
+                    .and_then(|duration| state.text_segment.to_running_time(pts + duration)),
+            ),
+            None => (None, None),
+        };
+        // segmentの範囲外やptsのないテキストは照合できない
+        let start = match start {
+            Some(start) => start,
+            None => {
+                gst::debug!(CAT, obj: pad, "drop text without running time");
+                return Ok(gst::FlowSuccess::Ok);
+            }
+        };
+
+        self.queue_cue(pad, state, TextCue { start, end, text })
+    }
+
+    // videoが追いつくまで待ってからcueを保持する
+    fn queue_cue(
+        &self,
+        pad: &gst::Pad,
+        mut state: MutexGuard<State>,
+        cue: TextCue,
+    ) -> Result<gst::FlowSuccess, gst::FlowError> {
+        while state.cues.len() >= MAX_QUEUED_CUES {
+            if state.text_flushing {
+                return Err(gst::FlowError::Flushing);
+            }
+            state = self.cond.wait(state).unwrap();
+        }
+        if state.text_flushing {
+            return Err(gst::FlowError::Flushing);
+        }
+        gst::trace!(
+            CAT,
+            obj: pad,
+            "queue text {:?} {} - {:?}",
+            cue.text,
+            cue.start,
+            cue.end
+        );
+        state.cues.push_back(cue);
+        self.cond.notify_all();
+        Ok(gst::FlowSuccess::Ok)
+    }
+
+    fn video_event(&self, pad: &gst::Pad, event: gst::Event) -> bool {
+        gst::trace!(CAT, obj: pad, "Handling event {:?}", event);
+        match event.view() {
+            EventView::Segment(seg) => {
+                match seg.segment().downcast_ref::<ClockTime>() {
+                    Some(segment) => self.state.lock().unwrap().video_segment = segment.clone(),
+                    None => {
+                        gst::element_imp_error!(
+                            self,
+                            gst::StreamError::Format,
+                            ["Only time segments are supported"]
+                        );
+                        return false;
+                    }
+                }
+                self.srcpad.push_event(event)
+            }
+            EventView::FlushStart(..) => {
+                self.state.lock().unwrap().video_flushing = true;
+                self.cond.notify_all();
+                self.srcpad.push_event(event)
+            }
+            EventView::FlushStop(..) => {
+                let mut state = self.state.lock().unwrap();
+                state.video_flushing = false;
+                state.video_segment = gst::FormattedSegment::new();
+                drop(state);
+                self.srcpad.push_event(event)
+            }
+            _ => self.srcpad.push_event(event),
+        }
+    }
+
+    // textの状態はvideoの照合にだけ使うのでsrcには流さない
+    fn text_event(&self, pad: &gst::Pad, event: gst::Event) -> bool {
+        gst::trace!(CAT, obj: pad, "Handling text event {:?}", event);
+        let mut state = self.state.lock().unwrap();
+        match event.view() {
+            EventView::Segment(seg) => match seg.segment().downcast_ref::<ClockTime>() {
+                Some(segment) => state.text_segment = segment.clone(),
+                None => {
+                    gst::warning!(CAT, obj: pad, "ignore non time segment");
+                    return false;
+                }
+            },
+            EventView::FlushStart(..) => state.text_flushing = true,
+            EventView::FlushStop(..) => {
+                state.text_flushing = false;
+                state.text_eos = false;
+                state.cues.clear();
+                state.text_segment = gst::FormattedSegment::new();
+            }
+            EventView::Eos(..) => state.text_eos = true,
+            // テキストのない区間を空のcueとして保持し、videoを先に進める
+            EventView::Gap(gap) => {
+                let (pts, duration) = gap.get();
+                let start = state.text_segment.to_running_time(pts);
+                let end = duration.and_then(|d| state.text_segment.to_running_time(pts + d));
+                return match start {
+                    Some(start) => {
+                        let cue = TextCue {
+                            start,
+                            end,
+                            text: String::new(),
+                        };
+                        self.queue_cue(pad, state, cue).is_ok()
+                    }
+                    None => {
+                        gst::debug!(CAT, obj: pad, "drop gap without running time");
+                        true
+                    }
+                };
+            }
+            _ => {}
+        }
+        self.cond.notify_all();
+        true
+    }
+
+    fn video_query(&self, pad: &gst::Pad, query: &mut gst::QueryRef) -> bool {
+        gst::trace!(CAT, obj: pad, "Handling query {:?}", query);
+        self.srcpad.peer_query(query)
+    }
+
+    fn src_query(&self, pad: &gst::Pad, query: &mut gst::QueryRef) -> bool {
+        gst::trace!(CAT, obj: pad, "Handling query {:?}", query);
+        self.videosinkpad.peer_query(query)
+    }
+
+    // seekとflushはtextoverlayと同じくtextにも送ってsegmentとcueを作り直す
+    // 新しい位置のvideoが古いcueと照合されないようにtextを先に送る
+    // textがseekできなくてもvideoは再生できるので結果はvideoに従う
+    fn src_event(&self, pad: &gst::Pad, event: gst::Event) -> bool {
+        gst::trace!(CAT, obj: pad, "Handling event {:?}", event);
+        match event.view() {
+            EventView::Seek(..) | EventView::FlushStart(..) | EventView::FlushStop(..) => {
+                if self.textsinkpad.is_linked() && !self.textsinkpad.push_event(event.clone()) {
+                    gst::debug!(CAT, obj: pad, "text did not handle {:?}", event.type_());
+                }
+                self.videosinkpad.push_event(event)
+            }
+            _ => self.videosinkpad.push_event(event),
+        }
+    }
+}
+
+impl ElementImpl for MetaTextMux {
+    fn metadata() -> Option<&'static gst::subclass::ElementMetadata> {
+        static ELEMENT_METADATA: Lazy<gst::subclass::ElementMetadata> = Lazy::new(|| {
+            gst::subclass::ElementMetadata::new(
+                CLASS_NAME,
+                "Muxer",
+                "Attach text stream to video as ExampleRsMeta label",
+                "FUJINAKA Fumiya <uzuna.kf@gmail.com>",
+            )
+        });
+
+        Some(&*ELEMENT_METADATA)
+    }
+
+    fn pad_templates() -> &'static [gst::PadTemplate] {
+        static PAD_TEMPLATES: Lazy<Vec<gst::PadTemplate>> = Lazy::new(|| {
+            // metaを付与するだけなのでmetademuxと同じくx-rawに限定する
+            let caps = gst::Caps::builder("video/x-raw").build();
+            let src_pad_template = gst::PadTemplate::new(
+                "src",
+                gst::PadDirection::Src,
+                gst::PadPresence::Always,
+                &caps,
+            )
+            .unwrap();
+
+            let video_sink_pad_template = gst::PadTemplate::new(
+                "video_sink",
+                gst::PadDirection::Sink,
+                gst::PadPresence::Always,
+                &caps,
+            )
+            .unwrap();
+
+            // subparseはutf8を要求するとpango markupを取り除いて出力する
+            let text_sink_pad_template = gst::PadTemplate::new(
+                "text_sink",
+                gst::PadDirection::Sink,
+                gst::PadPresence::Always,
+                &gst::Caps::builder("text/x-raw")
+                    .field("format", "utf8")
+                    .build(),
+            )
+            .unwrap();
+
+            vec![
+                src_pad_template,
+                video_sink_pad_template,
+                text_sink_pad_template,
+            ]
+        });
+
+        PAD_TEMPLATES.as_ref()
+    }
+
+    fn change_state(
+        &self,
+        transition: gst::StateChange,
+    ) -> Result<gst::StateChangeSuccess, gst::StateChangeError> {
+        gst::trace!(CAT, imp: self, "Changing state {:?}", transition);
+        // 待機中のchainを抜けさせる
+        if let gst::StateChange::PausedToReady = transition {
+            let mut state = self.state.lock().unwrap();
+            state.video_flushing = true;
+            state.text_flushing = true;
+            self.cond.notify_all();
+        }
+        let res = self.parent_change_state(transition)?;
+        if let gst::StateChange::PausedToReady = transition {
+            *self.state.lock().unwrap() = State::default();
+        }
+        Ok(res)
+    }
+}
+
+impl ObjectImpl for MetaTextMux {
+    fn constructed(&self) {
+        self.parent_constructed();
+
+        let obj = self.obj();
+        obj.add_pad(&self.videosinkpad).unwrap();
+        obj.add_pad(&self.textsinkpad).unwrap();
+        obj.add_pad(&self.srcpad).unwrap();
+    }
+}
+impl GstObjectImpl for MetaTextMux {}
+
+#[glib::object_subclass]
+impl ObjectSubclass for MetaTextMux {
+    const NAME: &'static str = CLASS_NAME;
+    type Type = super::MetaTextMux;
+    type ParentType = gst::Element;
+
+    fn with_class(klass: &Self::Class) -> Self {
+        let videosinkpad = {
+            let templ = klass.pad_template("video_sink").unwrap();
+            gst::Pad::builder_with_template(&templ, Some("video_sink"))
+                .chain_function(|pad, parent, buffer| {
+                    Self::catch_panic_pad_function(
+                        parent,
+                        || Err(gst::FlowError::Error),
+                        |mt| mt.video_chain(pad, buffer),
+                    )
+                })
+                .event_function(|pad, parent, event| {
+                    Self::catch_panic_pad_function(
+                        parent,
+                        || false,
+                        |mt| mt.video_event(pad, event),
+                    )
+                })
+                .query_function(|pad, parent, query| {
+                    Self::catch_panic_pad_function(
+                        parent,
+                        || false,
+                        |mt| mt.video_query(pad, query),
+                    )
+                })
+                .build()
+        };
+        let textsinkpad = {
+            let templ = klass.pad_template("text_sink").unwrap();
+            gst::Pad::builder_with_template(&templ, Some("text_sink"))
+                .chain_function(|pad, parent, buffer| {
+                    Self::catch_panic_pad_function(
+                        parent,
+                        || Err(gst::FlowError::Error),
+                        |mt| mt.text_chain(pad, buffer),
+                    )
+                })
+                .event_function(|pad, parent, event| {
+                    Self::catch_panic_pad_function(parent, || false, |mt| mt.text_event(pad, event))
+                })
+                .build()
+        };
+        let srcpad = {
+            let templ = klass.pad_template("src").unwrap();
+            gst::Pad::builder_with_template(&templ, Some("src"))
+                .event_function(|pad, parent, event| {
+                    Self::catch_panic_pad_function(parent, || false, |mt| mt.src_event(pad, event))
+                })
+                .query_function(|pad, parent, query| {
+                    Self::catch_panic_pad_function(parent, || false, |mt| mt.src_query(pad, query))
+                })
+                .build()
+        };
+        Self {
+            videosinkpad,
+            textsinkpad,
+            srcpad,
+            state: Mutex::new(State::default()),
+            cond: Condvar::new(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+
+    fn cue(start: u64, end: Option<u64>, text: &str) -> TextCue {
+        TextCue {
+            start: ClockTime::from_seconds(start),
+            end: end.map(ClockTime::from_seconds),
+            text: text.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_cue_matching() {
+        let mut state = State::default();
+        let t = ClockTime::from_seconds;
+        state.cues.push_back(cue(1, Some(2), "a"));
+        // cueの途中ではまだ次のcueが来るか分からない
+        assert!(state.is_ready(t(0)));
+        assert!(state.is_ready(t(1)));
+        assert!(!state.is_ready(t(2)));
+
+        // durationのないcueは次のcueの開始まで有効
+        state.cues.push_back(cue(3, None, "b"));
+        assert!(state.drop_expired(t(2)));
+        assert_eq!(state.cues.len(), 1);
+        assert!(!state.is_ready(t(3)));
+        state.cues.push_back(cue(5, Some(6), "c"));
+        assert!(!state.drop_expired(t(4)));
+        assert!(state.cues[0].contains(t(4)));
+        assert!(state.drop_expired(t(5)));
+        assert_eq!(state.cues[0].text, "c");
+
+        state.text_eos = true;
+        assert!(state.is_ready(t(10)));
+    }
+
+    #[test]
+    fn test_overlapping_cues() {
+        let mut state = State::default();
+        let t = ClockTime::from_seconds;
+        // 長いcueの途中で短いcueが始まって終わる
+        state.cues.push_back(cue(0, Some(10), "long"));
+        state.cues.push_back(cue(1, Some(2), "short"));
+        assert!(!state.drop_expired(t(1)));
+        assert!(state.is_ready(t(1)));
+        assert!(state.drop_expired(t(5)));
+        assert_eq!(state.cues.len(), 1);
+        // 先頭のcueが続いていれば次のcueを待たずに決められる
+        assert!(state.is_ready(t(5)));
+        assert!(state.cues[0].contains(t(5)));
+
+        // durationのないcueは後のcueが始まれば重なっていても終わる
+        let mut state = State::default();
+        state.cues.push_back(cue(0, None, "a"));
+        state.cues.push_back(cue(1, Some(3), "b"));
+        assert!(state.drop_expired(t(1)));
+        assert_eq!(state.cues[0].text, "b");
+    }
+
+    #[test]
+    fn test_gap_cue() {
+        let mut state = State::default();
+        let t = ClockTime::from_seconds;
+        state.cues.push_back(cue(0, Some(1), "a"));
+        assert!(!state.is_ready(t(1)));
+        // GAPの区間はテキストがないことが確定している
+        state.cues.push_back(cue(1, Some(3), ""));
+        assert!(state.drop_expired(t(1)));
+        assert!(state.is_ready(t(2)));
+        assert!(!state.is_ready(t(3)));
+    }
+
+    // 字幕を先に進めてから戻すseekでもvideoとtextの位置が揃う
+    #[test]
+    fn test_seek() {
+        use gst::prelude::*;
+
+        if !crate::test_has_elements(&["videotestsrc", "subparse"]) {
+            return;
+        }
+        let path = std::env::temp_dir().join(format!("metatextmux-{}.srt", std::process::id()));
+        std::fs::write(
+            &path,
+            "1\n00:00:00,000 --> 00:00:01,000\na\n\n\
+             2\n00:00:01,000 --> 00:00:02,000\nb\n\n\
+             3\n00:00:02,000 --> 00:00:03,000\nc\n\n",
+        )
+        .unwrap();
+        let pipeline = gst::parse_launch(&format!(
+            "videotestsrc ! video/x-raw,framerate=10/1 ! metatextmux name=m \
+             ! fakesink name=sink sync=false signal-handoffs=true \
+             filesrc location={} ! subparse ! m.text_sink",
+            path.display()
+        ))
+        .unwrap()
+        .downcast::<gst::Pipeline>()
+        .unwrap();
+        let labels: Arc<Mutex<Vec<(ClockTime, Option<String>)>>> = Arc::default();
+        let l = labels.clone();
+        pipeline
+            .by_name("sink")
+            .unwrap()
+            .connect("handoff", false, move |args| {
+                let buffer = args[1].get::<gst::Buffer>().unwrap();
+                let label = buffer
+                    .meta::<ExampleRsMeta>()
+                    .map(|meta| meta.to_params().label);
+                l.lock().unwrap().push((buffer.pts().unwrap(), label));
+                None
+            });
+        let ms = ClockTime::from_mseconds;
+        let seek = |start: ClockTime| {
+            let seek = gst::event::Seek::new(
+                1.0,
+                gst::SeekFlags::FLUSH | gst::SeekFlags::ACCURATE,
+                gst::SeekType::Set,
+                start,
+                gst::SeekType::Set,
+                ms(3000),
+            );
+            assert!(pipeline.send_event(seek));
+            crate::test_run(&pipeline, gst::MessageType::AsyncDone);
+        };
+        pipeline.set_state(gst::State::Paused).unwrap();
+        crate::test_run(&pipeline, gst::MessageType::AsyncDone);
+        seek(ms(2500));
+        labels.lock().unwrap().clear();
+        seek(ms(500));
+        pipeline.set_state(gst::State::Playing).unwrap();
+        crate::test_run(&pipeline, gst::MessageType::Eos);
+        pipeline.set_state(gst::State::Null).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let expected = (5..30)
+            .map(|i| {
+                let label = ["a", "b", "c"][i as usize / 10];
+                (ms(i * 100), Some(label.to_string()))
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(*labels.lock().unwrap(), expected);
+    }
+
+    fn text_buffer(text: &str, pts: ClockTime, duration: ClockTime) -> gst::Buffer {
+        let mut buffer = gst::Buffer::from_mut_slice(text.as_bytes().to_vec());
+        {
+            let buffer = buffer.get_mut().unwrap();
+            buffer.set_pts(pts);
+            buffer.set_duration(duration);
+        }
+        buffer
+    }
+
+    fn video_buffer(pts: ClockTime) -> gst::Buffer {
+        let mut buffer = gst::Buffer::with_size(16).unwrap();
+        buffer.get_mut().unwrap().set_pts(pts);
+        buffer
+    }
+
+    // textのGAPでvideoが止まらずに進むこと
+    #[test]
+    fn test_text_gap() {
+        crate::test_init();
+        let mux = gst::ElementFactory::make("metatextmux").build().unwrap();
+        let labels: Arc<Mutex<Vec<(ClockTime, Option<String>)>>> = Arc::default();
+        let l = labels.clone();
+        let videosrc = gst::Pad::new(Some("videosrc"), gst::PadDirection::Src);
+        let textsrc = gst::Pad::new(Some("textsrc"), gst::PadDirection::Src);
+        let sinkpad = gst::Pad::builder(Some("sink"), gst::PadDirection::Sink)
+            .chain_function(move |_, _, buffer| {
+                let label = buffer
+                    .meta::<ExampleRsMeta>()
+                    .map(|meta| meta.to_params().label);
+                l.lock().unwrap().push((buffer.pts().unwrap(), label));
+                Ok(gst::FlowSuccess::Ok)
+            })
+            .build();
+        videosrc
+            .link(&mux.static_pad("video_sink").unwrap())
+            .unwrap();
+        textsrc.link(&mux.static_pad("text_sink").unwrap()).unwrap();
+        mux.static_pad("src").unwrap().link(&sinkpad).unwrap();
+        for pad in [&videosrc, &textsrc, &sinkpad] {
+            pad.set_active(true).unwrap();
+        }
+        mux.set_state(gst::State::Playing).unwrap();
+
+        let segment = gst::FormattedSegment::<ClockTime>::new();
+        let video_caps = gst::Caps::builder("video/x-raw")
+            .field("format", "GRAY8")
+            .field("width", 4)
+            .field("height", 4)
+            .field("framerate", gst::Fraction::new(2, 1))
+            .build();
+        let text_caps = gst::Caps::builder("text/x-raw")
+            .field("format", "utf8")
+            .build();
+        for (pad, caps) in [(&videosrc, video_caps), (&textsrc, text_caps)] {
+            assert!(pad.push_event(gst::event::StreamStart::new(&pad.name())));
+            assert!(pad.push_event(gst::event::Caps::new(&caps)));
+            assert!(pad.push_event(gst::event::Segment::new(&segment)));
+        }
+
+        let ms = ClockTime::from_mseconds;
+        let s = ClockTime::SECOND;
+        textsrc.push(text_buffer("a", ms(0), s)).unwrap();
+        assert!(textsrc.push_event(gst::event::Gap::builder(s).duration(s).build()));
+        for pts in [0, 500, 1000, 1500] {
+            videosrc.push(video_buffer(ms(pts))).unwrap();
+        }
+        // GAPの後のテキストも付与される
+        textsrc.push(text_buffer("b\n", 2 * s, s)).unwrap();
+        videosrc.push(video_buffer(2 * s)).unwrap();
+        assert!(textsrc.push_event(gst::event::Eos::new()));
+        videosrc.push(video_buffer(3 * s)).unwrap();
+
+        mux.set_state(gst::State::Null).unwrap();
+        let labels = labels.lock().unwrap().clone();
+        let a = Some("a".to_string());
+        let b = Some("b".to_string());
+        assert_eq!(
+            labels,
+            vec![
+                (ms(0), a.clone()),
+                (ms(500), a),
+                (ms(1000), None),
+                (ms(1500), None),
+                (2 * s, b),
+                (3 * s, None),
+            ]
+        );
+    }
+}
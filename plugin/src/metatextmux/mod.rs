@@ -0,0 +1,22 @@
+//! テキストストリームをExampleRsMetaのlabelとしてVideoに付与するエレメント
+
+use gst::glib;
+use gst::prelude::*;
+
+const ELEMENT_NAME: &str = "metatextmux";
+const CLASS_NAME: &str = "MetaTextMux";
+
+mod imp;
+
+gst::glib::wrapper! {
+    pub struct MetaTextMux(ObjectSubclass<imp::MetaTextMux>) @extends gst::Element, gst::Object;
+}
+
+pub fn register(plugin: &gst::Plugin) -> Result<(), glib::BoolError> {
+    gst::Element::register(
+        Some(plugin),
+        ELEMENT_NAME,
+        gst::Rank::None,
+        MetaTextMux::static_type(),
+    )
+}
@@ -1,16 +1,20 @@
+#[cfg(feature = "v1_20")]
+use std::str::FromStr;
 use std::sync::atomic::AtomicI32;
 use std::sync::RwLock;
 
 use ec_meta::{ExampleCMeta, ExampleCMetaParams};
 use gst::traits::GstObjectExt;
 
-use ers_meta::{ExampleRsMeta, ExampleRsMetaParams, TransformMode};
+use ers_meta::{ExampleRsMeta, ExampleRsMetaParams, Region, TransformMode};
 use gst::glib;
 use gst::prelude::{ParamSpecBuilderExt, ToValue};
 use gst::subclass::prelude::*;
 use gst_base::subclass::prelude::BaseTransformImpl;
 use once_cell::sync::Lazy;
 
+#[cfg(feature = "v1_20")]
+use crate::custommeta;
 use crate::metatrans::CLASS_NAME;
 
 use super::ELEMENT_NAME;
@@ -33,6 +37,8 @@ enum TransformMethod {
     Ignore,
     #[enum_value(name = "Copy: copy to dest buffer", nick = "copy")]
     Copy,
+    #[enum_value(name = "Region: scale and crop region to dest buffer", nick = "region")]
+    Region,
 }
 
 #[allow(clippy::from_over_into)]
@@ -41,6 +47,7 @@ impl Into<TransformMode> for TransformMethod {
         match self {
             TransformMethod::Ignore => TransformMode::Ignore,
             TransformMethod::Copy => TransformMode::Copy,
+            TransformMethod::Region => TransformMode::Region,
         }
     }
 }
@@ -60,6 +67,23 @@ enum OperationMode {
 }
 
 /// 使うメタデータの種類を切り替える
+///
+/// glib::Enumはcfgを付けたvariantを扱えないので`v1_20`の有無で定義を分ける
+#[cfg(feature = "v1_20")]
+#[derive(Default, Debug, PartialEq, Clone, Copy, glib::Enum)]
+#[repr(u32)]
+#[enum_type(name = "GstMetaTransMetaType")]
+enum MetaType {
+    #[default]
+    #[enum_value(name = "Rs: impl by Rust", nick = "rs")]
+    Rs = 0,
+    #[enum_value(name = "C: impl by C", nick = "c")]
+    C = 1,
+    #[enum_value(name = "Custom: GstCustomMeta with GstStructure", nick = "custom")]
+    Custom = 2,
+}
+
+#[cfg(not(feature = "v1_20"))]
 #[derive(Default, Debug, PartialEq, Clone, Copy, glib::Enum)]
 #[repr(u32)]
 #[enum_type(name = "GstMetaTransMetaType")]
@@ -76,6 +100,11 @@ struct Settings {
     op_mode: OperationMode,
     transform_meta: TransformMethod,
     meta_type: MetaType,
+    // width, heightが0の場合は矩形を付与しない
+    region: Region,
+    // Custom metaに持たせるフィールド
+    #[cfg(feature = "v1_20")]
+    custom_fields: Option<gst::Structure>,
 }
 
 impl Settings {
@@ -88,6 +117,13 @@ impl Settings {
     fn set_meta_type(&mut self, v: MetaType) {
         self.meta_type = v
     }
+    fn region(&self) -> Option<Region> {
+        if self.region.width > 0 && self.region.height > 0 {
+            Some(self.region)
+        } else {
+            None
+        }
+    }
 }
 
 #[derive(Default)]
@@ -147,7 +183,8 @@ impl ElementImpl for MetaTrans {
 impl ObjectImpl for MetaTrans {
     fn properties() -> &'static [glib::ParamSpec] {
         static PROPERTIES: Lazy<Vec<glib::ParamSpec>> = Lazy::new(|| {
-            vec![
+            #[allow(unused_mut)]
+            let mut properties = vec![
                 gst::glib::ParamSpecEnum::builder::<OperationMode>("op", OperationMode::default())
                     .nick("Operation")
                     .blurb("select operation mode")
@@ -163,7 +200,38 @@ impl ObjectImpl for MetaTrans {
                     .nick("Metatype")
                     .blurb("select metadata type")
                     .build(),
-            ]
+                glib::ParamSpecInt::builder("region-x")
+                    .nick("Region X")
+                    .blurb("x of region to add Rs meta")
+                    .default_value(0)
+                    .build(),
+                glib::ParamSpecInt::builder("region-y")
+                    .nick("Region Y")
+                    .blurb("y of region to add Rs meta")
+                    .default_value(0)
+                    .build(),
+                glib::ParamSpecInt::builder("region-width")
+                    .nick("Region Width")
+                    .blurb("width of region to add Rs meta. 0 is no region")
+                    .minimum(0)
+                    .default_value(0)
+                    .build(),
+                glib::ParamSpecInt::builder("region-height")
+                    .nick("Region Height")
+                    .blurb("height of region to add Rs meta. 0 is no region")
+                    .minimum(0)
+                    .default_value(0)
+                    .build(),
+            ];
+            // Custom metaはGStreamer 1.20以降でだけ使える
+            #[cfg(feature = "v1_20")]
+            properties.push(
+                glib::ParamSpecString::builder("custom-fields")
+                    .nick("Custom Fields")
+                    .blurb("fields of Custom meta as GstStructure. e.g. \"fields,label=cam0,altitude=100.5\"")
+                    .build(),
+            );
+            properties
         });
 
         PROPERTIES.as_ref()
@@ -192,6 +260,34 @@ impl ObjectImpl for MetaTrans {
                 let mut settings = self.settings.write().unwrap();
                 settings.set_meta_type(x);
             }
+            "region-x" | "region-y" | "region-width" | "region-height" => {
+                let x = value.get::<i32>().expect("type checked upstream");
+                gst::info!(CAT, imp: self, "set {} to {}", pspec.name(), x);
+                let mut settings = self.settings.write().unwrap();
+                match pspec.name() {
+                    "region-x" => settings.region.x = x,
+                    "region-y" => settings.region.y = x,
+                    "region-width" => settings.region.width = x,
+                    _ => settings.region.height = x,
+                }
+            }
+            #[cfg(feature = "v1_20")]
+            "custom-fields" => {
+                let x = value
+                    .get::<Option<String>>()
+                    .expect("type checked upstream");
+                gst::info!(CAT, imp: self, "set custom-fields to {:?}", x);
+                let fields = match x.as_deref().map(gst::Structure::from_str) {
+                    Some(Ok(fields)) => Some(fields),
+                    Some(Err(e)) => {
+                        gst::error!(CAT, imp: self, "failed to parse custom-fields: {}", e);
+                        return;
+                    }
+                    None => None,
+                };
+                let mut settings = self.settings.write().unwrap();
+                settings.custom_fields = fields;
+            }
             _ => unimplemented!(),
         }
     }
@@ -212,6 +308,19 @@ impl ObjectImpl for MetaTrans {
                 let settings = self.settings.read().unwrap();
                 settings.meta_type.to_value()
             }
+            "region-x" => self.settings.read().unwrap().region.x.to_value(),
+            "region-y" => self.settings.read().unwrap().region.y.to_value(),
+            "region-width" => self.settings.read().unwrap().region.width.to_value(),
+            "region-height" => self.settings.read().unwrap().region.height.to_value(),
+            #[cfg(feature = "v1_20")]
+            "custom-fields" => self
+                .settings
+                .read()
+                .unwrap()
+                .custom_fields
+                .as_ref()
+                .map(|s| s.to_string())
+                .to_value(),
             _ => unimplemented!(),
         }
     }
@@ -236,12 +345,13 @@ impl BaseTransformImpl for MetaTrans {
         &self,
         buffer: &mut gst::BufferRef,
     ) -> Result<gst::FlowSuccess, gst::FlowError> {
-        let (op_mode, transform_meta, meta_type) = {
+        let (op_mode, transform_meta, meta_type, region) = {
             let settings = self.settings.read().unwrap();
             (
                 settings.op_mode,
                 settings.transform_meta,
                 settings.meta_type,
+                settings.region(),
             )
         };
         match op_mode {
@@ -251,11 +361,12 @@ impl BaseTransformImpl for MetaTrans {
                         gst::trace!(
                             CAT,
                             imp: self,
-                            "found Rs meta ({:?}): {} {} {:?}",
+                            "found Rs meta ({:?}): {} {} {:?} {:?}",
                             buffer.pts(),
                             &meta.label(),
                             &meta.index(),
                             &meta.mode(),
+                            &meta.region(),
                         );
                     } else {
                         gst::trace!(CAT, imp: self, "has not Rs metadata");
@@ -266,9 +377,9 @@ impl BaseTransformImpl for MetaTrans {
                         gst::trace!(
                             CAT,
                             imp: self,
-                            "found C meta ({:?}): {} {} {:?}",
+                            "found C meta ({:?}): {:?} {} {:?}",
                             buffer.pts(),
-                            &meta.label().as_str(),
+                            &meta.label(),
                             &meta.count(),
                             &meta.num(),
                         );
@@ -276,6 +387,20 @@ impl BaseTransformImpl for MetaTrans {
                         gst::trace!(CAT, imp: self, "has not C metadata");
                     }
                 }
+                #[cfg(feature = "v1_20")]
+                MetaType::Custom => {
+                    if let Some(fields) = custommeta::structure(buffer) {
+                        gst::trace!(
+                            CAT,
+                            imp: self,
+                            "found Custom meta ({:?}): {}",
+                            buffer.pts(),
+                            fields,
+                        );
+                    } else {
+                        gst::trace!(CAT, imp: self, "has not Custom metadata");
+                    }
+                }
             },
             OperationMode::Add => {
                 // このプラグイン内では競合操作がないのでRelaxed
@@ -285,11 +410,12 @@ impl BaseTransformImpl for MetaTrans {
 
                 let msg_type = match meta_type {
                     MetaType::Rs => {
-                        let param = ExampleRsMetaParams::new(
+                        let mut param = ExampleRsMetaParams::new(
                             self.instance().name().to_string(),
                             count,
                             transform_meta.into(),
                         );
+                        param.region = region;
                         ers_meta::ExampleRsMeta::add(buffer, param);
                         "Rs Meta"
                     }
@@ -299,9 +425,27 @@ impl BaseTransformImpl for MetaTrans {
                             count.into(),
                             count as f32 / 10.0,
                         );
-                        ec_meta::ExampleCMeta::add(buffer, param);
+                        // エレメント名はC文字列なのでNULを含まない
+                        ec_meta::ExampleCMeta::add(buffer, param).expect("name has no NUL");
                         "C Meta"
                     }
+                    #[cfg(feature = "v1_20")]
+                    MetaType::Custom => {
+                        // 指定されたフィールドに加えて他のメタと同じくカウンタを持たせる
+                        let mut fields = self
+                            .settings
+                            .read()
+                            .unwrap()
+                            .custom_fields
+                            .clone()
+                            .unwrap_or_else(|| gst::Structure::new_empty("fields"));
+                        fields.set("count", count);
+                        if let Err(e) = custommeta::add(buffer, &fields) {
+                            gst::error!(CAT, imp: self, "{}", e);
+                            return Err(gst::FlowError::Error);
+                        }
+                        "Custom Meta"
+                    }
                 };
 
                 gst::trace!(
@@ -336,6 +480,18 @@ impl BaseTransformImpl for MetaTrans {
                         ec_meta::ExampleCMeta::remove(buffer);
                         gst::trace!(CAT, imp: self, "remove C meta ({:?})", buffer.pts(),);
                     }
+                    #[cfg(feature = "v1_20")]
+                    MetaType::Custom => {
+                        if let Some(fields) = custommeta::remove(buffer) {
+                            gst::trace!(
+                                CAT,
+                                imp: self,
+                                "remove Custom meta ({:?}): {}",
+                                buffer.pts(),
+                                fields,
+                            );
+                        }
+                    }
                 }
             }
         }